@@ -585,7 +585,10 @@ impl Client<UserClientConfig> {
                 let amount = c.amount;
                 (contract_id, amount)
             }
-            ContractOrOfferOutput::Offer(_) | ContractOrOfferOutput::CancelOutgoing { .. } => {
+            ContractOrOfferOutput::Offer(_)
+            | ContractOrOfferOutput::OfferBatch(_)
+            | ContractOrOfferOutput::CancelOutgoing { .. }
+            | ContractOrOfferOutput::OfferAndContract(..) => {
                 panic!()
             } // FIXME: impl TryFrom
         };
@@ -984,6 +987,8 @@ impl Client<GatewayClientConfig> {
             encrypted_preimage: offer.encrypted_preimage.clone(),
             decrypted_preimage: DecryptedPreimage::Pending,
             gateway_key: our_pub_key,
+            recipients: vec![],
+            incoming_refund_height: None,
         });
         let incoming_output = fedimint_core::transaction::Output::LN(
             ContractOrOfferOutput::Contract(ContractOutput {
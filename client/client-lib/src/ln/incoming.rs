@@ -3,7 +3,7 @@ use fedimint_api::encoding::{Decodable, Encodable};
 use fedimint_api::Amount;
 use fedimint_core::modules::ln::contracts::incoming::IncomingContract;
 use fedimint_core::modules::ln::contracts::{ContractId, IdentifyableContract};
-use fedimint_core::modules::ln::ContractInput;
+use fedimint_core::modules::ln::{ContractInput, ContractWitness};
 use lightning_invoice::Invoice;
 
 #[derive(Debug, Clone, Encodable, Decodable)]
@@ -17,7 +17,9 @@ impl IncomingContractAccount {
         ContractInput {
             contract_id: self.contract.contract_id(),
             amount: self.amount,
-            witness: None,
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract: None,
         }
     }
 }
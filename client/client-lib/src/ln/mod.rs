@@ -56,16 +56,17 @@ impl<'a> ModuleClient for LnClient<'a> {
         output: &<Self::Module as FederationModule>::TxOutput,
     ) -> TransactionItemAmount {
         match output {
-            ContractOrOfferOutput::Contract(account_output) => TransactionItemAmount {
+            ContractOrOfferOutput::Contract(account_output)
+            | ContractOrOfferOutput::OfferAndContract(_, account_output) => TransactionItemAmount {
                 amount: account_output.amount,
                 fee: self.config.fee_consensus.contract_output,
             },
-            ContractOrOfferOutput::Offer(_) | ContractOrOfferOutput::CancelOutgoing { .. } => {
-                TransactionItemAmount {
-                    amount: Amount::ZERO,
-                    fee: Amount::ZERO,
-                }
-            }
+            ContractOrOfferOutput::Offer(_)
+            | ContractOrOfferOutput::OfferBatch(_)
+            | ContractOrOfferOutput::CancelOutgoing { .. } => TransactionItemAmount {
+                amount: Amount::ZERO,
+                fee: Amount::ZERO,
+            },
         }
     }
 }
@@ -82,10 +83,10 @@ impl<'c> LnClient<'c> {
         timelock: u32,
         mut rng: impl RngCore + CryptoRng + 'a,
     ) -> Result<ContractOrOfferOutput> {
+        let invoice_amount_msat = invoice
+            .amount_milli_satoshis()
+            .ok_or(LnClientError::MissingInvoiceAmount)?;
         let contract_amount = {
-            let invoice_amount_msat = invoice
-                .amount_milli_satoshis()
-                .ok_or(LnClientError::MissingInvoiceAmount)?;
             // TODO: better define fee handling
             // Add 1% fee margin
             let contract_amount_msat = invoice_amount_msat + (invoice_amount_msat / 100);
@@ -100,6 +101,7 @@ impl<'c> LnClient<'c> {
             timelock,
             user_key: user_sk.x_only_public_key().0,
             invoice: invoice.to_string(),
+            invoice_amount: Amount::from_msat(invoice_amount_msat),
             cancelled: false,
         };
 
@@ -223,6 +225,9 @@ impl<'c> LnClient<'c> {
                 &self.config.threshold_pub_key,
             ),
             expiry_time,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
         })
     }
 
@@ -1,9 +1,9 @@
 use fedimint_api::encoding::{Decodable, Encodable};
 use fedimint_api::Amount;
 use fedimint_core::modules::ln::contracts::{
-    outgoing::OutgoingContract, IdentifyableContract, Preimage,
+    outgoing::OutgoingContract, ContractId, IdentifyableContract, Preimage,
 };
-use fedimint_core::modules::ln::ContractInput;
+use fedimint_core::modules::ln::{ContractInput, ContractWitness};
 
 #[derive(Debug, Encodable, Decodable)]
 pub struct OutgoingContractData {
@@ -22,7 +22,9 @@ impl OutgoingContractAccount {
         ContractInput {
             contract_id: self.contract.contract_id(),
             amount: self.amount,
-            witness: Some(preimage),
+            witness: ContractWitness::Preimage(preimage),
+            recipient: None,
+            change_contract: None,
         }
     }
 
@@ -30,7 +32,25 @@ impl OutgoingContractAccount {
         ContractInput {
             contract_id: self.contract.contract_id(),
             amount: self.amount,
-            witness: None,
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract: None,
         }
     }
 }
+
+/// Creates the inputs to claim multiple outgoing contracts at once, e.g. by a gateway that paid
+/// several invoices. Since signatures are aggregated on the transaction level all of them can be
+/// spent in a single transaction as long as they reference distinct contracts.
+pub fn claim_many(claims: Vec<(ContractId, Amount, Preimage)>) -> Vec<ContractInput> {
+    claims
+        .into_iter()
+        .map(|(contract_id, amount, preimage)| ContractInput {
+            contract_id,
+            amount,
+            witness: ContractWitness::Preimage(preimage),
+            recipient: None,
+            change_contract: None,
+        })
+        .collect()
+}
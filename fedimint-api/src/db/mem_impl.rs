@@ -55,11 +55,16 @@ impl IDatabase for MemDatabase {
     }
 
     fn raw_find_by_prefix(&self, key_prefix: &[u8]) -> PrefixIter<'_> {
+        self.raw_find_by_prefix_from(key_prefix, key_prefix)
+    }
+
+    fn raw_find_by_prefix_from(&self, key_prefix: &[u8], start: &[u8]) -> PrefixIter<'_> {
+        let start = start.max(key_prefix);
         let mut data = self
             .data
             .lock()
             .unwrap()
-            .range::<Vec<u8>, _>((key_prefix.to_vec())..)
+            .range::<Vec<u8>, _>((start.to_vec())..)
             .take_while(|(key, _)| key.starts_with(key_prefix))
             .map(|(key, value)| (key.clone(), value.clone()))
             .collect::<Vec<_>>();
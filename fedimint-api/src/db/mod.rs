@@ -66,6 +66,10 @@ pub trait IDatabase: Send + Sync {
 
     fn raw_find_by_prefix(&self, key_prefix: &[u8]) -> PrefixIter<'_>;
 
+    /// Like [`IDatabase::raw_find_by_prefix`], but seeks to the first key greater than or equal
+    /// to `start` instead of the first key with the prefix
+    fn raw_find_by_prefix_from(&self, key_prefix: &[u8], start: &[u8]) -> PrefixIter<'_>;
+
     fn raw_apply_batch(&self, batch: DbBatch) -> Result<()>;
 
     fn begin_transaction(&self) -> DatabaseTransaction;
@@ -153,9 +157,83 @@ impl Database {
         })
     }
 
+    /// Like [`Database::find_by_prefix`], but starts at `start`, e.g. to page through entries
+    /// without reading the ones before the cursor
+    pub fn find_by_prefix_from<KP>(
+        &self,
+        key_prefix: &KP,
+        start: &KP::Key,
+    ) -> impl Iterator<Item = Result<(KP::Key, KP::Value)>> + '_
+    where
+        KP: DatabaseKeyPrefix + DatabaseKeyPrefixConst,
+    {
+        let prefix_bytes = key_prefix.to_bytes();
+        self.raw_find_by_prefix_from(&prefix_bytes, &start.to_bytes())
+            .map(|res| {
+                res.and_then(|(key_bytes, value_bytes)| {
+                    let key = KP::Key::from_bytes(&key_bytes)?;
+                    trace!(
+                        "find by prefix from: Decoding {} from bytes {:?}",
+                        std::any::type_name::<KP::Value>(),
+                        value_bytes
+                    );
+                    let value = KP::Value::from_bytes(&value_bytes)?;
+                    Ok((key, value))
+                })
+            })
+    }
+
     pub fn apply_batch(&self, batch: DbBatch) -> Result<()> {
         self.raw_apply_batch(batch)
     }
+
+    /// Returns a view of the database that can only be read from
+    pub fn read_only(&self) -> ReadOnlyDb<'_> {
+        ReadOnlyDb(self)
+    }
+}
+
+/// Read-only view of a [`Database`] for code that must not have side effects, e.g. transaction
+/// validation. Writing through it doesn't compile:
+///
+/// ```compile_fail
+/// use fedimint_api::db::mem_impl::MemDatabase;
+/// use fedimint_api::db::Database;
+///
+/// let db: Database = MemDatabase::new().into();
+/// db.read_only().raw_remove_entry(&[0x00]).unwrap();
+/// ```
+#[derive(Clone, Copy)]
+pub struct ReadOnlyDb<'a>(&'a Database);
+
+impl<'a> ReadOnlyDb<'a> {
+    pub fn get_value<K>(&self, key: &K) -> Result<Option<K::Value>>
+    where
+        K: DatabaseKey + DatabaseKeyPrefixConst,
+    {
+        self.0.get_value(key)
+    }
+
+    pub fn find_by_prefix<KP>(
+        &self,
+        key_prefix: &KP,
+    ) -> impl Iterator<Item = Result<(KP::Key, KP::Value)>> + 'a
+    where
+        KP: DatabaseKeyPrefix + DatabaseKeyPrefixConst,
+    {
+        self.0.find_by_prefix(key_prefix)
+    }
+
+    pub fn find_by_prefix_from<KP>(
+        &self,
+        key_prefix: &KP,
+        start: &KP::Key,
+    ) -> impl Iterator<Item = Result<(KP::Key, KP::Value)>> + 'a
+    where
+        KP: DatabaseKeyPrefix + DatabaseKeyPrefixConst,
+    {
+        self.0.find_by_prefix_from(key_prefix, start)
+    }
 }
 
 pub trait IDatabaseTransaction<'a>: 'a {
@@ -455,6 +533,19 @@ mod tests {
                 _ => {}
             }
         }
+
+        // Keys are little endian encoded, but all of them are below 256
+        let keys_from = |start| {
+            db.find_by_prefix_from(&DbPrefixTestPrefix, &TestKey(start))
+                .map(|res| res.unwrap().0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys_from(55), vec![TestKey(55), TestKey(123)]);
+        assert_eq!(
+            keys_from(0),
+            vec![TestKey(42), TestKey(54), TestKey(55), TestKey(123)]
+        );
+        assert_eq!(keys_from(200), vec![]);
     }
 
     pub fn test_dbtx_impl(db: Database) {
@@ -71,7 +71,21 @@ pub struct Amount {
     pub milli_sat: u64,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+/// Out points are ordered by transaction id first and output index second
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Deserialize,
+    Serialize,
+    Encodable,
+    Decodable,
+)]
 pub struct OutPoint {
     pub txid: TransactionId,
     pub out_idx: u64,
@@ -178,6 +192,13 @@ impl Amount {
             milli_sat: self.milli_sat.saturating_sub(other.milli_sat),
         }
     }
+
+    /// Returns `None` instead of panicking if `other` exceeds `self`
+    pub fn checked_sub(self, other: Amount) -> Option<Self> {
+        Some(Amount {
+            milli_sat: self.milli_sat.checked_sub(other.milli_sat)?,
+        })
+    }
 }
 
 impl std::fmt::Display for Amount {
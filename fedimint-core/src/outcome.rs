@@ -49,15 +49,7 @@ impl Final for OutputOutcome {
             OutputOutcome::Mint(Some(_)) => true,
             OutputOutcome::Mint(None) => false,
             OutputOutcome::Wallet(_) => true,
-            OutputOutcome::LN(fedimint_ln::OutputOutcome::Offer { .. }) => true,
-            OutputOutcome::LN(fedimint_ln::OutputOutcome::Contract { outcome, .. }) => {
-                match outcome {
-                    ContractOutcome::Account(_) => true,
-                    ContractOutcome::Incoming(DecryptedPreimage::Some(_)) => true,
-                    ContractOutcome::Incoming(_) => false,
-                    ContractOutcome::Outgoing(_) => true,
-                }
-            }
+            OutputOutcome::LN(outcome) => outcome.is_final(),
         }
     }
 }
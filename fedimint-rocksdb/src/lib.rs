@@ -73,6 +73,25 @@ impl IDatabase for RocksDb {
         )
     }
 
+    fn raw_find_by_prefix_from(&self, key_prefix: &[u8], start: &[u8]) -> PrefixIter<'_> {
+        let prefix = key_prefix.to_vec();
+        Box::new(
+            self.inner()
+                .iterator(rocksdb::IteratorMode::From(
+                    start.max(key_prefix),
+                    rocksdb::Direction::Forward,
+                ))
+                .map_while(move |res| {
+                    let (key_bytes, value_bytes) = res.expect("DB error");
+                    key_bytes
+                        .starts_with(&prefix)
+                        .then_some((key_bytes, value_bytes))
+                })
+                .map(|(key_bytes, value_bytes)| (key_bytes.to_vec(), value_bytes.to_vec()))
+                .map(Ok),
+        )
+    }
+
     fn raw_apply_batch(&self, batch: DbBatch) -> Result<()> {
         let batch: Vec<_> = batch.into();
         let tx = self.inner().transaction();
@@ -1,7 +1,8 @@
 use std::collections::HashSet;
 
+use bitcoin::hashes::sha256;
 use fedimint_api::TieredMulti;
-use fedimint_core::modules::ln::contracts::{ContractId, IdentifyableContract};
+use fedimint_core::modules::ln::contracts::{Contract, ContractId, IdentifyableContract};
 use fedimint_core::modules::ln::ContractOrOfferOutput;
 use fedimint_core::modules::mint::Note;
 use fedimint_core::modules::wallet::txoproof::PegInProof;
@@ -15,6 +16,45 @@ where
     fn filter_conflicts<F>(self, map: F) -> ConflictFilter<Self, T, F>
     where
         F: Fn(&T) -> &Transaction;
+
+    fn partition_conflicts<F>(self, map: F) -> ConflictPartitionIter<Self, T, F>
+    where
+        F: Fn(&T) -> &Transaction;
+}
+
+/// Why a transaction was rejected by the conflict filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictReason {
+    /// The same notes were already spent by an earlier transaction
+    Coin,
+    /// The same peg-in proof was already used by an earlier transaction
+    PegIn,
+    /// The contract was already spent or funded by an earlier transaction
+    Contract(ContractId),
+    /// An earlier transaction already funded an incoming contract for the offer with this hash
+    Offer(sha256::Hash),
+    /// An earlier transaction already registered an offer with this hash
+    OfferRegistration(sha256::Hash),
+    /// An earlier transaction already contained a peg-out
+    PegOut,
+}
+
+/// Item yielded by [`ConflictPartitionIter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictResult<T> {
+    Accepted(T),
+    Rejected(T, ConflictReason),
+}
+
+/// Tracks everything spent or funded by previously accepted transactions
+#[derive(Default)]
+struct ConflictState {
+    coin_set: HashSet<TieredMulti<Note>>,
+    peg_in_set: HashSet<PegInProof>,
+    contract_set: HashSet<ContractId>,
+    offer_set: HashSet<sha256::Hash>,
+    registered_offer_set: HashSet<sha256::Hash>,
+    pegged_out: bool,
 }
 
 /// The conflict filter is used to ensure that no conflicting transactions are processed in the main
@@ -28,10 +68,19 @@ where
 {
     inner_iter: I,
     tx_accessor: F,
-    coin_set: HashSet<TieredMulti<Note>>,
-    peg_in_set: HashSet<PegInProof>,
-    contract_set: HashSet<ContractId>,
-    pegged_out: bool,
+    state: ConflictState,
+}
+
+/// Like [`ConflictFilter`], but instead of dropping conflicting items it yields every item together
+/// with the decision, so callers can e.g. audit rejected transactions
+pub struct ConflictPartitionIter<I, T, F>
+where
+    I: Iterator<Item = T>,
+    F: Fn(&T) -> &Transaction,
+{
+    inner_iter: I,
+    tx_accessor: F,
+    state: ConflictState,
 }
 
 impl<I, T> ConflictFilterable<T> for I
@@ -45,60 +94,118 @@ where
         ConflictFilter {
             inner_iter: self,
             tx_accessor,
-            coin_set: Default::default(),
-            peg_in_set: Default::default(),
-            contract_set: Default::default(),
-            pegged_out: false,
+            state: Default::default(),
+        }
+    }
+
+    fn partition_conflicts<F>(self, tx_accessor: F) -> ConflictPartitionIter<Self, T, F>
+    where
+        F: Fn(&T) -> &Transaction,
+    {
+        ConflictPartitionIter {
+            inner_iter: self,
+            tx_accessor,
+            state: Default::default(),
         }
     }
 }
 
-impl<I, T, F> ConflictFilter<I, T, F>
-where
-    I: Iterator<Item = T>,
-    F: Fn(&T) -> &Transaction,
-{
-    fn partition(&mut self, tx: &Transaction) -> Result<Transaction, Transaction> {
+impl ConflictState {
+    fn check(&mut self, tx: &Transaction) -> Result<(), ConflictReason> {
+        self.check_inputs(tx)?;
+        self.check_outputs(tx)
+    }
+
+    fn check_inputs(&mut self, tx: &Transaction) -> Result<(), ConflictReason> {
         for input in &tx.inputs {
             match input {
                 Input::Mint(ref coins) => {
                     // TODO: can this be done without cloning? E.g. hashing?
                     if !self.coin_set.insert(coins.clone()) {
-                        return Err(tx.clone());
+                        return Err(ConflictReason::Coin);
                     }
                 }
                 Input::Wallet(ref peg_in) => {
                     if !self.peg_in_set.insert(peg_in.as_ref().clone()) {
-                        return Err(tx.clone());
+                        return Err(ConflictReason::PegIn);
                     }
                 }
                 Input::LN(input) => {
                     if !self.contract_set.insert(input.contract_id) {
-                        return Err(tx.clone());
+                        return Err(ConflictReason::Contract(input.contract_id));
+                    }
+                    // The change contract is updated just like an output's contract
+                    if let Some(change_contract) = &input.change_contract {
+                        let change_id = change_contract.contract_id();
+                        if !self.contract_set.insert(change_id) {
+                            return Err(ConflictReason::Contract(change_id));
+                        }
                     }
                 }
             }
         }
+        Ok(())
+    }
+
+    fn check_outputs(&mut self, tx: &Transaction) -> Result<(), ConflictReason> {
         for output in &tx.outputs {
-            if let Output::LN(ContractOrOfferOutput::Contract(contract_output)) = output {
+            // The module only checks offers against already registered ones, so competing offers
+            // for the same hash within one epoch have to be caught here
+            let offers = match output {
+                Output::LN(
+                    ContractOrOfferOutput::Offer(offer)
+                    | ContractOrOfferOutput::OfferAndContract(offer, _),
+                ) => std::slice::from_ref(offer),
+                Output::LN(ContractOrOfferOutput::OfferBatch(offers)) => offers.as_slice(),
+                _ => &[],
+            };
+            for offer in offers {
+                if !self.registered_offer_set.insert(offer.hash) {
+                    return Err(ConflictReason::OfferRegistration(offer.hash));
+                }
+            }
+
+            if let Output::LN(
+                ContractOrOfferOutput::Contract(contract_output)
+                | ContractOrOfferOutput::OfferAndContract(_, contract_output),
+            ) = output
+            {
                 // For contracts we need to avoid any parallel updating, so outputs need to
                 // be tracked too. Once the main loop gets refactored such that only computation
                 // intensive operations are parallelized, this restriction can be lifted.
-                if !self
-                    .contract_set
-                    .insert(contract_output.contract.contract_id())
-                {
-                    return Err(tx.clone());
+                let contract_id = contract_output.contract.contract_id();
+                if !self.contract_set.insert(contract_id) {
+                    return Err(ConflictReason::Contract(contract_id));
+                }
+                // Only one of several competing fundings of an offer can be accepted, which one is
+                // decided by the order the transactions are processed in
+                if let Contract::Incoming(incoming) = &contract_output.contract {
+                    if !self.offer_set.insert(incoming.hash) {
+                        return Err(ConflictReason::Offer(incoming.hash));
+                    }
                 }
             }
             if let Output::Wallet(_) = output {
                 match self.pegged_out {
-                    true => return Err(tx.clone()),
+                    true => return Err(ConflictReason::PegOut),
                     false => self.pegged_out = true,
                 }
             }
         }
-        Ok(tx.clone())
+        Ok(())
+    }
+}
+
+impl<I, T, F> ConflictFilter<I, T, F>
+where
+    I: Iterator<Item = T>,
+    F: Fn(&T) -> &Transaction,
+{
+    fn partition(&mut self, tx: &Transaction) -> Result<Transaction, Transaction> {
+        match self.state.check(tx) {
+            Ok(()) => Ok(tx.clone()),
+            Err(_) => Err(tx.clone()),
+        }
     }
 
     pub fn partitioned(&mut self) -> (Vec<Transaction>, Vec<Transaction>) {
@@ -115,3 +222,287 @@ where
         (ok, err)
     }
 }
+
+impl<I, T, F> Iterator for ConflictPartitionIter<I, T, F>
+where
+    I: Iterator<Item = T>,
+    F: Fn(&T) -> &Transaction,
+{
+    type Item = ConflictResult<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.inner_iter.next()?;
+        let result = self.state.check((self.tx_accessor)(&next));
+        Some(match result {
+            Ok(()) => ConflictResult::Accepted(next),
+            Err(reason) => ConflictResult::Rejected(next, reason),
+        })
+    }
+}
+
+/// Checks whether a single transaction's inputs conflict with each other, e.g. spend the same notes
+/// twice, without needing a stream of transactions. This allows clients to catch malformed
+/// transactions before submitting them.
+pub fn has_internal_conflicts(tx: &Transaction) -> bool {
+    ConflictState::default().check_inputs(tx).is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use fedimint_api::{Amount, BitcoinHash, TieredMulti};
+    use fedimint_core::modules::ln::contracts::account::AccountContract;
+    use fedimint_core::modules::ln::contracts::incoming::{
+        IncomingContract, IncomingContractOffer,
+    };
+    use fedimint_core::modules::ln::contracts::{
+        Contract, ContractId, DecryptedPreimage, EncryptedPreimage, IdentifyableContract, Preimage,
+    };
+    use fedimint_core::modules::ln::{
+        ContractInput, ContractOrOfferOutput, ContractOutput, ContractWitness,
+    };
+    use fedimint_core::modules::mint::{Nonce, Note};
+    use fedimint_core::modules::wallet::{Feerate, PegOut, PegOutFees};
+    use secp256k1_zkp::KeyPair;
+
+    use crate::consensus::conflictfilter::{
+        has_internal_conflicts, ConflictFilterable, ConflictReason, ConflictResult,
+    };
+    use crate::transaction::{Input, Output, Transaction};
+
+    fn ln_input(contract_id: ContractId) -> Input {
+        Input::LN(ContractInput {
+            contract_id,
+            amount: Amount::from_sat(42),
+            witness: ContractWitness::Preimage(Preimage([42; 32])),
+            recipient: None,
+            change_contract: None,
+        })
+    }
+
+    #[test]
+    fn test_distinct_contracts_in_one_tx() {
+        let contract_ids = [b"a", b"b", b"c"]
+            .iter()
+            .map(|data| ContractId::hash(&data[..]))
+            .collect::<Vec<_>>();
+
+        let claim_tx = Transaction {
+            inputs: contract_ids.iter().copied().map(ln_input).collect(),
+            outputs: vec![],
+            signature: None,
+        };
+        let double_claim_tx = Transaction {
+            inputs: vec![ln_input(contract_ids[0])],
+            outputs: vec![],
+            signature: None,
+        };
+
+        let (ok, err) = vec![claim_tx.clone(), double_claim_tx.clone()]
+            .into_iter()
+            .filter_conflicts(|tx| tx)
+            .partitioned();
+
+        assert_eq!(ok, vec![claim_tx]);
+        assert_eq!(err, vec![double_claim_tx]);
+    }
+
+    fn mint_input() -> Input {
+        let nonce = Nonce(
+            KeyPair::from_seckey_slice(secp256k1_zkp::SECP256K1, &[42; 32])
+                .expect("valid secret key")
+                .x_only_public_key()
+                .0,
+        );
+        let note = Note(nonce, tbs::Signature(tbs::MessagePoint::generator()));
+        Input::Mint(vec![(Amount::from_sat(1), note)].into_iter().collect())
+    }
+
+    #[test]
+    fn test_has_internal_conflicts() {
+        let contract_ids = [b"a", b"b"]
+            .iter()
+            .map(|data| ContractId::hash(&data[..]))
+            .collect::<Vec<_>>();
+        let mint_input = mint_input();
+
+        let clean_tx = Transaction {
+            inputs: vec![
+                mint_input.clone(),
+                ln_input(contract_ids[0]),
+                ln_input(contract_ids[1]),
+            ],
+            outputs: vec![],
+            signature: None,
+        };
+        assert!(!has_internal_conflicts(&clean_tx));
+
+        let duplicate_mint_tx = Transaction {
+            inputs: vec![mint_input.clone(), mint_input],
+            outputs: vec![],
+            signature: None,
+        };
+        assert!(has_internal_conflicts(&duplicate_mint_tx));
+
+        // Only inputs are checked against each other
+        let double_peg_out_tx = Transaction {
+            inputs: vec![],
+            outputs: vec![peg_out(), peg_out()],
+            signature: None,
+        };
+        assert!(!has_internal_conflicts(&double_peg_out_tx));
+    }
+
+    fn peg_out() -> Output {
+        Output::Wallet(PegOut {
+            recipient: bitcoin::Address::from_str("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq")
+                .expect("valid address"),
+            amount: bitcoin::Amount::from_sat(42),
+            fees: PegOutFees {
+                fee_rate: Feerate { sats_per_kvb: 1000 },
+                total_weight: 0,
+            },
+        })
+    }
+
+    #[test]
+    fn test_partition_conflicts_reasons() {
+        let key = KeyPair::from_seckey_slice(secp256k1_zkp::SECP256K1, &[42; 32])
+            .expect("valid secret key")
+            .x_only_public_key()
+            .0;
+        let contract = Contract::Account(AccountContract {
+            key,
+            multisig: None,
+        });
+        let contract_id = contract.contract_id();
+        let funding = Output::LN(ContractOrOfferOutput::Contract(ContractOutput {
+            amount: Amount::from_sat(42),
+            contract,
+        }));
+
+        let tx = |inputs: Vec<Input>, outputs: Vec<Output>| Transaction {
+            inputs,
+            outputs,
+            signature: None,
+        };
+        let txs = vec![
+            tx(vec![mint_input()], vec![funding.clone(), peg_out()]),
+            tx(vec![mint_input()], vec![]),
+            tx(vec![ln_input(contract_id)], vec![]),
+            tx(vec![], vec![funding]),
+            tx(vec![], vec![peg_out()]),
+        ];
+
+        let results = txs
+            .clone()
+            .into_iter()
+            .partition_conflicts(|tx| tx)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            results,
+            vec![
+                ConflictResult::Accepted(txs[0].clone()),
+                ConflictResult::Rejected(txs[1].clone(), ConflictReason::Coin),
+                ConflictResult::Rejected(txs[2].clone(), ConflictReason::Contract(contract_id)),
+                ConflictResult::Rejected(txs[3].clone(), ConflictReason::Contract(contract_id)),
+                ConflictResult::Rejected(txs[4].clone(), ConflictReason::PegOut),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_competing_offer_fundings() {
+        let key = KeyPair::from_seckey_slice(secp256k1_zkp::SECP256K1, &[42; 32])
+            .expect("valid secret key")
+            .x_only_public_key()
+            .0;
+        let threshold_key = threshold_crypto::SecretKey::random().public_key();
+        let preimage = Preimage([42; 32]);
+        let funding = |gateway_key| {
+            let incoming = IncomingContract {
+                hash: bitcoin::hashes::sha256::Hash::hash(&preimage.0),
+                encrypted_preimage: EncryptedPreimage::new(preimage.clone(), &threshold_key),
+                decrypted_preimage: DecryptedPreimage::Pending,
+                gateway_key,
+                recipients: vec![],
+                incoming_refund_height: None,
+            };
+            Transaction {
+                inputs: vec![],
+                outputs: vec![Output::LN(ContractOrOfferOutput::Contract(
+                    ContractOutput {
+                        amount: Amount::from_sat(42),
+                        contract: Contract::Incoming(incoming),
+                    },
+                ))],
+                signature: None,
+            }
+        };
+        let other_key = KeyPair::from_seckey_slice(secp256k1_zkp::SECP256K1, &[21; 32])
+            .expect("valid secret key")
+            .x_only_public_key()
+            .0;
+        let txs = vec![funding(key), funding(other_key)];
+
+        let results = txs
+            .clone()
+            .into_iter()
+            .partition_conflicts(|tx| tx)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            results,
+            vec![
+                ConflictResult::Accepted(txs[0].clone()),
+                ConflictResult::Rejected(
+                    txs[1].clone(),
+                    ConflictReason::Offer(bitcoin::hashes::sha256::Hash::hash(&preimage.0))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_competing_offer_registrations() {
+        let threshold_key = threshold_crypto::SecretKey::random().public_key();
+        let preimage = Preimage([42; 32]);
+        let hash = bitcoin::hashes::sha256::Hash::hash(&preimage.0);
+        let offer = |amount| IncomingContractOffer {
+            amount: Amount::from_sat(amount),
+            hash,
+            encrypted_preimage: EncryptedPreimage::new(preimage.clone(), &threshold_key),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        let tx = |output| Transaction {
+            inputs: vec![],
+            outputs: vec![Output::LN(output)],
+            signature: None,
+        };
+        let txs = vec![
+            tx(ContractOrOfferOutput::Offer(offer(42))),
+            tx(ContractOrOfferOutput::Offer(offer(21))),
+            tx(ContractOrOfferOutput::OfferBatch(vec![offer(21)])),
+        ];
+
+        let results = txs
+            .clone()
+            .into_iter()
+            .partition_conflicts(|tx| tx)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            results,
+            vec![
+                ConflictResult::Accepted(txs[0].clone()),
+                ConflictResult::Rejected(txs[1].clone(), ConflictReason::OfferRegistration(hash)),
+                ConflictResult::Rejected(txs[2].clone(), ConflictReason::OfferRegistration(hash)),
+            ]
+        );
+    }
+}
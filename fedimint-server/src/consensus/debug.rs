@@ -1,7 +1,9 @@
 use std::fmt::Write;
 
 use fedimint_core::modules::ln::contracts::Contract;
-use fedimint_core::modules::ln::{ContractOrOfferOutput, ContractOutput, DecryptionShareCI};
+use fedimint_core::modules::ln::{
+    ContractOrOfferOutput, ContractOutput, DecryptionShareCI, LightningConsensusItem,
+};
 use fedimint_core::modules::mint::PartiallySignedRequest;
 use fedimint_core::transaction::{Input, Output, Transaction};
 use fedimint_wallet::{PegOutSignatureItem, RoundConsensusItem, WalletConsensusItem};
@@ -43,9 +45,24 @@ fn item_message(item: &ConsensusItem) -> String {
                 out_point.txid
             )
         }
-        ConsensusItem::LN(DecryptionShareCI { contract_id, .. }) => {
+        ConsensusItem::LN(LightningConsensusItem::DecryptionShare(DecryptionShareCI {
+            contract_id,
+            ..
+        })) => {
             format!("LN Decryption Share for contract {}", contract_id)
         }
+        ConsensusItem::LN(LightningConsensusItem::FreezeContract(contract_id)) => {
+            format!("LN Freeze contract {}", contract_id)
+        }
+        ConsensusItem::LN(LightningConsensusItem::UnfreezeContract(contract_id)) => {
+            format!("LN Unfreeze contract {}", contract_id)
+        }
+        ConsensusItem::LN(LightningConsensusItem::FallbackDecryptionShare(DecryptionShareCI {
+            contract_id,
+            ..
+        })) => {
+            format!("LN Fallback Decryption Share for contract {}", contract_id)
+        }
         ConsensusItem::Transaction(Transaction {
             inputs, outputs, ..
         }) => {
@@ -71,9 +88,18 @@ fn item_message(item: &ConsensusItem) -> String {
                     Output::LN(ContractOrOfferOutput::Offer(o)) => {
                         format!("LN Offer for {} with hash {}", o.amount, o.hash)
                     }
+                    Output::LN(ContractOrOfferOutput::OfferBatch(offers)) => {
+                        format!("LN Offer batch of {} offers", offers.len())
+                    }
                     Output::LN(ContractOrOfferOutput::CancelOutgoing { contract, .. }) => {
                         format!("LN Outgoing contract {} cancellation", contract)
                     }
+                    Output::LN(ContractOrOfferOutput::OfferAndContract(
+                        o,
+                        ContractOutput { amount, .. },
+                    )) => {
+                        format!("LN Offer with hash {} funded with {}", o.hash, amount)
+                    }
                     Output::LN(ContractOrOfferOutput::Contract(ContractOutput {
                         amount,
                         contract,
@@ -1,6 +1,6 @@
 #![allow(clippy::let_unit_value)]
 
-mod conflictfilter;
+pub mod conflictfilter;
 pub mod debug;
 mod interconnect;
 
@@ -15,7 +15,11 @@ use fedimint_api::module::audit::Audit;
 use fedimint_api::module::TransactionItemAmount;
 use fedimint_api::{Amount, FederationModule, OutPoint, PeerId, TransactionId};
 use fedimint_core::epoch::*;
-use fedimint_core::modules::ln::{LightningModule, LightningModuleError};
+use fedimint_core::modules::ln::contracts::incoming::IncomingContract;
+use fedimint_core::modules::ln::contracts::{Contract, IdentifyableContract};
+use fedimint_core::modules::ln::{
+    ContractInput, ContractOrOfferOutput, LightningModule, LightningModuleError,
+};
 use fedimint_core::modules::mint::{Mint, MintError};
 use fedimint_core::modules::wallet::{Wallet, WalletError};
 use fedimint_core::outcome::TransactionStatus;
@@ -24,13 +28,15 @@ use fedimint_core_api::ModuleKey;
 use futures::future::select_all;
 use hbbft::honey_badger::Batch;
 use rand::rngs::OsRng;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::Notify;
 use tracing::{debug, error, info, info_span, instrument, trace, warn};
 
 use crate::config::ServerConfig;
-use crate::consensus::conflictfilter::ConflictFilterable;
+use crate::consensus::conflictfilter::{ConflictFilterable, ConflictResult};
 use crate::consensus::interconnect::FedimintInterconnect;
 use crate::db::{
     AcceptedTransactionKey, DropPeerKey, DropPeerKeyPrefix, EpochHistoryKey, LastEpochKey,
@@ -153,6 +159,10 @@ impl FedimintConsensus {
 
         let mut funding_verifier = FundingVerifier::default();
 
+        self.ln
+            .validate_inputs(ln_inputs(&transaction))
+            .map_err(TransactionSubmissionError::ContractInputError)?;
+
         let mut pub_keys = Vec::new();
         for input in &transaction.inputs {
             let meta = match input {
@@ -225,7 +235,7 @@ impl FedimintConsensus {
 
         let UnzipConsensusItem {
             epoch_info: _epoch_info_cis,
-            transaction: transaction_cis,
+            transaction: mut transaction_cis,
             wallet: wallet_cis,
             mint: mint_cis,
             ln: ln_cis,
@@ -254,6 +264,15 @@ impl FedimintConsensus {
             db_vec
                 .into_iter()
                 .for_each(|tx| tx.commit_tx().expect("DB Error"));
+
+            // The LN module neither knows the epoch number nor can it read the block height the
+            // wallet module just agreed on itself
+            let mut dbtx = self.db.begin_transaction();
+            self.ln.record_consensus_epoch(&mut dbtx, epoch);
+            if let Some(block_height) = self.wallet.consensus_height() {
+                self.ln.record_block_height(&mut dbtx, block_height);
+            }
+            dbtx.commit_tx().expect("DB Error");
         }
 
         // Process transactions
@@ -265,19 +284,30 @@ impl FedimintConsensus {
             //  * peg-ins that each peg-in tx is only used to issue coins once
             //  * coin spends to avoid double spends in one batch
             //  * only one peg-out allowed per epoch
-            let (ok_tx, err_tx) = transaction_cis
-                .into_iter()
-                .filter_conflicts(|(_, tx)| tx)
-                .partitioned();
-
+            //  * only one incoming contract funding per offer, chosen by the randomness beacon
+            //    The guardians' local RNGs would diverge, so the ordering RNG is seeded from the
+            //    beacon every guardian agreed on
+            if let Some(beacon) = self.ln.randomness_beacon(&self.build_interconnect()) {
+                order_competing_fundings(&mut transaction_cis, &mut ChaCha20Rng::from_seed(beacon));
+            }
             let mut db_batch = DbBatch::new();
             let mut batch_tx = db_batch.transaction();
 
-            for transaction in err_tx {
-                batch_tx.append_insert(
-                    RejectedTransactionKey(transaction.tx_hash()),
-                    format!("{:?}", TransactionSubmissionError::TransactionConflictError),
-                );
+            let mut ok_tx = vec![];
+            for result in transaction_cis
+                .into_iter()
+                .partition_conflicts(|(_, tx)| tx)
+            {
+                match result {
+                    ConflictResult::Accepted((_, transaction)) => ok_tx.push(transaction),
+                    ConflictResult::Rejected((_, transaction), reason) => {
+                        debug!(tx_hash = %transaction.tx_hash(), ?reason, "Transaction conflicts");
+                        batch_tx.append_insert(
+                            RejectedTransactionKey(transaction.tx_hash()),
+                            format!("{:?}", TransactionSubmissionError::TransactionConflictError),
+                        );
+                    }
+                }
             }
 
             let caches = self.build_verification_caches(ok_tx.iter());
@@ -347,6 +377,10 @@ impl FedimintConsensus {
             batch_tx.commit();
 
             self.db.apply_batch(db_batch).expect("DB error");
+
+            // Only now the database reflects the state the epoch left behind
+            #[cfg(debug_assertions)]
+            self.ln.check_invariants();
         }
 
         let audit = self.audit();
@@ -467,6 +501,10 @@ impl FedimintConsensus {
 
         let tx_hash = transaction.tx_hash();
 
+        self.ln
+            .validate_inputs(ln_inputs(&transaction))
+            .map_err(TransactionSubmissionError::ContractInputError)?;
+
         let mut pub_keys = Vec::new();
         for input in transaction.inputs.iter() {
             let meta = match input {
@@ -648,6 +686,66 @@ impl FedimintConsensus {
     }
 }
 
+/// Reorders transactions funding incoming contracts for the same offer by hashing their contract
+/// ids with a beacon drawn from `rng`, so the conflict filter doesn't favor whichever funding was
+/// submitted first. Competing fundings only swap places among each other, all other transactions
+/// keep their order.
+fn order_competing_fundings(transactions: &mut [(PeerId, Transaction)], rng: &mut impl RngCore) {
+    let mut competing = BTreeMap::<_, Vec<usize>>::new();
+    for (idx, (_, transaction)) in transactions.iter().enumerate() {
+        if let Some(incoming) = funded_incoming_contract(transaction) {
+            competing.entry(incoming.hash).or_default().push(idx);
+        }
+    }
+
+    for slots in competing.into_values().filter(|slots| slots.len() > 1) {
+        let mut competitors = slots
+            .iter()
+            .map(|idx| {
+                let competitor = transactions[*idx].clone();
+                let contract_id = funded_incoming_contract(&competitor.1)
+                    .expect("only transactions funding incoming contracts are competing")
+                    .contract_id();
+                (contract_id, competitor)
+            })
+            .collect::<Vec<_>>();
+        let order = LightningModule::order_competing_fundings_with_rng(
+            rng,
+            competitors
+                .iter()
+                .map(|(contract_id, _)| *contract_id)
+                .collect(),
+        );
+        competitors.sort_by_key(|(contract_id, _)| order.iter().position(|id| id == contract_id));
+        for (idx, (_, competitor)) in slots.into_iter().zip(competitors) {
+            transactions[idx] = competitor;
+        }
+    }
+}
+
+/// Returns the first incoming contract funded by `transaction`, if any
+fn funded_incoming_contract(transaction: &Transaction) -> Option<&IncomingContract> {
+    transaction.outputs.iter().find_map(|output| match output {
+        Output::LN(
+            ContractOrOfferOutput::Contract(contract_output)
+            | ContractOrOfferOutput::OfferAndContract(_, contract_output),
+        ) => match &contract_output.contract {
+            Contract::Incoming(incoming) => Some(incoming),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// Returns the inputs of `transaction` that spend from LN contracts
+fn ln_inputs(transaction: &Transaction) -> impl Iterator<Item = &ContractInput> {
+    transaction.inputs.iter().filter_map(|input| match input {
+        Input::Mint(_) => None,
+        Input::Wallet(_) => None,
+        Input::LN(input) => Some(input),
+    })
+}
+
 impl FundingVerifier {
     fn add_input(&mut self, input_amount: TransactionItemAmount) {
         self.input_amount += input_amount.amount;
@@ -80,6 +80,22 @@ impl IDatabase for SledDb {
         }))
     }
 
+    fn raw_find_by_prefix_from(&self, key_prefix: &[u8], start: &[u8]) -> PrefixIter<'_> {
+        let prefix = key_prefix.to_vec();
+        Box::new(
+            self.inner()
+                .range(start.max(key_prefix)..)
+                .take_while(move |res| {
+                    res.as_ref()
+                        .map_or(true, |(key_bytes, _)| key_bytes.starts_with(&prefix))
+                })
+                .map(|res| {
+                    res.map(|(key_bytes, value_bytes)| (key_bytes.to_vec(), value_bytes.to_vec()))
+                        .map_err(anyhow::Error::from)
+                }),
+        )
+    }
+
     fn raw_apply_batch(&self, batch: DbBatch) -> Result<()> {
         let batch: Vec<_> = batch.into();
 
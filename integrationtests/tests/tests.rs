@@ -5,9 +5,9 @@ use std::time::Duration;
 use assert_matches::assert_matches;
 use bitcoin::{Amount, KeyPair};
 use fedimint_api::db::batch::DbBatch;
-use fedimint_api::TieredMulti;
+use fedimint_api::{PeerId, TieredMulti};
 use fedimint_ln::contracts::{Preimage, PreimageDecryptionShare};
-use fedimint_ln::DecryptionShareCI;
+use fedimint_ln::{DecryptionShareCI, LightningConsensusItem};
 use fedimint_mint::{PartialSigResponse, PartiallySignedRequest};
 use fedimint_server::epoch::ConsensusItem;
 use fedimint_server::transaction::Output;
@@ -269,10 +269,13 @@ async fn drop_peers_who_dont_contribute_decryption_shares() {
     let share = SecretKeyShare::default()
         .decrypt_share_no_verify(&SecretKey::random().public_key().encrypt(""));
     fed.subset_peers(&[3])
-        .override_proposal(vec![ConsensusItem::LN(DecryptionShareCI {
-            contract_id,
-            share: PreimageDecryptionShare(share),
-        })]);
+        .override_proposal(vec![ConsensusItem::LN(
+            LightningConsensusItem::DecryptionShare(DecryptionShareCI {
+                contract_id,
+                peer: PeerId::from(3),
+                share: PreimageDecryptionShare(share),
+            }),
+        )]);
     drop_peer_3_during_epoch(&fed).await; // preimage decryption
 
     user.client
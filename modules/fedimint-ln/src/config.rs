@@ -1,14 +1,20 @@
 use std::collections::BTreeMap;
 
 use async_trait::async_trait;
+use bitcoin_hashes::Hash as BitcoinHash;
+use bitcoin_hashes::{sha256, sha256d};
 use fedimint_api::config::{DkgMessage, DkgRunner, GenerateConfig};
 use fedimint_api::net::peers::AnyPeerConnections;
-use fedimint_api::{NumPeers, PeerId};
+use fedimint_api::{Amount, NumPeers, PeerId};
 use secp256k1::rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use threshold_crypto::serde_impl::SerdeSecret;
 use threshold_crypto::G1Projective;
 
+/// No fee can exceed the total supply of 21 million bitcoin
+const MAX_FEE: Amount = Amount::from_sat(21_000_000 * 100_000_000);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LightningModuleConfig {
     pub threshold_pub_keys: threshold_crypto::PublicKeySet,
@@ -16,7 +22,118 @@ pub struct LightningModuleConfig {
     pub threshold_sec_key:
         threshold_crypto::serde_impl::SerdeSecret<threshold_crypto::SecretKeyShare>,
     pub threshold: usize,
+    /// Number of peers that received a share of the threshold key set, valid share indices are
+    /// `0..total_peers`
+    pub total_peers: usize,
     pub fee_consensus: FeeConsensus,
+    pub preimage_policy: PreimagePolicy,
+    pub preimage_hash: PreimageHash,
+    /// If disabled, preimages are only decrypted once the operator calls
+    /// `LightningModule::decrypt_contract`
+    pub auto_decrypt: bool,
+    /// If disabled, contracts can only be spent entirely and are removed once spent
+    pub allow_partial_spends: bool,
+    /// Number of consensus epochs the decryption of a funded incoming contract's preimage is
+    /// postponed by, i.e. it gets decrypted `1 + decrypt_delay_epochs` epochs after the funding
+    pub decrypt_delay_epochs: u32,
+    /// If set, contract outputs have to fund contracts with a multiple of this amount, e.g. the
+    /// mint's smallest denomination to avoid unspendable dust. Offers are exempt.
+    pub amount_granularity: Option<fedimint_api::Amount>,
+    /// If set, outgoing contracts may not be timelocked more than this many blocks in the future
+    /// so the user's funds can't be locked up effectively forever
+    pub max_timelock_delta: Option<u32>,
+    /// If set, a single transaction may spend from at most this many contracts, bounding the work
+    /// of validating and applying it
+    pub max_inputs_per_tx: Option<usize>,
+    /// If enabled, incoming contracts whose preimage decrypts to an invalid one are marked as
+    /// refundable to the gateway, see `LightningModule::invalid_preimage_refund_key`
+    pub auto_refund_invalid: bool,
+    /// If enabled, offers have to be signed by the receiver so nobody else can register offers on
+    /// their behalf
+    pub require_signed_offers: bool,
+    /// Backup threshold key set preimages are decrypted with if they don't decrypt to a valid one
+    /// using `threshold_pub_keys`, e.g. since the offer was encrypted to the wrong key
+    pub fallback_pub_keys: Option<threshold_crypto::PublicKeySet>,
+    /// Our share of `fallback_pub_keys`, has to be set if and only if these are
+    pub fallback_sec_key:
+        Option<threshold_crypto::serde_impl::SerdeSecret<threshold_crypto::SecretKeyShare>>,
+}
+
+impl LightningModuleConfig {
+    /// Returns the `(threshold, total_peers)` of the threshold key set, e.g. to check a federation
+    /// has the expected security parameters before funding contracts
+    pub fn threshold_params(&self) -> (usize, usize) {
+        (self.threshold, self.total_peers)
+    }
+
+    /// Checks that the config is consistent, so misconfigurations are caught when the module is
+    /// created instead of breaking consensus later on
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.threshold == 0 || self.threshold > self.total_peers {
+            return Err(ConfigError::InvalidThreshold(
+                self.threshold,
+                self.total_peers,
+            ));
+        }
+
+        let key_set_threshold = self.threshold_pub_keys.threshold() + 1;
+        if self.threshold != key_set_threshold {
+            return Err(ConfigError::ThresholdMismatch(
+                self.threshold,
+                key_set_threshold,
+            ));
+        }
+
+        let our_public_key_share = self.threshold_sec_key.public_key_share();
+        let our_idx = (0..self.total_peers)
+            .find(|idx| self.threshold_pub_keys.public_key_share(*idx) == our_public_key_share)
+            .ok_or(ConfigError::UnknownKeyShare)?;
+
+        match (&self.fallback_pub_keys, &self.fallback_sec_key) {
+            (None, None) => {}
+            (Some(pub_keys), Some(sec_key)) => {
+                if pub_keys.threshold() + 1 != self.threshold
+                    || pub_keys.public_key_share(our_idx) != sec_key.public_key_share()
+                {
+                    return Err(ConfigError::FallbackKeyMismatch);
+                }
+            }
+            _ => return Err(ConfigError::FallbackKeyMismatch),
+        }
+
+        for (kind, fee) in [
+            ("contract input", self.fee_consensus.contract_input),
+            ("contract output", self.fee_consensus.contract_output),
+        ] {
+            if fee > MAX_FEE {
+                return Err(ConfigError::FeeTooHigh(kind, fee));
+            }
+        }
+
+        if self.amount_granularity == Some(Amount::ZERO) {
+            return Err(ConfigError::ZeroGranularity);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum ConfigError {
+    #[error("Threshold {0} has to be between 1 and the number of peers {1}")]
+    InvalidThreshold(usize, usize),
+    #[error("Threshold {0} doesn't match the key set, which requires {1} decryption shares")]
+    ThresholdMismatch(usize, usize),
+    #[error("Our secret key share doesn't belong to the threshold key set")]
+    UnknownKeyShare,
+    #[error("The {0} fee {1} exceeds the bitcoin supply")]
+    FeeTooHigh(&'static str, Amount),
+    #[error("Amount granularity may not be zero")]
+    ZeroGranularity,
+    #[error(
+        "Fallback key set and our share of it have to be set together and match our key share"
+    )]
+    FallbackKeyMismatch,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -51,7 +168,20 @@ impl GenerateConfig for LightningModuleConfig {
                         threshold_pub_keys: pks.clone(),
                         threshold_sec_key: threshold_crypto::serde_impl::SerdeSecret(sk),
                         threshold: peers.threshold(),
+                        total_peers: peers.total(),
                         fee_consensus: FeeConsensus::default(),
+                        preimage_policy: PreimagePolicy::default(),
+                        preimage_hash: PreimageHash::default(),
+                        auto_decrypt: true,
+                        allow_partial_spends: true,
+                        decrypt_delay_epochs: 0,
+                        amount_granularity: None,
+                        max_timelock_delta: None,
+                        max_inputs_per_tx: None,
+                        auto_refund_invalid: false,
+                        require_signed_offers: false,
+                        fallback_pub_keys: None,
+                        fallback_sec_key: None,
                     },
                 )
             })
@@ -95,7 +225,20 @@ impl GenerateConfig for LightningModuleConfig {
             threshold_pub_keys: pks.clone(),
             threshold_sec_key: SerdeSecret(sks),
             threshold: peers.threshold(),
+            total_peers: peers.total(),
             fee_consensus: Default::default(),
+            preimage_policy: Default::default(),
+            preimage_hash: Default::default(),
+            auto_decrypt: true,
+            allow_partial_spends: true,
+            decrypt_delay_epochs: 0,
+            amount_granularity: None,
+            max_timelock_delta: None,
+            max_inputs_per_tx: None,
+            auto_refund_invalid: false,
+            require_signed_offers: false,
+            fallback_pub_keys: None,
+            fallback_sec_key: None,
         };
 
         let client = LightningModuleClientConfig {
@@ -121,3 +264,116 @@ impl Default for FeeConsensus {
         }
     }
 }
+
+/// Determines which decrypted preimages of incoming contracts are considered valid
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum PreimagePolicy {
+    /// The preimage has to be a valid schnorr public key which then locks the incoming contract
+    Schnorr32,
+    /// Any preimage matching the payment hash is accepted, regardless of its length. Since such a
+    /// preimage isn't necessarily a public key it can't lock the incoming contract, so incoming
+    /// contracts have to name their recipients, whose keys are then used to claim the funds.
+    RawHashPreimage,
+}
+
+impl Default for PreimagePolicy {
+    fn default() -> Self {
+        PreimagePolicy::Schnorr32
+    }
+}
+
+/// Hash function locking contracts to their preimages, used both to check preimages provided by
+/// spenders of outgoing contracts and to check decrypted preimages of incoming contracts
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum PreimageHash {
+    /// SHA256 as used by the Bitcoin Lightning Network
+    Sha256,
+    /// Double SHA256, for networks locking payments to `sha256(sha256(preimage))`
+    Sha256d,
+}
+
+impl PreimageHash {
+    /// Hashes `preimage`, the result is stored as [`sha256::Hash`] independent of the function
+    /// used since contracts carry their payment hash in that type
+    pub fn hash(&self, preimage: &[u8]) -> sha256::Hash {
+        match self {
+            PreimageHash::Sha256 => sha256::Hash::hash(preimage),
+            PreimageHash::Sha256d => {
+                sha256::Hash::from_inner(sha256d::Hash::hash(preimage).into_inner())
+            }
+        }
+    }
+}
+
+impl Default for PreimageHash {
+    fn default() -> Self {
+        PreimageHash::Sha256
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_api::config::GenerateConfig;
+    use fedimint_api::{Amount, PeerId};
+    use secp256k1::rand::rngs::OsRng;
+
+    use super::{ConfigError, LightningModuleConfig, MAX_FEE};
+
+    fn config() -> LightningModuleConfig {
+        let peers = (0..4u16).map(PeerId::from).collect::<Vec<_>>();
+        let (mut server_cfg, _) = LightningModuleConfig::trusted_dealer_gen(&peers, &(), OsRng);
+        server_cfg.remove(&PeerId::from(0)).unwrap()
+    }
+
+    #[test_log::test]
+    fn test_threshold_params() {
+        let peers = (0..4u16).map(PeerId::from).collect::<Vec<_>>();
+        let (server_cfg, _) = LightningModuleConfig::trusted_dealer_gen(&peers, &(), OsRng);
+
+        for cfg in server_cfg.values() {
+            assert_eq!(cfg.threshold_params(), (3, 4));
+        }
+    }
+
+    #[test_log::test]
+    fn test_validate() {
+        assert_eq!(config().validate(), Ok(()));
+
+        let invalid = |patch: fn(&mut LightningModuleConfig)| {
+            let mut cfg = config();
+            patch(&mut cfg);
+            cfg.validate()
+        };
+        assert_eq!(
+            invalid(|cfg| cfg.threshold = 0),
+            Err(ConfigError::InvalidThreshold(0, 4))
+        );
+        assert_eq!(
+            invalid(|cfg| cfg.threshold = 5),
+            Err(ConfigError::InvalidThreshold(5, 4))
+        );
+        assert_eq!(
+            invalid(|cfg| cfg.threshold = 2),
+            Err(ConfigError::ThresholdMismatch(2, 3))
+        );
+        assert_eq!(
+            invalid(|cfg| cfg.threshold_sec_key = config().threshold_sec_key),
+            Err(ConfigError::UnknownKeyShare)
+        );
+        assert_eq!(
+            invalid(|cfg| cfg.fee_consensus.contract_output = MAX_FEE + Amount::from_msat(1)),
+            Err(ConfigError::FeeTooHigh(
+                "contract output",
+                MAX_FEE + Amount::from_msat(1)
+            ))
+        );
+        assert_eq!(
+            invalid(|cfg| cfg.amount_granularity = Some(Amount::ZERO)),
+            Err(ConfigError::ZeroGranularity)
+        );
+        assert_eq!(
+            invalid(|cfg| cfg.fallback_pub_keys = Some(cfg.threshold_pub_keys.clone())),
+            Err(ConfigError::FallbackKeyMismatch)
+        );
+    }
+}
@@ -8,6 +8,35 @@ use crate::contracts::{ContractId, IdentifyableContract};
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
 pub struct AccountContract {
     pub key: secp256k1::XOnlyPublicKey,
+    /// Turns the account into a shared custody one, see [`AccountMultisig`]
+    pub multisig: Option<AccountMultisig>,
+}
+
+/// Allows any `threshold` out of the account's `key` and the `cosigners` to spend the account
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct AccountMultisig {
+    pub cosigners: Vec<secp256k1::XOnlyPublicKey>,
+    pub threshold: u16,
+}
+
+impl AccountContract {
+    /// All keys that may take part in spending the account
+    pub fn keys(&self) -> Vec<secp256k1::XOnlyPublicKey> {
+        std::iter::once(self.key)
+            .chain(
+                self.multisig
+                    .iter()
+                    .flat_map(|multisig| multisig.cosigners.iter().copied()),
+            )
+            .collect()
+    }
+
+    /// Number of [`AccountContract::keys`] that have to sign a spend
+    pub fn threshold(&self) -> usize {
+        self.multisig
+            .as_ref()
+            .map_or(1, |multisig| multisig.threshold as usize)
+    }
 }
 
 impl IdentifyableContract for AccountContract {
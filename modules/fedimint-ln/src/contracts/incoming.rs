@@ -9,6 +9,8 @@ use serde::{Deserialize, Serialize};
 
 use crate::contracts::{ContractId, DecryptedPreimage, EncryptedPreimage, IdentifyableContract};
 
+const OFFER_SIGNATURE_TAG: &str = "incoming contract offer";
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
 pub struct IncomingContractOffer {
     /// Amount for which the user is willing to sell the preimage
@@ -16,12 +18,41 @@ pub struct IncomingContractOffer {
     pub hash: bitcoin_hashes::sha256::Hash,
     pub encrypted_preimage: EncryptedPreimage,
     pub expiry_time: Option<u64>,
+    /// Mint denomination the seller would like to receive, purely advisory for UIs and not
+    /// enforced when the offer is funded
+    pub denomination_hint: Option<fedimint_api::Amount>,
+    /// Block height from which on the offer can be funded, allowing scheduled offers
+    pub not_before: Option<u32>,
+    /// Proves the offer was registered by the receiver, required if the federation enforces
+    /// `require_signed_offers`
+    pub signature: Option<OfferSignature>,
+}
+
+/// Signature of the receiver over [`IncomingContractOffer::signing_message`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct OfferSignature {
+    pub receiver_key: secp256k1::XOnlyPublicKey,
+    pub signature: secp256k1::schnorr::Signature,
 }
 
 impl IncomingContractOffer {
     pub fn id(&self) -> OfferId {
         OfferId::from_hash(self.hash)
     }
+
+    /// Message the receiver signs, committing to all fields of the offer except the signature
+    pub fn signing_message(&self) -> bitcoin_hashes::sha256::Hash {
+        let unsigned = IncomingContractOffer {
+            signature: None,
+            ..self.clone()
+        };
+
+        let mut engine = bitcoin_hashes::sha256::Hash::engine();
+        Encodable::consensus_encode(&OFFER_SIGNATURE_TAG.as_bytes(), &mut engine)
+            .expect("Hashing never fails");
+        Encodable::consensus_encode(&unsigned, &mut engine).expect("Hashing never fails");
+        bitcoin_hashes::sha256::Hash::from_engine(engine)
+    }
 }
 
 // FIXME: the protocol currently envisions the use of a pub key as preimage. This is bad for privacy
@@ -47,6 +78,9 @@ impl IncomingContractOffer {
 ///      with the private key corresponding to the public key which they used as preimage.
 ///   2. The decryption results in an invalid preimage, the gateway can claim back the money. For
 ///      this to work securely they have to specify a public key when creating the actual contract.
+///
+/// If the gateway set an `incoming_refund_height` it can also claim back the money once that
+/// height is reached without the decryption having finished.
 // TODO: don't duplicate offer, include id instead and fetch offer on mint side
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
 pub struct IncomingContract {
@@ -60,6 +94,15 @@ pub struct IncomingContract {
     pub decrypted_preimage: DecryptedPreimage,
     /// Key that can unlock contract in case the decrypted preimage was invalid
     pub gateway_key: secp256k1::XOnlyPublicKey,
+    /// If not empty, the contract's funds are split between these keys once a valid preimage was
+    /// decrypted instead of being locked to the preimage. Each recipient can spend up to its
+    /// amount, the amounts have to sum up to the funded amount. Since recipients spend their
+    /// shares separately this requires partial spends to be allowed.
+    pub recipients: Vec<(secp256k1::XOnlyPublicKey, fedimint_api::Amount)>,
+    /// Block height from which on the gateway can reclaim the funds while the preimage is still
+    /// being decrypted, e.g. because too many guardians are offline to ever reach the threshold.
+    /// Has to lie sufficiently far ahead of the block height the contract is funded at.
+    pub incoming_refund_height: Option<u32>,
 }
 
 /// The funded version of an [`IncomingContract`] contains the [`OutPoint`] of it's creation. Since
@@ -73,6 +116,9 @@ pub struct FundedIncomingContract {
     /// outcome (This can already be queried by users, making an additional way of querying contract
     /// states unnecessary for now).
     pub out_point: OutPoint,
+    /// Consensus epoch in which the contract was funded, see
+    /// [`crate::config::LightningModuleConfig::decrypt_delay_epochs`]
+    pub funding_epoch: u64,
 }
 
 hash_newtype!(
@@ -11,6 +11,8 @@ use fedimint_api::encoding::{Decodable, DecodeError, Encodable};
 use fedimint_api::OutPoint;
 use serde::{Deserialize, Serialize};
 
+use crate::LightningModuleError;
+
 /// Anything representing a contract which thus has an associated [`ContractId`]
 pub trait IdentifyableContract: Encodable {
     fn contract_id(&self) -> ContractId;
@@ -51,6 +53,15 @@ pub enum ContractOutcome {
     Outgoing(OutgoingContractOutcome),
 }
 
+impl ContractOutcome {
+    /// Returns `true` if the outcome won't change anymore, which is the case unless the preimage
+    /// of an incoming contract is still pending. An invalid preimage is final too, the decryption
+    /// isn't retried.
+    pub fn is_final(&self) -> bool {
+        !matches!(self, ContractOutcome::Incoming(DecryptedPreimage::Pending))
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
 pub struct AccountContractOutcome {}
 
@@ -88,14 +99,57 @@ impl Contract {
         }
     }
 
-    /// Converts a contract to its executed version.
-    pub fn to_funded(self, out_point: OutPoint) -> FundedContract {
+    /// Checks the contract for structural errors that don't depend on the federation's state, so
+    /// clients can catch them before building a [`crate::ContractOutput`].
+    pub fn validate_structure(&self) -> Result<(), LightningModuleError> {
+        match self {
+            Contract::Account(account) => {
+                // Duplicate keys would let fewer parties than intended reach the threshold
+                let keys = account.keys();
+                let distinct_keys = keys.iter().collect::<std::collections::BTreeSet<_>>().len();
+                if account.threshold() == 0
+                    || account.threshold() > keys.len()
+                    || distinct_keys != keys.len()
+                {
+                    return Err(LightningModuleError::InvalidAccountMultisig);
+                }
+            }
+            Contract::Incoming(incoming) => {
+                if incoming.hash == Sha256::from_inner([0; 32]) {
+                    return Err(LightningModuleError::EmptyHash);
+                }
+                if !incoming.encrypted_preimage.0.verify() {
+                    return Err(LightningModuleError::InvalidEncryptedPreimage);
+                }
+                // Only the federation may set the preimage once decryption finishes
+                if incoming.decrypted_preimage != DecryptedPreimage::Pending {
+                    return Err(LightningModuleError::PreimageAlreadyDecrypted);
+                }
+            }
+            Contract::Outgoing(outgoing) => {
+                if outgoing.hash == Sha256::from_inner([0; 32]) {
+                    return Err(LightningModuleError::EmptyHash);
+                }
+                // If the same key can claim and refund the contract the timelock is meaningless
+                if outgoing.gateway_key == outgoing.user_key {
+                    return Err(LightningModuleError::DegenerateOutgoingContract);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Converts a contract to its executed version, funded by `out_point` in consensus epoch
+    /// `epoch`.
+    pub fn to_funded(self, out_point: OutPoint, epoch: u64) -> FundedContract {
         match self {
             Contract::Account(account) => FundedContract::Account(account),
             Contract::Incoming(incoming) => {
                 FundedContract::Incoming(incoming::FundedIncomingContract {
                     contract: incoming,
                     out_point,
+                    funding_epoch: epoch,
                 })
             }
             Contract::Outgoing(outgoing) => FundedContract::Outgoing(outgoing),
@@ -103,6 +157,41 @@ impl Contract {
     }
 }
 
+impl FundedContract {
+    pub fn as_account(&self) -> Option<&account::AccountContract> {
+        match self {
+            FundedContract::Account(account) => Some(account),
+            _ => None,
+        }
+    }
+
+    pub fn as_incoming(&self) -> Option<&incoming::FundedIncomingContract> {
+        match self {
+            FundedContract::Incoming(incoming) => Some(incoming),
+            _ => None,
+        }
+    }
+
+    pub fn as_outgoing(&self) -> Option<&outgoing::OutgoingContract> {
+        match self {
+            FundedContract::Outgoing(outgoing) => Some(outgoing),
+            _ => None,
+        }
+    }
+
+    /// Returns the contract's current outcome, unlike [`Contract::to_outcome`] this reflects the
+    /// decryption state of incoming contracts
+    pub fn to_outcome(&self) -> ContractOutcome {
+        match self {
+            FundedContract::Account(_) => ContractOutcome::Account(AccountContractOutcome {}),
+            FundedContract::Incoming(incoming) => {
+                ContractOutcome::Incoming(incoming.contract.decrypted_preimage.clone())
+            }
+            FundedContract::Outgoing(_) => ContractOutcome::Outgoing(OutgoingContractOutcome {}),
+        }
+    }
+}
+
 impl Encodable for ContractId {
     fn consensus_encode<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, Error> {
         self.as_inner().consensus_encode(writer)
@@ -138,6 +227,9 @@ pub enum DecryptedPreimage {
     Some(Preimage),
     /// The decrypted preimage was invalid
     Invalid,
+    /// The decrypted preimage matched the payment hash but isn't 32 bytes long, which is only
+    /// accepted under [`crate::config::PreimagePolicy::RawHashPreimage`]
+    Raw(Vec<u8>),
 }
 
 /// Threshold-encrypted [`Preimage`]
@@ -152,6 +244,16 @@ impl EncryptedPreimage {
     pub fn new(preimage: Preimage, key: &threshold_crypto::PublicKey) -> EncryptedPreimage {
         EncryptedPreimage(key.encrypt(preimage.0))
     }
+
+    /// Encrypts a raw `preimage` to the federation's threshold public key `key`, e.g. to create an
+    /// offer. The resulting ciphertext passes [`threshold_crypto::Ciphertext::verify`].
+    ///
+    /// Under the default [`crate::config::PreimagePolicy::Schnorr32`] the preimage has to be the
+    /// serialization of a valid x-only public key, otherwise it will be decrypted as invalid.
+    /// Under [`crate::config::PreimagePolicy::RawHashPreimage`] it may be of any length.
+    pub fn encrypt(preimage: &[u8], key: &threshold_crypto::PublicKey) -> EncryptedPreimage {
+        EncryptedPreimage(key.encrypt(preimage))
+    }
 }
 
 impl Encodable for EncryptedPreimage {
@@ -187,3 +289,89 @@ impl Decodable for PreimageDecryptionShare {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin_hashes::Hash as BitcoinHash;
+    use fedimint_api::{Amount, OutPoint};
+    use secp256k1::rand::rngs::OsRng;
+    use secp256k1::KeyPair;
+    use threshold_crypto::SecretKeySet;
+
+    use super::account::AccountContract;
+    use super::incoming::IncomingContract;
+    use super::outgoing::OutgoingContract;
+    use super::{Contract, DecryptedPreimage, EncryptedPreimage, FundedContract, Preimage};
+
+    fn funded_contracts() -> [FundedContract; 3] {
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+        let out_point = OutPoint {
+            txid: bitcoin_hashes::sha256::Hash::hash(b"funding").into(),
+            out_idx: 0,
+        };
+        let threshold_key = SecretKeySet::random(1, &mut OsRng)
+            .public_keys()
+            .public_key();
+
+        [
+            Contract::Account(AccountContract {
+                key: key(),
+                multisig: None,
+            }),
+            Contract::Incoming(IncomingContract {
+                hash: bitcoin_hashes::sha256::Hash::hash(&[42; 32]),
+                encrypted_preimage: EncryptedPreimage::new(Preimage([42; 32]), &threshold_key),
+                decrypted_preimage: DecryptedPreimage::Pending,
+                gateway_key: key(),
+                recipients: vec![],
+                incoming_refund_height: None,
+            }),
+            Contract::Outgoing(OutgoingContract {
+                hash: bitcoin_hashes::sha256::Hash::hash(&[21; 32]),
+                gateway_key: key(),
+                timelock: 42,
+                user_key: key(),
+                invoice: "not enforced yet".to_string(),
+                invoice_amount: Amount::from_sat(42),
+                cancelled: false,
+            }),
+        ]
+        .map(|contract| contract.to_funded(out_point, 0))
+    }
+
+    #[test_log::test]
+    fn test_as_account() {
+        let [account, incoming, outgoing] = funded_contracts();
+        assert!(account.as_account().is_some());
+        assert_eq!(incoming.as_account(), None);
+        assert_eq!(outgoing.as_account(), None);
+    }
+
+    #[test_log::test]
+    fn test_as_incoming() {
+        let [account, incoming, outgoing] = funded_contracts();
+        assert_eq!(account.as_incoming(), None);
+        assert_eq!(
+            incoming
+                .as_incoming()
+                .map(|incoming| incoming.funding_epoch),
+            Some(0)
+        );
+        assert_eq!(outgoing.as_incoming(), None);
+    }
+
+    #[test_log::test]
+    fn test_as_outgoing() {
+        let [account, incoming, outgoing] = funded_contracts();
+        assert_eq!(account.as_outgoing(), None);
+        assert_eq!(incoming.as_outgoing(), None);
+        assert_eq!(
+            outgoing.as_outgoing().map(|outgoing| outgoing.timelock),
+            Some(42)
+        );
+    }
+}
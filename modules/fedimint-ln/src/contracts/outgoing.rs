@@ -24,6 +24,8 @@ pub struct OutgoingContract {
     // FIXME: use pruned, privacy friendly version without description etc.
     /// Invoice containing metadata on how to obtain the preimage
     pub invoice: String,
+    /// Amount the gateway has to pay to the invoice, the rest of the contract's funds is its fee
+    pub invoice_amount: fedimint_api::Amount,
     /// Flag that can be set by the gateway and allows the client to claim an early refund
     pub cancelled: bool,
 }
@@ -36,6 +38,8 @@ impl IdentifyableContract for OutgoingContract {
         Encodable::consensus_encode(&self.timelock, &mut engine).expect("Hashing never fails");
         Encodable::consensus_encode(&self.user_key, &mut engine).expect("Hashing never fails");
         Encodable::consensus_encode(&self.invoice, &mut engine).expect("Hashing never fails");
+        Encodable::consensus_encode(&self.invoice_amount, &mut engine)
+            .expect("Hashing never fails");
         ContractId::from_engine(engine)
     }
 }
@@ -1,9 +1,10 @@
 use fedimint_api::db::DatabaseKeyPrefixConst;
 use fedimint_api::encoding::{Decodable, Encodable};
-use fedimint_api::{OutPoint, PeerId};
-use secp256k1::PublicKey;
+use fedimint_api::{Amount, OutPoint, PeerId};
+use secp256k1::{PublicKey, XOnlyPublicKey};
 
-use crate::contracts::{incoming::IncomingContractOffer, ContractId, PreimageDecryptionShare};
+use crate::contracts::incoming::{IncomingContractOffer, OfferId};
+use crate::contracts::{ContractId, PreimageDecryptionShare};
 use crate::{ContractAccount, LightningGateway, OutputOutcome};
 
 const DB_PREFIX_CONTRACT: u8 = 0x40;
@@ -12,6 +13,28 @@ const DB_PREFIX_PROPOSE_DECRYPTION_SHARE: u8 = 0x42;
 const DB_PREFIX_AGREED_DECRYPTION_SHARE: u8 = 0x43;
 const DB_PREFIX_CONTRACT_UPDATE: u8 = 0x44;
 const DB_PREFIX_LIGHTNING_GATEWAY: u8 = 0x45;
+const DB_PREFIX_FUNDED_OFFER: u8 = 0x46;
+const DB_PREFIX_DECRYPTION_READY: u8 = 0x47;
+const DB_PREFIX_GATEWAY_EARNINGS: u8 = 0x48;
+const DB_PREFIX_PROPOSE_FREEZE: u8 = 0x49;
+const DB_PREFIX_AGREED_FREEZE: u8 = 0x4a;
+const DB_PREFIX_FROZEN_CONTRACT: u8 = 0x4b;
+const DB_PREFIX_MAX_SEEN_BLOCK_HEIGHT: u8 = 0x4c;
+const DB_PREFIX_CURRENT_EPOCH: u8 = 0x4d;
+const DB_PREFIX_EPOCH_SPENT_CONTRACT: u8 = 0x4e;
+const DB_PREFIX_REDUNDANT_DECRYPTION_ATTEMPT: u8 = 0x4f;
+const DB_PREFIX_RECIPIENT_CLAIMED: u8 = 0x50;
+const DB_PREFIX_SPENT_OFFER: u8 = 0x51;
+const DB_PREFIX_CONTRACT_CREATED: u8 = 0x52;
+const DB_PREFIX_INVALID_PREIMAGE_REFUNDABLE: u8 = 0x53;
+const DB_PREFIX_OFFER_CREATED: u8 = 0x54;
+const DB_PREFIX_FEE_ACCUMULATOR: u8 = 0x56;
+const DB_PREFIX_PAYMENT_HASH_INDEX: u8 = 0x57;
+const DB_PREFIX_SHARE_PROPOSAL_ATTEMPT: u8 = 0x58;
+const DB_PREFIX_FAILED_SHARE: u8 = 0x59;
+const DB_PREFIX_PROPOSE_FALLBACK_SHARE: u8 = 0x5a;
+const DB_PREFIX_AGREED_FALLBACK_SHARE: u8 = 0x5b;
+const DB_PREFIX_FREEZE_VOTE_EPOCH: u8 = 0x5c;
 
 #[derive(Debug, Clone, Copy, Encodable, Decodable)]
 pub struct ContractKey(pub ContractId);
@@ -40,6 +63,17 @@ impl DatabaseKeyPrefixConst for ContractUpdateKey {
     type Value = OutputOutcome;
 }
 
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct ContractUpdateKeyPrefix;
+
+impl DatabaseKeyPrefixConst for ContractUpdateKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_CONTRACT_UPDATE;
+    type Key = ContractUpdateKey;
+    type Value = OutputOutcome;
+}
+
+/// Keyed by the offer's payment hash, which uses the configured
+/// [`crate::config::PreimageHash`], see [`crate::LightningModule::payment_hash`]
 #[derive(Debug, Encodable, Decodable)]
 pub struct OfferKey(pub bitcoin_hashes::sha256::Hash);
 
@@ -98,6 +132,35 @@ impl DatabaseKeyPrefixConst for AgreedDecryptionShareKeyPrefix {
     type Value = PreimageDecryptionShare;
 }
 
+/// Preimage decryption shares we received for a certain contract
+#[derive(Debug, Encodable)]
+pub struct AgreedDecryptionShareContractPrefix(pub ContractId);
+
+impl DatabaseKeyPrefixConst for AgreedDecryptionShareContractPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_AGREED_DECRYPTION_SHARE;
+    type Key = AgreedDecryptionShareKey;
+    type Value = PreimageDecryptionShare;
+}
+
+/// Incoming contracts for which we received at least threshold many decryption shares
+#[derive(Debug, Encodable, Decodable)]
+pub struct DecryptionReadyKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for DecryptionReadyKey {
+    const DB_PREFIX: u8 = DB_PREFIX_DECRYPTION_READY;
+    type Key = Self;
+    type Value = ();
+}
+
+#[derive(Debug, Encodable)]
+pub struct DecryptionReadyKeyPrefix;
+
+impl DatabaseKeyPrefixConst for DecryptionReadyKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_DECRYPTION_READY;
+    type Key = DecryptionReadyKey;
+    type Value = ();
+}
+
 #[derive(Debug, Encodable, Decodable)]
 pub struct LightningGatewayKey(pub PublicKey);
 
@@ -115,3 +178,292 @@ impl DatabaseKeyPrefixConst for LightningGatewayKeyPrefix {
     type Key = LightningGatewayKey;
     type Value = LightningGateway;
 }
+
+/// Incoming contracts that funded an offer, kept after the contract was spent
+#[derive(Debug, Encodable, Decodable)]
+pub struct FundedOfferKey(pub OfferId);
+
+impl DatabaseKeyPrefixConst for FundedOfferKey {
+    const DB_PREFIX: u8 = DB_PREFIX_FUNDED_OFFER;
+    type Key = Self;
+    type Value = ContractId;
+}
+
+/// Fees a gateway earned by claiming an outgoing contract, summed up over all partial claims. It is
+/// keyed by contract since a contract can only be spent once per epoch, unlike a gateway's earnings.
+#[derive(Debug, Encodable, Decodable)]
+pub struct GatewayEarningsKey(pub XOnlyPublicKey, pub ContractId);
+
+impl DatabaseKeyPrefixConst for GatewayEarningsKey {
+    const DB_PREFIX: u8 = DB_PREFIX_GATEWAY_EARNINGS;
+    type Key = Self;
+    type Value = Amount;
+}
+
+#[derive(Debug, Encodable)]
+pub struct GatewayEarningsKeyPrefix(pub XOnlyPublicKey);
+
+impl DatabaseKeyPrefixConst for GatewayEarningsKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_GATEWAY_EARNINGS;
+    type Key = GatewayEarningsKey;
+    type Value = Amount;
+}
+
+/// Our vote to freeze (`true`) or unfreeze (`false`) a contract that we propose until the
+/// federation agreed on it
+#[derive(Debug, Encodable, Decodable)]
+pub struct ProposeFreezeKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for ProposeFreezeKey {
+    const DB_PREFIX: u8 = DB_PREFIX_PROPOSE_FREEZE;
+    type Key = Self;
+    type Value = bool;
+}
+
+#[derive(Debug, Encodable)]
+pub struct ProposeFreezeKeyPrefix;
+
+impl DatabaseKeyPrefixConst for ProposeFreezeKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_PROPOSE_FREEZE;
+    type Key = ProposeFreezeKey;
+    type Value = bool;
+}
+
+/// Votes of peers to freeze (`true`) or unfreeze (`false`) a contract that didn't reach the
+/// threshold yet
+#[derive(Debug, Encodable, Decodable)]
+pub struct AgreedFreezeKey(pub ContractId, pub PeerId);
+
+impl DatabaseKeyPrefixConst for AgreedFreezeKey {
+    const DB_PREFIX: u8 = DB_PREFIX_AGREED_FREEZE;
+    type Key = Self;
+    type Value = bool;
+}
+
+#[derive(Debug, Encodable)]
+pub struct AgreedFreezeContractPrefix(pub ContractId);
+
+impl DatabaseKeyPrefixConst for AgreedFreezeContractPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_AGREED_FREEZE;
+    type Key = AgreedFreezeKey;
+    type Value = bool;
+}
+
+/// Epoch in which the first of the outstanding freeze votes for a contract was agreed on, so votes
+/// that never reach the threshold can be discarded eventually
+#[derive(Debug, Encodable, Decodable)]
+pub struct FreezeVoteEpochKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for FreezeVoteEpochKey {
+    const DB_PREFIX: u8 = DB_PREFIX_FREEZE_VOTE_EPOCH;
+    type Key = Self;
+    type Value = u64;
+}
+
+#[derive(Debug, Encodable)]
+pub struct FreezeVoteEpochKeyPrefix;
+
+impl DatabaseKeyPrefixConst for FreezeVoteEpochKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_FREEZE_VOTE_EPOCH;
+    type Key = FreezeVoteEpochKey;
+    type Value = u64;
+}
+
+/// Contracts that may not be spent
+#[derive(Debug, Encodable, Decodable)]
+pub struct FrozenContractKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for FrozenContractKey {
+    const DB_PREFIX: u8 = DB_PREFIX_FROZEN_CONTRACT;
+    type Key = Self;
+    type Value = ();
+}
+
+/// Highest consensus block height the module used to validate inputs
+#[derive(Debug, Encodable, Decodable)]
+pub struct MaxSeenBlockHeightKey;
+
+impl DatabaseKeyPrefixConst for MaxSeenBlockHeightKey {
+    const DB_PREFIX: u8 = DB_PREFIX_MAX_SEEN_BLOCK_HEIGHT;
+    type Key = Self;
+    type Value = u32;
+}
+
+/// Number of the consensus epoch being processed
+#[derive(Debug, Encodable, Decodable)]
+pub struct CurrentEpochKey;
+
+impl DatabaseKeyPrefixConst for CurrentEpochKey {
+    const DB_PREFIX: u8 = DB_PREFIX_CURRENT_EPOCH;
+    type Key = Self;
+    type Value = u64;
+}
+
+/// Contracts that were spent from in a consensus epoch
+#[derive(Debug, Encodable, Decodable)]
+pub struct EpochSpentContractKey(pub u64, pub ContractId);
+
+impl DatabaseKeyPrefixConst for EpochSpentContractKey {
+    const DB_PREFIX: u8 = DB_PREFIX_EPOCH_SPENT_CONTRACT;
+    type Key = Self;
+    type Value = ();
+}
+
+#[derive(Debug, Encodable)]
+pub struct EpochSpentContractPrefix(pub u64);
+
+impl DatabaseKeyPrefixConst for EpochSpentContractPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_EPOCH_SPENT_CONTRACT;
+    type Key = EpochSpentContractKey;
+    type Value = ();
+}
+
+/// Number of times the decryption of an already decrypted contract's preimage was attempted
+#[derive(Debug, Encodable, Decodable)]
+pub struct RedundantDecryptionAttemptKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for RedundantDecryptionAttemptKey {
+    const DB_PREFIX: u8 = DB_PREFIX_REDUNDANT_DECRYPTION_ATTEMPT;
+    type Key = Self;
+    type Value = u64;
+}
+
+#[derive(Debug, Encodable)]
+pub struct RedundantDecryptionAttemptKeyPrefix;
+
+impl DatabaseKeyPrefixConst for RedundantDecryptionAttemptKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_REDUNDANT_DECRYPTION_ATTEMPT;
+    type Key = RedundantDecryptionAttemptKey;
+    type Value = u64;
+}
+
+/// Amount a recipient of a split incoming contract already spent from it
+#[derive(Debug, Encodable, Decodable)]
+pub struct RecipientClaimedKey(pub ContractId, pub XOnlyPublicKey);
+
+impl DatabaseKeyPrefixConst for RecipientClaimedKey {
+    const DB_PREFIX: u8 = DB_PREFIX_RECIPIENT_CLAIMED;
+    type Key = Self;
+    type Value = Amount;
+}
+
+/// Offers that were funded once and may not be registered again
+#[derive(Debug, Encodable, Decodable)]
+pub struct SpentOfferKey(pub OfferId);
+
+impl DatabaseKeyPrefixConst for SpentOfferKey {
+    const DB_PREFIX: u8 = DB_PREFIX_SPENT_OFFER;
+    type Key = Self;
+    type Value = ();
+}
+
+/// Block height at which a contract was first funded
+#[derive(Debug, Encodable, Decodable)]
+pub struct ContractCreatedKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for ContractCreatedKey {
+    const DB_PREFIX: u8 = DB_PREFIX_CONTRACT_CREATED;
+    type Key = Self;
+    type Value = u32;
+}
+
+/// Incoming contracts whose preimage decrypted to an invalid one, so the gateway with the given key
+/// can reclaim the funds
+#[derive(Debug, Encodable, Decodable)]
+pub struct InvalidPreimageRefundableKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for InvalidPreimageRefundableKey {
+    const DB_PREFIX: u8 = DB_PREFIX_INVALID_PREIMAGE_REFUNDABLE;
+    type Key = Self;
+    type Value = XOnlyPublicKey;
+}
+
+/// Highest block height seen when an offer was registered
+#[derive(Debug, Encodable, Decodable)]
+pub struct OfferCreatedKey(pub OfferId);
+
+impl DatabaseKeyPrefixConst for OfferCreatedKey {
+    const DB_PREFIX: u8 = DB_PREFIX_OFFER_CREATED;
+    type Key = Self;
+    type Value = u32;
+}
+
+/// Funds the federation took from contracts, e.g. when sweeping dust
+#[derive(Debug, Encodable, Decodable)]
+pub struct FeeAccumulatorKey;
+
+impl DatabaseKeyPrefixConst for FeeAccumulatorKey {
+    const DB_PREFIX: u8 = DB_PREFIX_FEE_ACCUMULATOR;
+    type Key = Self;
+    type Value = Amount;
+}
+
+/// Incoming contract funded for a payment hash, like [`FundedOfferKey`] this is kept after the
+/// contract was spent
+#[derive(Debug, Encodable, Decodable)]
+pub struct PaymentHashIndexKey(pub bitcoin_hashes::sha256::Hash);
+
+impl DatabaseKeyPrefixConst for PaymentHashIndexKey {
+    const DB_PREFIX: u8 = DB_PREFIX_PAYMENT_HASH_INDEX;
+    type Key = Self;
+    type Value = ContractId;
+}
+
+/// Number of epochs that ended without our proposed decryption share being agreed on
+#[derive(Debug, Encodable, Decodable)]
+pub struct ShareProposalAttemptKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for ShareProposalAttemptKey {
+    const DB_PREFIX: u8 = DB_PREFIX_SHARE_PROPOSAL_ATTEMPT;
+    type Key = Self;
+    type Value = u64;
+}
+
+/// Our decryption shares that never got agreed on and aren't proposed anymore
+#[derive(Debug, Encodable, Decodable)]
+pub struct FailedShareKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for FailedShareKey {
+    const DB_PREFIX: u8 = DB_PREFIX_FAILED_SHARE;
+    type Key = Self;
+    type Value = PreimageDecryptionShare;
+}
+
+/// Our decryption shares of the fallback key set that still need to be broadcasted
+#[derive(Debug, Encodable, Decodable)]
+pub struct ProposeFallbackShareKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for ProposeFallbackShareKey {
+    const DB_PREFIX: u8 = DB_PREFIX_PROPOSE_FALLBACK_SHARE;
+    type Key = Self;
+    type Value = PreimageDecryptionShare;
+}
+
+#[derive(Debug, Encodable)]
+pub struct ProposeFallbackShareKeyPrefix;
+
+impl DatabaseKeyPrefixConst for ProposeFallbackShareKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_PROPOSE_FALLBACK_SHARE;
+    type Key = ProposeFallbackShareKey;
+    type Value = PreimageDecryptionShare;
+}
+
+/// Decryption shares of the fallback key set we received
+#[derive(Debug, Encodable, Decodable)]
+pub struct AgreedFallbackShareKey(pub ContractId, pub PeerId);
+
+impl DatabaseKeyPrefixConst for AgreedFallbackShareKey {
+    const DB_PREFIX: u8 = DB_PREFIX_AGREED_FALLBACK_SHARE;
+    type Key = Self;
+    type Value = PreimageDecryptionShare;
+}
+
+/// Decryption shares of the fallback key set we received for a certain contract
+#[derive(Debug, Encodable)]
+pub struct AgreedFallbackShareContractPrefix(pub ContractId);
+
+impl DatabaseKeyPrefixConst for AgreedFallbackShareContractPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_AGREED_FALLBACK_SHARE;
+    type Key = AgreedFallbackShareKey;
+    type Value = PreimageDecryptionShare;
+}
@@ -13,40 +13,109 @@ extern crate core;
 pub mod config;
 pub mod contracts;
 mod db;
+pub mod snapshot;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::num::NonZeroUsize;
 use std::ops::Sub;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use bitcoin_hashes::Hash as BitcoinHash;
+use bitcoin_hashes::HashEngine;
 use db::{LightningGatewayKey, LightningGatewayKeyPrefix};
 use fedimint_api::db::batch::BatchTx;
-use fedimint_api::db::{Database, DatabaseTransaction};
+use fedimint_api::db::{
+    Database, DatabaseKeyPrefix, DatabaseKeyPrefixConst, DatabaseTransaction, ReadOnlyDb,
+    SerializableDatabaseValue,
+};
 use fedimint_api::encoding::{Decodable, Encodable};
 use fedimint_api::module::audit::Audit;
 use fedimint_api::module::interconnect::ModuleInterconect;
 use fedimint_api::module::{api_endpoint, ApiEndpoint, ApiError, TransactionItemAmount};
+use fedimint_api::task;
 use fedimint_api::{Amount, FederationModule, PeerId};
-use fedimint_api::{InputMeta, OutPoint};
-use itertools::Itertools;
+use fedimint_api::{InputMeta, OutPoint, TransactionId};
+use lru::LruCache;
 use secp256k1::rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::{debug, error, info_span, instrument, trace, warn};
+use tokio::sync::watch;
+use tracing::{debug, error, info, info_span, instrument, trace, warn};
 use url::Url;
 
-use crate::config::LightningModuleConfig;
+use crate::config::{LightningModuleConfig, PreimagePolicy};
 use crate::contracts::{
-    incoming::{IncomingContractOffer, OfferId},
+    account::AccountContract,
+    incoming::{FundedIncomingContract, IncomingContract, IncomingContractOffer, OfferId},
     Contract, ContractId, ContractOutcome, DecryptedPreimage, EncryptedPreimage, FundedContract,
     IdentifyableContract, Preimage, PreimageDecryptionShare,
 };
 use crate::db::{
-    AgreedDecryptionShareKey, AgreedDecryptionShareKeyPrefix, ContractKey, ContractKeyPrefix,
-    ContractUpdateKey, OfferKey, OfferKeyPrefix, ProposeDecryptionShareKey,
-    ProposeDecryptionShareKeyPrefix,
+    AgreedDecryptionShareContractPrefix, AgreedDecryptionShareKey, AgreedDecryptionShareKeyPrefix,
+    AgreedFallbackShareContractPrefix, AgreedFallbackShareKey, AgreedFreezeContractPrefix,
+    AgreedFreezeKey, ContractCreatedKey, ContractKey, ContractKeyPrefix, ContractUpdateKey,
+    ContractUpdateKeyPrefix, CurrentEpochKey, DecryptionReadyKey, DecryptionReadyKeyPrefix,
+    EpochSpentContractKey, EpochSpentContractPrefix, FailedShareKey, FeeAccumulatorKey,
+    FreezeVoteEpochKey, FreezeVoteEpochKeyPrefix, FrozenContractKey, FundedOfferKey,
+    GatewayEarningsKey, GatewayEarningsKeyPrefix, InvalidPreimageRefundableKey,
+    MaxSeenBlockHeightKey, OfferCreatedKey, OfferKey, OfferKeyPrefix, PaymentHashIndexKey,
+    ProposeDecryptionShareKey, ProposeDecryptionShareKeyPrefix, ProposeFallbackShareKey,
+    ProposeFallbackShareKeyPrefix, ProposeFreezeKey, ProposeFreezeKeyPrefix, RecipientClaimedKey,
+    RedundantDecryptionAttemptKey, RedundantDecryptionAttemptKeyPrefix, ShareProposalAttemptKey,
+    SpentOfferKey,
 };
 
+/// Maximum number of offers that can be registered by a single [`ContractOrOfferOutput::OfferBatch`]
+pub const MAX_OFFER_BATCH_SIZE: usize = 128;
+
+/// Failed spend attempts are only counted up to this number per contract
+const MAX_SPEND_ATTEMPTS: u64 = 1000;
+
+/// Number of epochs our decryption share may fail to get agreed on before we stop proposing it
+const MAX_SHARE_PROPOSALS: u64 = 10;
+
+/// Number of epochs freeze votes for a contract may take to reach the threshold before they are
+/// discarded
+const MAX_FREEZE_VOTE_EPOCHS: u64 = 100;
+
+/// Minimum number of blocks an incoming contract's refund height has to lie ahead of the current
+/// block height, so the federation has time to decrypt the preimage before the gateway can reclaim
+/// the funds
+const MIN_INCOMING_REFUND_DELTA: u32 = 144;
+
+/// Kind of contract spend a [`ContractInput`] represents
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ContractInputSource {
+    /// Spend from an account contract
+    AccountSpend,
+    /// Gateway claiming an outgoing contract by providing the preimage
+    OutgoingClaim,
+    /// User reclaiming an outgoing contract after its timelock expired or it was cancelled
+    OutgoingRefund,
+    /// User claiming an incoming contract after a valid preimage was decrypted
+    IncomingClaim,
+    /// Gateway reclaiming an incoming contract because the decrypted preimage was invalid or it
+    /// wasn't decrypted before the contract's refund height
+    IncomingRefund,
+}
+
+/// What applying a [`ContractOrOfferOutput`] changed in the module's state
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ApplyOutputEffect {
+    /// One or more offers were registered, re-registrations of existing offers included
+    OffersRegistered(Vec<OfferId>),
+    /// A new account or outgoing contract was funded
+    ContractFunded(ContractId),
+    /// A new incoming contract was funded and our decryption share for it will be proposed
+    DecryptionStarted(ContractId),
+    /// An already existing contract received additional funds
+    ContractToppedUp(ContractId),
+    /// An outgoing contract was cancelled by its gateway
+    OutgoingCancelled(ContractId),
+}
+
 /// The lightning module implements an account system. It does not have the privacy guarantees of
 /// the e-cash mint module but instead allows for smart contracting. There exist three contract
 /// types that can be used to "lock" accounts:
@@ -68,18 +137,73 @@ use crate::db::{
 /// [Incoming]: contracts::incoming::IncomingContract
 pub struct LightningModule {
     cfg: LightningModuleConfig,
+    our_peer_id: PeerId,
     db: Database,
+    contract_cache: Option<Mutex<ContractCache>>,
+    balance_sink: Option<BalanceSink>,
+    /// Number of inputs spending a contract that failed this guardian's validation. It's kept in
+    /// memory since validation may not change any state, see [`LightningModule::spend_attempts`].
+    spend_attempts: Mutex<HashMap<ContractId, u64>>,
+    /// Preimages decrypted since the epoch began, published to
+    /// [`LightningModule::await_decryption`] before the epoch's batch is applied
+    decryptions: watch::Sender<HashMap<ContractId, DecryptedPreimage>>,
+}
+
+/// Receives [`ContractBalanceChanged`] events, see [`LightningModule::with_balance_sink`]
+pub type BalanceSink = Box<dyn Fn(ContractBalanceChanged) + Send + Sync>;
+
+/// Emitted whenever an input or output changes the balance of a contract account
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ContractBalanceChanged {
+    pub id: ContractId,
+    /// Balance before the change, zero if the contract didn't exist yet
+    pub old: Amount,
+    /// Balance after the change, zero if the contract was spent entirely
+    pub new: Amount,
+}
+
+/// Cache of contract accounts read from the database, see [`LightningModule::with_contract_cache`]
+struct ContractCache {
+    accounts: LruCache<ContractId, ContractAccount>,
+    /// Contracts written to since the epoch began. Their updates only become visible once the
+    /// epoch's batch is applied, so they bypass the cache until it is reset at the next epoch.
+    written: HashSet<ContractId>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
 pub struct ContractInput {
     pub contract_id: contracts::ContractId,
-    /// While for now we only support spending the entire contract we need to avoid
+    /// Has to equal the contract's balance if `allow_partial_spends` is disabled in the config
     pub amount: Amount,
-    /// Of the three contract types only the outgoing one needs any other witness data than a
-    /// signature. The signature is aggregated on the transaction level, so only the optional
-    /// preimage remains.
-    pub witness: Option<Preimage>,
+    pub witness: ContractWitness,
+    /// Recipient spending its share of an incoming contract with multiple recipients, see
+    /// [`contracts::incoming::IncomingContract::recipients`]
+    pub recipient: Option<secp256k1::XOnlyPublicKey>,
+    /// Account contract the balance left over by a partial spend is moved to, the spent contract
+    /// is deleted instead of keeping the remainder
+    pub change_contract: Option<Contract>,
+}
+
+/// Witness data of a [`ContractInput`] besides the signature, which is aggregated on the
+/// transaction level. Outgoing contracts need the preimage and shared custody account contracts
+/// the keys signing the spend.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub enum ContractWitness {
+    None,
+    /// The preimage is encoded as a fixed-size array without a length prefix, so decoding it can't
+    /// be tricked into large allocations by a malicious length declaration.
+    Preimage(Preimage),
+    /// Keys out of [`contracts::account::AccountContract::keys`] that sign the spend of a shared
+    /// custody account, at least its threshold have to be given
+    Signers(Vec<secp256k1::XOnlyPublicKey>),
+}
+
+impl ContractInput {
+    /// Size of the consensus encoded input in bytes, e.g. to charge fees by transaction size
+    pub fn encoded_size(&self) -> usize {
+        self.consensus_encode(&mut std::io::sink())
+            .expect("Writing to sink can't fail")
+    }
 }
 
 /// Represents an output of the Lightning module.
@@ -99,6 +223,8 @@ pub enum ContractOrOfferOutput {
     Contract(ContractOutput),
     /// Creat incoming contract offer
     Offer(contracts::incoming::IncomingContractOffer),
+    /// Create multiple incoming contract offers at once, at most [`MAX_OFFER_BATCH_SIZE`]
+    OfferBatch(Vec<contracts::incoming::IncomingContractOffer>),
     /// Allow early refund of outgoing contract
     CancelOutgoing {
         /// Contract to update
@@ -106,6 +232,17 @@ pub enum ContractOrOfferOutput {
         /// Signature of gateway
         gateway_signature: secp256k1::schnorr::Signature,
     },
+    /// Register an offer and fund the incoming contract buying it, e.g. for a receiver's first
+    /// payment. The offer is registered first, independent of the output order in the transaction.
+    OfferAndContract(contracts::incoming::IncomingContractOffer, ContractOutput),
+}
+
+impl ContractOrOfferOutput {
+    /// Size of the consensus encoded output in bytes, e.g. to charge fees by transaction size
+    pub fn encoded_size(&self) -> usize {
+        self.consensus_encode(&mut std::io::sink())
+            .expect("Writing to sink can't fail")
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
@@ -120,6 +257,25 @@ pub struct ContractAccount {
     pub contract: contracts::FundedContract,
 }
 
+/// Funds held in contract accounts, grouped by what they are owed for
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct LnLiabilities {
+    pub outgoing: Amount,
+    /// Incoming contracts whose preimage is still being decrypted
+    pub incoming_pending: Amount,
+    /// Incoming contracts that can be claimed by either the user or the gateway
+    pub incoming_claimable: Amount,
+    pub account: Amount,
+}
+
+/// Number of existing contract accounts by contract type
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct ContractCounts {
+    pub account: usize,
+    pub outgoing: usize,
+    pub incoming: usize,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
 pub enum OutputOutcome {
     Contract {
@@ -129,6 +285,31 @@ pub enum OutputOutcome {
     Offer {
         id: OfferId,
     },
+    OfferBatch {
+        ids: Vec<OfferId>,
+    },
+}
+
+impl OutputOutcome {
+    /// Returns `true` if the outcome won't change anymore, see [`ContractOutcome::is_final`]
+    pub fn is_final(&self) -> bool {
+        match self {
+            OutputOutcome::Contract { outcome, .. } => outcome.is_final(),
+            OutputOutcome::Offer { .. } | OutputOutcome::OfferBatch { .. } => true,
+        }
+    }
+}
+
+/// State of an output as returned by [`LightningModule::output_status_detailed`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub enum OutputStatus {
+    /// No output with this out point was processed (yet)
+    Unknown,
+    /// The output was processed but its outcome may still change, i.e. the preimage of an
+    /// incoming contract wasn't decrypted yet
+    Pending(OutputOutcome),
+    /// The output was processed and its outcome won't change anymore
+    Final(OutputOutcome),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Encodable, Decodable, PartialEq, Eq, Hash)]
@@ -141,16 +322,31 @@ pub struct LightningGateway {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Encodable, Decodable, Serialize, Deserialize)]
 pub struct DecryptionShareCI {
     pub contract_id: ContractId,
+    /// Peer that created the share, has to match the peer the consensus attributes the item to.
+    /// Keeps the share attributable if it is read without the consensus context.
+    pub peer: PeerId,
     pub share: PreimageDecryptionShare,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Encodable, Decodable, Serialize, Deserialize)]
+pub enum LightningConsensusItem {
+    DecryptionShare(DecryptionShareCI),
+    /// Vote to block all spends of a contract, e.g. pending a dispute
+    FreezeContract(ContractId),
+    /// Vote to make a frozen contract spendable again
+    UnfreezeContract(ContractId),
+    /// Decryption share of the fallback key set, see
+    /// [`config::LightningModuleConfig::fallback_pub_keys`]
+    FallbackDecryptionShare(DecryptionShareCI),
+}
+
 #[async_trait(?Send)]
 impl FederationModule for LightningModule {
     type Error = LightningModuleError;
     type TxInput = ContractInput;
     type TxOutput = ContractOrOfferOutput;
     type TxOutputOutcome = OutputOutcome;
-    type ConsensusItem = DecryptionShareCI;
+    type ConsensusItem = LightningConsensusItem;
     type VerificationCache = ();
 
     async fn await_consensus_proposal<'a>(&'a self, rng: impl RngCore + CryptoRng + 'a) {
@@ -163,12 +359,62 @@ impl FederationModule for LightningModule {
         &'a self,
         _rng: impl RngCore + CryptoRng + 'a,
     ) -> Vec<Self::ConsensusItem> {
-        self.db
+        let mut decryption_shares = self
+            .db
             .find_by_prefix(&ProposeDecryptionShareKeyPrefix)
             .map(|res| {
                 let (ProposeDecryptionShareKey(contract_id), share) = res.expect("DB error");
-                DecryptionShareCI { contract_id, share }
+                DecryptionShareCI {
+                    contract_id,
+                    peer: self.our_peer_id,
+                    share,
+                }
+            })
+            .filter(|share| !self.share_agreement_reached(share.contract_id))
+            .collect::<Vec<_>>();
+        sort_decryption_shares(&mut decryption_shares);
+        let mut fallback_shares = self
+            .db
+            .find_by_prefix(&ProposeFallbackShareKeyPrefix)
+            .map(|res| {
+                let (ProposeFallbackShareKey(contract_id), share) = res.expect("DB error");
+                DecryptionShareCI {
+                    contract_id,
+                    peer: self.our_peer_id,
+                    share,
+                }
+            })
+            .collect::<Vec<_>>();
+        sort_decryption_shares(&mut fallback_shares);
+        let freeze_votes = self
+            .db
+            .find_by_prefix(&ProposeFreezeKeyPrefix)
+            .map(|res| res.expect("DB error"))
+            // Once our vote was agreed on there is no need to propose it again
+            .filter(|(ProposeFreezeKey(contract_id), freeze)| {
+                self.db
+                    .get_value(&AgreedFreezeKey(*contract_id, self.our_peer_id))
+                    .expect("DB error")
+                    != Some(*freeze)
             })
+            .map(|proposal| match proposal {
+                (ProposeFreezeKey(contract_id), true) => {
+                    LightningConsensusItem::FreezeContract(contract_id)
+                }
+                (ProposeFreezeKey(contract_id), false) => {
+                    LightningConsensusItem::UnfreezeContract(contract_id)
+                }
+            });
+
+        decryption_shares
+            .into_iter()
+            .map(LightningConsensusItem::DecryptionShare)
+            .chain(
+                fallback_shares
+                    .into_iter()
+                    .map(LightningConsensusItem::FallbackDecryptionShare),
+            )
+            .chain(freeze_votes)
             .collect()
     }
 
@@ -178,18 +424,30 @@ impl FederationModule for LightningModule {
         consensus_items: Vec<(PeerId, Self::ConsensusItem)>,
         _rng: impl RngCore + CryptoRng + 'a,
     ) {
-        consensus_items
-            .into_iter()
-            .for_each(|(peer, decryption_share)| {
-                let span = info_span!("process decryption share", %peer);
-                let _guard = span.enter();
+        self.reset_contract_cache();
+        // The previous epoch's decryptions can be read from the database now
+        self.decryptions.send_if_modified(|decryptions| {
+            let modified = !decryptions.is_empty();
+            decryptions.clear();
+            modified
+        });
 
-                dbtx.insert_new_entry(
-                    &AgreedDecryptionShareKey(decryption_share.contract_id, peer),
-                    &decryption_share.share,
-                )
-                .expect("DB Error");
-            });
+        for (peer, consensus_item) in consensus_items {
+            match consensus_item {
+                LightningConsensusItem::DecryptionShare(decryption_share) => {
+                    self.process_decryption_share(dbtx, peer, decryption_share)
+                }
+                LightningConsensusItem::FreezeContract(contract_id) => {
+                    self.process_freeze_vote(dbtx, peer, contract_id, true)
+                }
+                LightningConsensusItem::UnfreezeContract(contract_id) => {
+                    self.process_freeze_vote(dbtx, peer, contract_id, false)
+                }
+                LightningConsensusItem::FallbackDecryptionShare(decryption_share) => {
+                    self.process_fallback_share(dbtx, peer, decryption_share)
+                }
+            }
+        }
     }
 
     fn build_verification_cache<'a>(
@@ -204,64 +462,20 @@ impl FederationModule for LightningModule {
         _cache: &Self::VerificationCache,
         input: &'a Self::TxInput,
     ) -> Result<InputMeta<'a>, Self::Error> {
-        let account: ContractAccount = self
-            .get_contract_account(input.contract_id)
-            .ok_or(LightningModuleError::UnknownContract(input.contract_id))?;
-
-        if account.amount < input.amount {
-            return Err(LightningModuleError::InsufficientFunds(
-                account.amount,
-                input.amount,
-            ));
+        // Input validation reads the module's state only through a read-only view of the database.
+        // Failed spend attempts are counted in memory, see `record_failed_spend_attempt`.
+        let db = self.db.read_only();
+        let result = self
+            .check_input(
+                db,
+                input,
+                Self::max_seen_block_height(db, block_height(interconnect)),
+            )
+            .map(|(meta, _)| meta);
+        if result.is_err() {
+            self.record_failed_spend_attempt(db, input.contract_id);
         }
-
-        let pub_key = match account.contract {
-            FundedContract::Outgoing(outgoing) => {
-                if outgoing.timelock > block_height(interconnect) && !outgoing.cancelled {
-                    // If the timelock hasn't expired yet …
-                    let preimage_hash = bitcoin_hashes::sha256::Hash::hash(
-                        &input
-                            .witness
-                            .as_ref()
-                            .ok_or(LightningModuleError::MissingPreimage)?
-                            .0,
-                    );
-
-                    // … and the spender provides a valid preimage …
-                    if preimage_hash != outgoing.hash {
-                        return Err(LightningModuleError::InvalidPreimage);
-                    }
-
-                    // … then the contract account can be spent using the gateway key,
-                    outgoing.gateway_key
-                } else {
-                    // otherwise the user can claim the funds back.
-                    outgoing.user_key
-                }
-            }
-            FundedContract::Account(acc_contract) => acc_contract.key,
-            FundedContract::Incoming(incoming) => match incoming.contract.decrypted_preimage {
-                // Once the preimage has been decrypted …
-                DecryptedPreimage::Pending => {
-                    return Err(LightningModuleError::ContractNotReady);
-                }
-                // … either the user may spend the funds since they sold a valid preimage …
-                DecryptedPreimage::Some(preimage) => match preimage.to_public_key() {
-                    Ok(pub_key) => pub_key,
-                    Err(_) => return Err(LightningModuleError::InvalidPreimage),
-                },
-                // … or the gateway may claim back funds for not receiving the advertised preimage.
-                DecryptedPreimage::Invalid => incoming.contract.gateway_key,
-            },
-        };
-
-        Ok(InputMeta {
-            amount: TransactionItemAmount {
-                amount: input.amount,
-                fee: self.cfg.fee_consensus.contract_input,
-            },
-            puk_keys: Box::new(std::iter::once(pub_key)),
-        })
+        result
     }
 
     fn apply_input<'a, 'b>(
@@ -269,18 +483,69 @@ impl FederationModule for LightningModule {
         interconnect: &'a dyn ModuleInterconect,
         mut batch: BatchTx<'a>,
         input: &'b Self::TxInput,
-        cache: &Self::VerificationCache,
+        _cache: &Self::VerificationCache,
     ) -> Result<InputMeta<'b>, Self::Error> {
-        let meta = self.validate_input(interconnect, cache, input)?;
+        let block_height =
+            Self::max_seen_block_height(self.db.read_only(), block_height(interconnect));
+        let (meta, source) = self.validate_input_with_source(input, block_height)?;
+        batch.append_insert(MaxSeenBlockHeightKey, block_height);
 
         let account_db_key = ContractKey(input.contract_id);
         let mut contract_account = self
-            .db
-            .get_value(&account_db_key)
-            .expect("DB error")
+            .get_contract_account(input.contract_id)
             .expect("Should fail validation if contract account doesn't exist");
-        contract_account.amount -= meta.amount.amount;
-        batch.append_insert(account_db_key, contract_account);
+        let old_amount = contract_account.amount;
+        contract_account.amount = contract_account
+            .amount
+            .checked_sub(meta.amount.amount)
+            .ok_or(LightningModuleError::AmountUnderflow(
+                contract_account.amount,
+                meta.amount.amount,
+            ))?;
+
+        if let (ContractInputSource::OutgoingClaim, FundedContract::Outgoing(outgoing)) =
+            (source, &contract_account.contract)
+        {
+            // Whatever the gateway claims beyond the invoice amount is its routing fee. Partial
+            // claims take the fee first, so they add up to the same total as a single claim.
+            let earnings_key = GatewayEarningsKey(outgoing.gateway_key, input.contract_id);
+            let earned = self
+                .db
+                .get_value(&earnings_key)
+                .expect("DB error")
+                .unwrap_or(Amount::ZERO);
+            let fee = input
+                .amount
+                .min(old_amount.saturating_sub(outgoing.invoice_amount));
+            batch.append_insert(earnings_key, earned + fee);
+        }
+        if let (ContractInputSource::IncomingClaim, FundedContract::Incoming(incoming)) =
+            (source, &contract_account.contract)
+        {
+            if !incoming.contract.recipients.is_empty() {
+                let recipient = input.recipient.expect("Checked during validation");
+                batch.append_insert(
+                    RecipientClaimedKey(input.contract_id, recipient),
+                    Self::recipient_claimed(self.db.read_only(), input.contract_id, recipient)
+                        + input.amount,
+                );
+            }
+        }
+        if let Some(Contract::Account(change_contract)) = &input.change_contract {
+            let change = std::mem::replace(&mut contract_account.amount, Amount::ZERO);
+            self.fund_change_contract(&mut batch, change_contract, change);
+        }
+        self.invalidate_contract(input.contract_id);
+        self.emit_balance_change(input.contract_id, old_amount, contract_account.amount);
+        if self.cfg.allow_partial_spends && input.change_contract.is_none() {
+            batch.append_insert(account_db_key, contract_account);
+        } else {
+            self.delete_spent_contract(&mut batch, input.contract_id, &contract_account);
+        }
+        batch.append_insert(
+            EpochSpentContractKey(self.current_epoch(), input.contract_id),
+            (),
+        );
 
         batch.commit();
         Ok(meta)
@@ -290,49 +555,49 @@ impl FederationModule for LightningModule {
         &self,
         output: &Self::TxOutput,
     ) -> Result<TransactionItemAmount, Self::Error> {
+        // Output validation reads the module's state only through a read-only view of the database
+        let db = self.db.read_only();
         match output {
             ContractOrOfferOutput::Contract(contract) => {
-                // Incoming contracts are special, they need to match an offer
-                if let Contract::Incoming(incoming) = &contract.contract {
-                    let offer = self
-                        .db
-                        .get_value(&OfferKey(incoming.hash))
-                        .expect("DB error")
-                        .ok_or(LightningModuleError::NoOffer(incoming.hash))?;
-
-                    if contract.amount < offer.amount {
-                        // If the account is not sufficiently funded fail the output
-                        return Err(LightningModuleError::InsufficientIncomingFunding(
-                            offer.amount,
-                            contract.amount,
-                        ));
-                    }
+                self.validate_contract_output(db, contract, None)
+            }
+            ContractOrOfferOutput::OfferAndContract(offer, contract) => {
+                let funds_offer = matches!(
+                    &contract.contract,
+                    Contract::Incoming(incoming) if incoming.hash == offer.hash
+                );
+                if !funds_offer {
+                    return Err(LightningModuleError::BundledOfferMismatch(offer.hash));
                 }
 
-                if contract.amount == Amount::ZERO {
-                    Err(LightningModuleError::ZeroOutput)
-                } else {
-                    Ok(TransactionItemAmount {
-                        amount: contract.amount,
-                        fee: self.cfg.fee_consensus.contract_output,
-                    })
-                }
+                self.validate_offer(db, offer)?;
+                self.validate_contract_output(db, contract, Some(offer))
             }
             ContractOrOfferOutput::Offer(offer) => {
-                if !offer.encrypted_preimage.0.verify() {
-                    Err(LightningModuleError::InvalidEncryptedPreimage)
-                } else {
-                    Ok(TransactionItemAmount::ZERO)
+                self.validate_offer(db, offer)?;
+                Ok(TransactionItemAmount::ZERO)
+            }
+            ContractOrOfferOutput::OfferBatch(offers) => {
+                if offers.len() > MAX_OFFER_BATCH_SIZE {
+                    return Err(LightningModuleError::OfferBatchTooLarge(offers.len()));
+                }
+
+                let mut hashes = HashSet::new();
+                for offer in offers {
+                    if !hashes.insert(offer.hash) {
+                        return Err(LightningModuleError::DuplicateOffer(offer.hash));
+                    }
+                    self.validate_offer(db, offer)?;
                 }
+
+                Ok(TransactionItemAmount::ZERO)
             }
             ContractOrOfferOutput::CancelOutgoing {
                 contract,
                 gateway_signature,
             } => {
                 let contract_account = self
-                    .db
-                    .get_value(&ContractKey(*contract))
-                    .expect("DB error")
+                    .cached_contract_account(db, *contract)
                     .ok_or(LightningModuleError::UnknownContract(*contract))?;
 
                 let outgoing_contract = match &contract_account.contract {
@@ -357,90 +622,12 @@ impl FederationModule for LightningModule {
 
     fn apply_output<'a>(
         &'a self,
-        mut batch: BatchTx<'a>,
+        batch: BatchTx<'a>,
         output: &'a Self::TxOutput,
         out_point: OutPoint,
     ) -> Result<TransactionItemAmount, Self::Error> {
-        let amount = self.validate_output(output)?;
-
-        match output {
-            ContractOrOfferOutput::Contract(contract) => {
-                let contract_db_key = ContractKey(contract.contract.contract_id());
-                let updated_contract_account = self
-                    .db
-                    .get_value(&contract_db_key)
-                    .expect("DB error")
-                    .map(|mut value: ContractAccount| {
-                        value.amount += amount.amount;
-                        value
-                    })
-                    .unwrap_or_else(|| ContractAccount {
-                        amount: amount.amount,
-                        contract: contract.contract.clone().to_funded(out_point),
-                    });
-                batch.append_insert(contract_db_key, updated_contract_account);
-
-                batch.append_insert_new(
-                    ContractUpdateKey(out_point),
-                    OutputOutcome::Contract {
-                        id: contract.contract.contract_id(),
-                        outcome: contract.contract.to_outcome(),
-                    },
-                );
-
-                if let Contract::Incoming(incoming) = &contract.contract {
-                    let offer = self
-                        .db
-                        .get_value(&OfferKey(incoming.hash))
-                        .expect("DB error")
-                        .expect("offer exists if output is valid");
-
-                    let decryption_share = self
-                        .cfg
-                        .threshold_sec_key
-                        .decrypt_share(&incoming.encrypted_preimage.0)
-                        .expect("We checked for decryption share validity on contract creation");
-                    batch.append_insert_new(
-                        ProposeDecryptionShareKey(contract.contract.contract_id()),
-                        PreimageDecryptionShare(decryption_share),
-                    );
-                    batch.append_delete(OfferKey(offer.hash));
-                }
-            }
-            ContractOrOfferOutput::Offer(offer) => {
-                batch.append_insert_new(
-                    ContractUpdateKey(out_point),
-                    OutputOutcome::Offer { id: offer.id() },
-                );
-                // TODO: sanity-check encrypted preimage size
-                batch.append_insert_new(OfferKey(offer.hash), (*offer).clone());
-            }
-            ContractOrOfferOutput::CancelOutgoing { contract, .. } => {
-                let updated_contract_account = {
-                    let mut contract_account = self
-                        .db
-                        .get_value(&ContractKey(*contract))
-                        .expect("DB error")
-                        .expect("Contract exists if output is valid");
-
-                    let outgoing_contract = match &mut contract_account.contract {
-                        FundedContract::Outgoing(contract) => contract,
-                        _ => {
-                            panic!("Contract type was checked in validate_output");
-                        }
-                    };
-
-                    outgoing_contract.cancelled = true;
-
-                    contract_account
-                };
-
-                batch.append_insert(ContractKey(*contract), updated_contract_account);
-            }
-        }
-
-        batch.commit();
-        Ok(amount)
+        self.apply_output_with_effect(batch, output, out_point)
+            .map(|(amount, _)| amount)
     }
 
     #[instrument(skip_all)]
@@ -450,36 +637,61 @@ impl FederationModule for LightningModule {
         mut batch: BatchTx<'a>,
         _rng: impl RngCore + CryptoRng + 'a,
     ) -> Vec<PeerId> {
-        // Decrypt preimages
-        let preimage_decryption_shares = self
-            .db
-            .find_by_prefix(&AgreedDecryptionShareKeyPrefix)
-            .map(|res| {
-                let (key, value) = res.expect("DB error");
-                (key.0, (key.1, value))
-            })
-            .into_group_map();
+        // Decrypt preimages, without automatic decryption the shares accumulate until
+        // `decrypt_contract` is called
+        let ready_contracts = if self.cfg.auto_decrypt {
+            self.db
+                .find_by_prefix(&DecryptionReadyKeyPrefix)
+                .map(|res| res.expect("DB error").0 .0)
+                .collect()
+        } else {
+            vec![]
+        };
+
+        self.track_share_proposals(&mut batch);
+        self.expire_freeze_votes(&mut batch);
 
         let mut bad_peers = vec![];
-        for (contract_id, shares) in preimage_decryption_shares {
-            let peers: Vec<PeerId> = shares.iter().map(|(peer, _)| *peer).collect();
+        for contract_id in ready_contracts {
+            let shares = self.agreed_decryption_shares(contract_id);
             let span = info_span!("decrypt_preimage", %contract_id);
             let _gaurd = span.enter();
 
-            let incoming_contract = match self.get_contract_account(contract_id) {
-                Some(ContractAccount {
-                    contract: FundedContract::Incoming(incoming),
-                    ..
-                }) => incoming.contract,
-                _ => {
+            // Nothing to validate or blame peers for, the contract stays ready until shares exist
+            if shares.is_empty() {
+                trace!("No agreed decryption shares for ready contract, skipping");
+                continue;
+            }
+            let peers: Vec<PeerId> = shares.iter().map(|(peer, _)| *peer).collect();
+
+            let account = self.get_contract_account(contract_id);
+            let incoming = match account
+                .as_ref()
+                .and_then(|account| account.contract.as_incoming())
+            {
+                Some(incoming) => incoming,
+                None => {
                     warn!("Received decryption share for non-existent incoming contract");
                     for peer in peers {
                         batch.append_delete(AgreedDecryptionShareKey(contract_id, peer));
                     }
+                    batch.append_delete(DecryptionReadyKey(contract_id));
                     continue;
                 }
             };
 
+            // Shares are agreed upon in the epoch after the funding at the earliest, so the delay
+            // counts from there
+            if self.cfg.decrypt_delay_epochs != 0 {
+                let decryptable_epoch =
+                    incoming.funding_epoch + 1 + u64::from(self.cfg.decrypt_delay_epochs);
+                if self.current_epoch() < decryptable_epoch {
+                    debug!(%decryptable_epoch, "Delaying decryption of recently funded contract");
+                    continue;
+                }
+            }
+            let incoming_contract = &incoming.contract;
+
             let valid_shares: HashMap<PeerId, PreimageDecryptionShare> = shares
                 .into_iter()
                 .filter(|(peer, share)| {
@@ -504,99 +716,11 @@ impl FederationModule for LightningModule {
                 );
                 continue;
             }
-            debug!("Beginning to decrypt preimage");
-
-            let contract = self
-                .get_contract_account(contract_id)
-                .expect("decryption shares without contracts should be discarded earlier"); // FIXME: verify
-
-            let (incoming_contract, out_point) = match contract.contract {
-                FundedContract::Incoming(incoming) => (incoming.contract, incoming.out_point),
-                _ => panic!(
-                    "decryption shares without incoming contracts should be discarded earlier"
-                ),
-            };
-
-            if !matches!(
-                incoming_contract.decrypted_preimage,
-                DecryptedPreimage::Pending
-            ) {
-                warn!("Tried to decrypt the same preimage twice, this should not happen.");
-                continue;
-            }
-
-            let preimage_vec = match self.cfg.threshold_pub_keys.decrypt(
-                valid_shares
-                    .iter()
-                    .map(|(peer, share)| (peer.to_usize(), &share.0)),
-                &incoming_contract.encrypted_preimage.0,
-            ) {
-                Ok(preimage_vec) => preimage_vec,
-                Err(_) => {
-                    // TODO: check if that can happen even though shares are verified before
-                    error!(contract_hash = %incoming_contract.hash, "Failed to decrypt preimage");
-                    continue;
-                }
-            };
-
-            // Delete decryption shares once we've decrypted the preimage
-            batch.append_delete(ProposeDecryptionShareKey(contract_id));
-            for peer in peers {
-                batch.append_delete(AgreedDecryptionShareKey(contract_id, peer));
-            }
-
-            let decrypted_preimage = if preimage_vec.len() == 32
-                && incoming_contract.hash == bitcoin_hashes::sha256::Hash::hash(&preimage_vec)
-            {
-                let preimage = Preimage(
-                    preimage_vec
-                        .as_slice()
-                        .try_into()
-                        .expect("Invalid preimage length"),
-                );
-                if preimage.to_public_key().is_ok() {
-                    DecryptedPreimage::Some(preimage)
-                } else {
-                    DecryptedPreimage::Invalid
-                }
-            } else {
-                DecryptedPreimage::Invalid
-            };
-            debug!(?decrypted_preimage);
-
-            // TODO: maybe define update helper fn
-            // Update contract
-            let contract_db_key = ContractKey(contract_id);
-            let mut contract_account = self
-                .db
-                .get_value(&contract_db_key)
-                .expect("DB error")
-                .expect("checked before that it exists");
-            let mut incoming = match &mut contract_account.contract {
-                FundedContract::Incoming(incoming) => incoming,
-                _ => unreachable!("previously checked that it's an incoming contrac"),
-            };
-            incoming.contract.decrypted_preimage = decrypted_preimage.clone();
-            trace!(?contract_account, "Updating contract account");
-            batch.append_insert(contract_db_key, contract_account);
 
-            // Update output outcome
-            let outcome_db_key = ContractUpdateKey(out_point);
-            let mut outcome = self
-                .db
-                .get_value(&outcome_db_key)
-                .expect("DB error")
-                .expect("outcome was created on funding");
-            let incoming_contract_outcome_preimage = match &mut outcome {
-                OutputOutcome::Contract {
-                    outcome: ContractOutcome::Incoming(decryption_outcome),
-                    ..
-                } => decryption_outcome,
-                _ => panic!("We are expeccting an incoming contract"),
-            };
-            *incoming_contract_outcome_preimage = decrypted_preimage.clone();
-            batch.append_insert(outcome_db_key, outcome);
+            self.decrypt_preimage(contract_id, peers, &valid_shares, &mut batch);
         }
+
+        batch.append_insert(CurrentEpochKey, self.current_epoch() + 1);
         batch.commit();
 
         bad_peers
@@ -665,94 +789,6293 @@ impl FederationModule for LightningModule {
 
 impl LightningModule {
     pub fn new(cfg: LightningModuleConfig, db: Database) -> Self {
-        LightningModule { cfg, db }
+        if let Err(e) = cfg.validate() {
+            panic!("Invalid lightning module config: {}", e);
+        }
+
+        // Our key share index equals our peer id, see `LightningModuleConfig::validate_config`
+        let our_public_key_share = cfg.threshold_sec_key.public_key_share();
+        let our_peer_id = (0..cfg.total_peers)
+            .find(|&idx| cfg.threshold_pub_keys.public_key_share(idx) == our_public_key_share)
+            .map(|idx| PeerId::from(idx as u16))
+            .expect("Own key not found among pub keys.");
+
+        LightningModule {
+            cfg,
+            our_peer_id,
+            db,
+            contract_cache: None,
+            balance_sink: None,
+            spend_attempts: Mutex::new(HashMap::new()),
+            decryptions: watch::channel(HashMap::new()).0,
+        }
     }
 
-    fn validate_decryption_share(
-        &self,
-        peer: PeerId,
-        share: &PreimageDecryptionShare,
-        message: &EncryptedPreimage,
-    ) -> bool {
-        self.cfg
-            .threshold_pub_keys
-            .public_key_share(peer.to_usize())
-            .verify_decryption_share(&share.0, &message.0)
+    /// Keeps up to `capacity` recently read contract accounts in memory to avoid repeated
+    /// database reads of the same contract within an epoch
+    pub fn with_contract_cache(mut self, capacity: NonZeroUsize) -> Self {
+        self.contract_cache = Some(Mutex::new(ContractCache {
+            accounts: LruCache::new(capacity),
+            written: HashSet::new(),
+        }));
+        self
     }
 
-    pub fn get_offer(
-        &self,
-        payment_hash: bitcoin_hashes::sha256::Hash,
-    ) -> Option<IncomingContractOffer> {
-        self.db
-            .get_value(&OfferKey(payment_hash))
-            .expect("DB error")
+    /// Notifies `sink` of every contract balance change caused by applied inputs and outputs, e.g.
+    /// for an accounting module. Since events are emitted while the changes are appended to the
+    /// epoch's batch they can precede the batch being committed to the database.
+    pub fn with_balance_sink(mut self, sink: BalanceSink) -> Self {
+        self.balance_sink = Some(sink);
+        self
     }
 
-    pub fn get_offers(&self) -> Vec<IncomingContractOffer> {
-        self.db
-            .find_by_prefix(&OfferKeyPrefix)
-            .map(|res| res.expect("DB error").1)
-            .collect()
+    fn emit_balance_change(&self, id: ContractId, old: Amount, new: Amount) {
+        if let Some(sink) = &self.balance_sink {
+            sink(ContractBalanceChanged { id, old, new });
+        }
     }
 
-    pub fn get_contract_account(&self, contract_id: ContractId) -> Option<ContractAccount> {
-        self.db
-            .get_value(&ContractKey(contract_id))
-            .expect("DB error")
+    /// Has to be called whenever a write to the contract account `contract_id` is appended to a
+    /// batch so the cache never serves the outdated account
+    fn invalidate_contract(&self, contract_id: ContractId) {
+        if let Some(cache) = &self.contract_cache {
+            let mut cache = cache.lock().expect("lock poisoned");
+            cache.accounts.pop(&contract_id);
+            cache.written.insert(contract_id);
+        }
     }
 
-    pub fn list_gateways(&self) -> Vec<LightningGateway> {
+    /// Called at the beginning of each epoch, once the previous epoch's batch has been applied
+    fn reset_contract_cache(&self) {
+        if let Some(cache) = &self.contract_cache {
+            let mut cache = cache.lock().expect("lock poisoned");
+            cache.accounts.clear();
+            cache.written.clear();
+        }
+    }
+
+    fn apply_contract_output(
+        &self,
+        batch: &mut BatchTx,
+        contract: &ContractOutput,
+        amount: Amount,
+        out_point: OutPoint,
+    ) -> ApplyOutputEffect {
+        let contract_id = contract.contract.contract_id();
+        let existing_contract_account = self.get_contract_account(contract_id);
+        let effect = match (&existing_contract_account, &contract.contract) {
+            (Some(_), _) => ApplyOutputEffect::ContractToppedUp(contract_id),
+            (None, Contract::Incoming(_)) => ApplyOutputEffect::DecryptionStarted(contract_id),
+            (None, _) => ApplyOutputEffect::ContractFunded(contract_id),
+        };
+        let old_amount = existing_contract_account
+            .as_ref()
+            .map_or(Amount::ZERO, |account| account.amount);
+        let updated_contract_account = existing_contract_account
+            .map(|mut value: ContractAccount| {
+                value.amount += amount;
+                value
+            })
+            .unwrap_or_else(|| ContractAccount {
+                amount,
+                contract: contract
+                    .contract
+                    .clone()
+                    .to_funded(out_point, self.current_epoch()),
+            });
+        self.invalidate_contract(contract_id);
+        self.emit_balance_change(contract_id, old_amount, updated_contract_account.amount);
+        batch.append_insert(ContractKey(contract_id), updated_contract_account);
+
+        // Outputs can't access the consensus block height, so the highest one seen so far
+        // is used. Only the first funding of a contract counts as its creation.
+        if self.contract_created_height(contract_id).is_none() {
+            batch.append_insert(
+                ContractCreatedKey(contract_id),
+                Self::max_seen_block_height(self.db.read_only(), 0),
+            );
+        }
+
+        batch.append_insert_new(
+            ContractUpdateKey(out_point),
+            OutputOutcome::Contract {
+                id: contract.contract.contract_id(),
+                outcome: contract.contract.to_outcome(),
+            },
+        );
+
+        if let Contract::Incoming(incoming) = &contract.contract {
+            let offer_id = OfferId::from_hash(incoming.hash);
+            let decryption_share = self
+                .cfg
+                .threshold_sec_key
+                .decrypt_share(&incoming.encrypted_preimage.0)
+                .expect("We checked for decryption share validity on contract creation");
+            batch.append_insert_new(
+                ProposeDecryptionShareKey(contract.contract.contract_id()),
+                PreimageDecryptionShare(decryption_share),
+            );
+            if let Some(fallback_sec_key) = &self.cfg.fallback_sec_key {
+                let fallback_share = fallback_sec_key
+                    .decrypt_share(&incoming.encrypted_preimage.0)
+                    .expect("We checked for decryption share validity on contract creation");
+                batch.append_insert_new(
+                    ProposeFallbackShareKey(contract.contract.contract_id()),
+                    PreimageDecryptionShare(fallback_share),
+                );
+            }
+            batch.append_insert(FundedOfferKey(offer_id), contract.contract.contract_id());
+            batch.append_insert(
+                PaymentHashIndexKey(incoming.hash),
+                contract.contract.contract_id(),
+            );
+            batch.append_insert(SpentOfferKey(offer_id), ());
+            batch.append_maybe_delete(OfferCreatedKey(offer_id));
+            // Batch items are applied in order, so the offer is deleted last and never appears
+            // consumed before the contract funding it exists. Several contracts funding the same
+            // offer in one epoch all find it in the database, so it may already be deleted once
+            // this batch item is applied.
+            batch.append_maybe_delete(OfferKey(incoming.hash));
+        }
+
+        effect
+    }
+
+    /// Moves the `change` left over by a partial spend into the account contract `change_contract`
+    fn fund_change_contract(
+        &self,
+        batch: &mut BatchTx,
+        change_contract: &AccountContract,
+        change: Amount,
+    ) {
+        let contract_id = change_contract.contract_id();
+        let existing_contract_account = self.get_contract_account(contract_id);
+        let old_amount = existing_contract_account
+            .as_ref()
+            .map_or(Amount::ZERO, |account| account.amount);
+        let updated_contract_account = match existing_contract_account {
+            Some(mut account) => {
+                account.amount += change;
+                account
+            }
+            None => {
+                batch.append_insert(
+                    ContractCreatedKey(contract_id),
+                    Self::max_seen_block_height(self.db.read_only(), 0),
+                );
+                ContractAccount {
+                    amount: change,
+                    contract: FundedContract::Account(change_contract.clone()),
+                }
+            }
+        };
+        self.invalidate_contract(contract_id);
+        self.emit_balance_change(contract_id, old_amount, updated_contract_account.amount);
+        batch.append_insert(ContractKey(contract_id), updated_contract_account);
+    }
+
+    /// Validates funding a contract, incoming contracts have to fund either an already registered
+    /// offer or the `bundled_offer` registered by the same output
+    fn validate_contract_output(
+        &self,
+        db: ReadOnlyDb,
+        contract: &ContractOutput,
+        bundled_offer: Option<&IncomingContractOffer>,
+    ) -> Result<TransactionItemAmount, LightningModuleError> {
+        contract.contract.validate_structure()?;
+
+        if let Some(granularity) = self.cfg.amount_granularity {
+            if granularity != Amount::ZERO && contract.amount.milli_sat % granularity.milli_sat != 0
+            {
+                return Err(LightningModuleError::AmountNotAligned(
+                    contract.amount,
+                    granularity,
+                ));
+            }
+        }
+
+        if let (Contract::Outgoing(outgoing), Some(max_timelock_delta)) =
+            (&contract.contract, self.cfg.max_timelock_delta)
+        {
+            // Outputs can't access the consensus block height, so we use the highest one
+            // the module has seen so far
+            let max_timelock =
+                Self::max_seen_block_height(db, 0).saturating_add(max_timelock_delta);
+            if outgoing.timelock > max_timelock {
+                return Err(LightningModuleError::TimelockTooFar(
+                    outgoing.timelock,
+                    max_timelock,
+                ));
+            }
+        }
+
+        // Incoming contracts are special, they need to match an offer
+        if let Contract::Incoming(incoming) = &contract.contract {
+            // Otherwise the gateway could reclaim the funds right away and still learn the preimage
+            // once it gets decrypted. Outputs can't access the consensus block height, so we use
+            // the highest one the module has seen so far
+            if let Some(refund_height) = incoming.incoming_refund_height {
+                let min_refund_height =
+                    Self::max_seen_block_height(db, 0).saturating_add(MIN_INCOMING_REFUND_DELTA);
+                if refund_height < min_refund_height {
+                    return Err(LightningModuleError::RefundHeightTooEarly(
+                        refund_height,
+                        min_refund_height,
+                    ));
+                }
+            }
+
+            // Raw preimages don't have to be public keys, so the recipients' keys lock the funds
+            if incoming.recipients.is_empty()
+                && self.cfg.preimage_policy == PreimagePolicy::RawHashPreimage
+            {
+                return Err(LightningModuleError::MissingClaimKey);
+            }
+            // Each recipient claims their share with a separate input, which a contract that has to
+            // be spent entirely can't support
+            if incoming.recipients.len() > 1 && !self.cfg.allow_partial_spends {
+                return Err(LightningModuleError::SplitWithoutPartialSpends);
+            }
+            if !incoming.recipients.is_empty() {
+                let split_amount = incoming
+                    .recipients
+                    .iter()
+                    .map(|(_, amount)| *amount)
+                    .sum::<Amount>();
+                if split_amount != contract.amount {
+                    return Err(LightningModuleError::InvalidRecipientSplit(
+                        contract.amount,
+                        split_amount,
+                    ));
+                }
+            }
+
+            let offer = match bundled_offer {
+                Some(offer) => offer.clone(),
+                None => db
+                    .get_value(&OfferKey(incoming.hash))
+                    .expect("DB error")
+                    .ok_or(LightningModuleError::NoOffer(incoming.hash))?,
+            };
+
+            // Otherwise the federation would decrypt a ciphertext the seller never
+            // committed to
+            if incoming.encrypted_preimage != offer.encrypted_preimage {
+                return Err(LightningModuleError::PreimageMismatch(incoming.hash));
+            }
+
+            // Outputs can't access the consensus block height, so we use the highest one
+            // the module has seen so far
+            if let Some(not_before) = offer.not_before {
+                if Self::max_seen_block_height(db, 0) < not_before {
+                    return Err(LightningModuleError::OfferNotYetActive(not_before));
+                }
+            }
+
+            if contract.amount < offer.amount {
+                // If the account is not sufficiently funded fail the output
+                return Err(LightningModuleError::InsufficientIncomingFunding(
+                    offer.amount,
+                    contract.amount,
+                ));
+            }
+        }
+
+        if contract.amount == Amount::ZERO {
+            Err(LightningModuleError::ZeroOutput)
+        } else {
+            Ok(TransactionItemAmount {
+                amount: contract.amount,
+                fee: self.cfg.fee_consensus.contract_output,
+            })
+        }
+    }
+
+    fn validate_offer(
+        &self,
+        db: ReadOnlyDb,
+        offer: &IncomingContractOffer,
+    ) -> Result<(), LightningModuleError> {
+        // A zero amount offer would be fulfilled by any incoming contract
+        if offer.amount == Amount::ZERO {
+            return Err(LightningModuleError::ZeroOffer);
+        }
+
+        if !offer.encrypted_preimage.0.verify() {
+            return Err(LightningModuleError::InvalidEncryptedPreimage);
+        }
+
+        match &offer.signature {
+            Some(signature) => secp256k1::global::SECP256K1
+                .verify_schnorr(
+                    &signature.signature,
+                    &offer.signing_message().into(),
+                    &signature.receiver_key,
+                )
+                .map_err(|_| LightningModuleError::InvalidOfferSignature)?,
+            None if self.cfg.require_signed_offers => {
+                return Err(LightningModuleError::UnsignedOffer);
+            }
+            None => {}
+        }
+
+        // Re-registering a funded offer would allow funding the settled preimage a second time
+        if db
+            .get_value(&SpentOfferKey(offer.id()))
+            .expect("DB error")
+            .is_some()
+        {
+            return Err(LightningModuleError::OfferAlreadyFunded(offer.id()));
+        }
+
+        // Clients may resubmit an offer if they missed its confirmation, which is a no-op as long
+        // as it matches the registered one in every field
+        if let Some(existing) = db.get_value(&OfferKey(offer.hash)).expect("DB error") {
+            if existing != *offer {
+                return Err(LightningModuleError::OfferConflict(offer.hash));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register_offer(&self, batch: &mut BatchTx, offer: &IncomingContractOffer) {
+        // Resubmitted offers were checked to be identical in validate_output
+        if self.get_offer(offer.hash).is_some() {
+            return;
+        }
+
+        // Defense in depth against ciphertexts `verify` wrongly accepts, the offer is registered
+        // either way since outputs can't fail to apply
+        if self
+            .preview_decryption_share(&offer.encrypted_preimage)
+            .is_none()
+        {
+            error!(
+                offer_hash = %offer.hash,
+                "Our decryption share for the offer is malformed, funding it may never decrypt"
+            );
+        }
+
+        batch.append_insert_new(OfferKey(offer.hash), offer.clone());
+        // Outputs can't access the consensus block height, so we use the highest one the module
+        // has seen so far
+        batch.append_insert_new(
+            OfferCreatedKey(offer.id()),
+            Self::max_seen_block_height(self.db.read_only(), 0),
+        );
+    }
+
+    /// Computes our decryption share of `encrypted` and checks it against our public key share,
+    /// returning `None` if it wouldn't be accepted by the other guardians
+    fn preview_decryption_share(
+        &self,
+        encrypted: &EncryptedPreimage,
+    ) -> Option<PreimageDecryptionShare> {
+        let share = self.cfg.threshold_sec_key.decrypt_share(&encrypted.0)?;
+        let share = PreimageDecryptionShare(share);
+        self.validate_decryption_share(self.our_peer_id, &share, encrypted)
+            .then_some(share)
+    }
+
+    fn process_decryption_share(
+        &self,
+        dbtx: &mut DatabaseTransaction<'_>,
+        peer: PeerId,
+        decryption_share: DecryptionShareCI,
+    ) {
+        let span = info_span!("process decryption share", %peer);
+        let _guard = span.enter();
+
+        if decryption_share.peer != peer {
+            warn!(
+                share_peer = %decryption_share.peer,
+                "Received decryption share attributed to another peer"
+            );
+            return;
+        }
+
+        let contract_id = decryption_share.contract_id;
+        dbtx.insert_new_entry(
+            &AgreedDecryptionShareKey(contract_id, peer),
+            &decryption_share.share,
+        )
+        .expect("DB Error");
+
+        // Only contracts that may be decryptable are considered at the end of the epoch
+        let share_count = dbtx
+            .find_by_prefix(&AgreedDecryptionShareContractPrefix(contract_id))
+            .count();
+        if share_count >= self.cfg.threshold {
+            dbtx.insert_entry(&DecryptionReadyKey(contract_id), &())
+                .expect("DB Error");
+        }
+    }
+
+    /// Stores `peer`'s share of the fallback key set, which is only used if the preimage doesn't
+    /// decrypt to a valid one with the primary key set
+    fn process_fallback_share(
+        &self,
+        dbtx: &mut DatabaseTransaction<'_>,
+        peer: PeerId,
+        decryption_share: DecryptionShareCI,
+    ) {
+        if decryption_share.peer != peer || self.cfg.fallback_pub_keys.is_none() {
+            warn!(%peer, "Ignoring unexpected fallback decryption share");
+            return;
+        }
+        if !self
+            .get_contract_account(decryption_share.contract_id)
+            .map_or(false, |account| account.contract.as_incoming().is_some())
+        {
+            warn!(%peer, "Received fallback decryption share for non-existent incoming contract");
+            return;
+        }
+
+        dbtx.insert_new_entry(
+            &AgreedFallbackShareKey(decryption_share.contract_id, peer),
+            &decryption_share.share,
+        )
+        .expect("DB Error");
+    }
+
+    /// Records `peer`'s vote to freeze or unfreeze `contract_id`, applying the change once
+    /// `threshold` peers agree
+    fn process_freeze_vote(
+        &self,
+        dbtx: &mut DatabaseTransaction<'_>,
+        peer: PeerId,
+        contract_id: ContractId,
+        freeze: bool,
+    ) {
+        if self.get_contract_account(contract_id).is_none() {
+            warn!(%peer, %contract_id, "Received freeze vote for non-existent contract");
+            return;
+        }
+
+        dbtx.insert_entry(&AgreedFreezeKey(contract_id, peer), &freeze)
+            .expect("DB Error");
+        if dbtx
+            .get_value(&FreezeVoteEpochKey(contract_id))
+            .expect("DB Error")
+            .is_none()
+        {
+            dbtx.insert_entry(&FreezeVoteEpochKey(contract_id), &self.current_epoch())
+                .expect("DB Error");
+        }
+
+        let votes = dbtx
+            .find_by_prefix(&AgreedFreezeContractPrefix(contract_id))
+            .map(|res| res.expect("DB Error"))
+            .collect::<Vec<_>>();
+        if votes.iter().filter(|(_, vote)| *vote == freeze).count() < self.cfg.threshold {
+            return;
+        }
+
+        info!(%contract_id, %freeze, "Federation agreed on freezing contract");
+        if freeze {
+            dbtx.insert_entry(&FrozenContractKey(contract_id), &())
+                .expect("DB Error");
+        } else {
+            dbtx.maybe_remove_entry(&FrozenContractKey(contract_id))
+                .expect("DB Error");
+        }
+
+        for (key, _) in votes {
+            dbtx.remove_entry(&key).expect("DB Error");
+        }
+        dbtx.remove_entry(&FreezeVoteEpochKey(contract_id))
+            .expect("DB Error");
+        if dbtx
+            .get_value(&ProposeFreezeKey(contract_id))
+            .expect("DB Error")
+            == Some(freeze)
+        {
+            dbtx.remove_entry(&ProposeFreezeKey(contract_id))
+                .expect("DB Error");
+        }
+    }
+
+    fn agreed_decryption_shares(
+        &self,
+        contract_id: ContractId,
+    ) -> Vec<(PeerId, PreimageDecryptionShare)> {
         self.db
-            .find_by_prefix(&LightningGatewayKeyPrefix)
-            .map(|res| res.expect("DB error").1)
+            .find_by_prefix(&AgreedDecryptionShareContractPrefix(contract_id))
+            .map(|res| {
+                let (key, value) = res.expect("DB error");
+                (key.1, value)
+            })
             .collect()
     }
 
-    pub fn register_gateway(&self, gateway: LightningGateway) {
+    /// Checks if enough valid decryption shares for `contract_id` were agreed on to decrypt its
+    /// preimage, in which case proposing our share would only add redundant consensus traffic.
+    /// Shares are only validated once the agreed ones could suffice, which rarely happens outside
+    /// the epoch in which the contract gets decrypted anyway.
+    fn share_agreement_reached(&self, contract_id: ContractId) -> bool {
+        let shares = self.agreed_decryption_shares(contract_id);
+        if shares.len() < self.cfg.threshold {
+            return false;
+        }
+
+        let encrypted_preimage = match self
+            .get_contract_account(contract_id)
+            .and_then(|account| account.contract.as_incoming().cloned())
+        {
+            Some(incoming) => incoming.contract.encrypted_preimage,
+            None => return false,
+        };
+        let valid_shares = shares
+            .iter()
+            .filter(|(peer, share)| {
+                self.validate_decryption_share(*peer, share, &encrypted_preimage)
+            })
+            .count();
+        valid_shares >= self.cfg.threshold
+    }
+
+    /// Removes a fully spent contract and all keys indexed by its id. The deletions are appended
+    /// in a fixed order so all guardians produce identical batches: first the contract account,
+    /// then its decryption state, its freeze votes and finally its bookkeeping entries. Keys of
+    /// several peers or recipients are ordered by peer id or recipient key. Failed spend attempts
+    /// counted in memory are forgotten as well.
+    ///
+    /// The offer funding and payment hash indexes are kept, so clients can still find the contract
+    /// that paid an offer once it was spent. So are the contract's outcomes and the spent contract
+    /// index of the epoch history.
+    fn delete_spent_contract(
+        &self,
+        batch: &mut BatchTx,
+        contract_id: ContractId,
+        contract_account: &ContractAccount,
+    ) {
+        batch.append_delete(ContractKey(contract_id));
+
+        // Decryption state, partially guardian-local so it may be absent
+        batch.append_maybe_delete(ProposeDecryptionShareKey(contract_id));
+        let mut share_peers = self
+            .agreed_decryption_shares(contract_id)
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect::<Vec<_>>();
+        share_peers.sort();
+        for peer in share_peers {
+            batch.append_delete(AgreedDecryptionShareKey(contract_id, peer));
+        }
+        batch.append_maybe_delete(DecryptionReadyKey(contract_id));
+        batch.append_maybe_delete(ShareProposalAttemptKey(contract_id));
+        batch.append_maybe_delete(FailedShareKey(contract_id));
+        batch.append_maybe_delete(ProposeFallbackShareKey(contract_id));
+        let mut fallback_share_peers = self
+            .agreed_fallback_shares(contract_id)
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect::<Vec<_>>();
+        fallback_share_peers.sort();
+        for peer in fallback_share_peers {
+            batch.append_delete(AgreedFallbackShareKey(contract_id, peer));
+        }
+        batch.append_maybe_delete(RedundantDecryptionAttemptKey(contract_id));
+        batch.append_maybe_delete(InvalidPreimageRefundableKey(contract_id));
+
+        // Freeze votes, our own one is guardian-local
+        batch.append_maybe_delete(ProposeFreezeKey(contract_id));
+        let mut vote_peers = self
+            .db
+            .find_by_prefix(&AgreedFreezeContractPrefix(contract_id))
+            .map(|res| res.expect("DB error").0 .1)
+            .collect::<Vec<_>>();
+        vote_peers.sort();
+        for peer in vote_peers {
+            batch.append_delete(AgreedFreezeKey(contract_id, peer));
+        }
+        batch.append_maybe_delete(FreezeVoteEpochKey(contract_id));
+        batch.append_maybe_delete(FrozenContractKey(contract_id));
+
+        // Bookkeeping, recipients' claims may only have been appended to this batch
+        if let FundedContract::Incoming(incoming) = &contract_account.contract {
+            let mut recipients = incoming
+                .contract
+                .recipients
+                .iter()
+                .map(|(recipient, _)| *recipient)
+                .collect::<Vec<_>>();
+            recipients.sort();
+            recipients.dedup();
+            for recipient in recipients {
+                batch.append_maybe_delete(RecipientClaimedKey(contract_id, recipient));
+            }
+        }
+        batch.append_maybe_delete(ContractCreatedKey(contract_id));
+
+        self.spend_attempts
+            .lock()
+            .expect("lock poisoned")
+            .remove(&contract_id);
+    }
+
+    /// Combines the `valid_shares` of `peers` to decrypt the preimage of the incoming contract
+    /// `contract_id` and updates the contract and its outcome accordingly. Returns `None` if the
+    /// preimage couldn't be decrypted.
+    fn decrypt_preimage(
+        &self,
+        contract_id: ContractId,
+        peers: Vec<PeerId>,
+        valid_shares: &HashMap<PeerId, PreimageDecryptionShare>,
+        batch: &mut BatchTx,
+    ) -> Option<DecryptedPreimage> {
+        debug!("Beginning to decrypt preimage");
+
+        let contract = self
+            .get_contract_account(contract_id)
+            .expect("decryption shares without contracts should be discarded earlier"); // FIXME: verify
+
+        let (incoming_contract, out_point) = match contract.contract {
+            FundedContract::Incoming(incoming) => (incoming.contract, incoming.out_point),
+            _ => panic!("decryption shares without incoming contracts should be discarded earlier"),
+        };
+
+        if !matches!(
+            incoming_contract.decrypted_preimage,
+            DecryptedPreimage::Pending
+        ) {
+            warn!("Tried to decrypt the same preimage twice, this should not happen.");
+            // Counted so operators can tell if shares systematically aren't cleaned up
+            let attempts = self
+                .db
+                .get_value(&RedundantDecryptionAttemptKey(contract_id))
+                .expect("DB error")
+                .unwrap_or(0);
+            batch.append_insert(RedundantDecryptionAttemptKey(contract_id), attempts + 1);
+            return None;
+        }
+
+        let primary_preimage = match self.cfg.threshold_pub_keys.decrypt(
+            self.key_set_decryption_shares(valid_shares),
+            &incoming_contract.encrypted_preimage.0,
+        ) {
+            Ok(preimage_vec) => {
+                Some(self.check_decrypted_preimage(&incoming_contract, &preimage_vec))
+            }
+            Err(_) => {
+                // TODO: check if that can happen even though shares are verified before
+                error!(contract_hash = %incoming_contract.hash, "Failed to decrypt preimage");
+                None
+            }
+        };
+
+        // Retry with the fallback key set before giving up on the preimage
+        let fallback_shares = self.agreed_fallback_shares(contract_id);
+        let decrypted_preimage = match primary_preimage {
+            Some(preimage) if preimage != DecryptedPreimage::Invalid => preimage,
+            primary_preimage => {
+                let fallback_preimage = self
+                    .decrypt_with_fallback(
+                        &incoming_contract.encrypted_preimage,
+                        fallback_shares.clone(),
+                    )
+                    .map(|preimage_vec| {
+                        self.check_decrypted_preimage(&incoming_contract, &preimage_vec)
+                    });
+                match (fallback_preimage, primary_preimage) {
+                    (
+                        Some(preimage @ (DecryptedPreimage::Some(_) | DecryptedPreimage::Raw(_))),
+                        _,
+                    ) => {
+                        info!("Preimage decrypted using the fallback key set");
+                        preimage
+                    }
+                    (_, Some(invalid_preimage)) => invalid_preimage,
+                    // Neither key set could decrypt, so keep the shares and try again later
+                    (_, None) => return None,
+                }
+            }
+        };
+        debug!(?decrypted_preimage);
+
+        // Delete decryption shares once we've decrypted the preimage
+        // We may have given up on proposing our share already
+        batch.append_maybe_delete(ProposeDecryptionShareKey(contract_id));
+        batch.append_maybe_delete(ShareProposalAttemptKey(contract_id));
+        batch.append_maybe_delete(FailedShareKey(contract_id));
+        for peer in peers {
+            batch.append_delete(AgreedDecryptionShareKey(contract_id, peer));
+        }
+        batch.append_delete(DecryptionReadyKey(contract_id));
+        batch.append_maybe_delete(ProposeFallbackShareKey(contract_id));
+        for (peer, _) in fallback_shares {
+            batch.append_delete(AgreedFallbackShareKey(contract_id, peer));
+        }
+
+        // TODO: maybe define update helper fn
+        // Update contract
+        let mut contract_account = self
+            .get_contract_account(contract_id)
+            .expect("checked before that it exists");
+        let mut incoming = match &mut contract_account.contract {
+            FundedContract::Incoming(incoming) => incoming,
+            _ => unreachable!("previously checked that it's an incoming contrac"),
+        };
+        incoming.contract.decrypted_preimage = decrypted_preimage.clone();
+        let gateway_key = incoming.contract.gateway_key;
+        trace!(?contract_account, "Updating contract account");
+        self.invalidate_contract(contract_id);
+        batch.append_insert(ContractKey(contract_id), contract_account);
+        self.decryptions.send_modify(|decryptions| {
+            decryptions.insert(contract_id, decrypted_preimage.clone());
+        });
+
+        // Signals the gateway's client that it can reclaim the funds right away
+        if decrypted_preimage == DecryptedPreimage::Invalid && self.cfg.auto_refund_invalid {
+            batch.append_insert(InvalidPreimageRefundableKey(contract_id), gateway_key);
+        }
+
+        // Update output outcome. A mismatching outcome indicates a bug or corrupted data, which
+        // shouldn't halt consensus, so it is left untouched in that case.
+        let outcome_db_key = ContractUpdateKey(out_point);
+        let mut outcome = self
+            .db
+            .get_value(&outcome_db_key)
+            .expect("DB error")
+            .expect("outcome was created on funding");
+        let updated = match &mut outcome {
+            OutputOutcome::Contract {
+                id,
+                outcome: ContractOutcome::Incoming(decryption_outcome),
+            } if *id == contract_id => {
+                *decryption_outcome = decrypted_preimage.clone();
+                true
+            }
+            _ => false,
+        };
+        if updated {
+            batch.append_insert(outcome_db_key, outcome);
+        } else {
+            error!(
+                ?outcome,
+                "Stored outcome doesn't belong to the incoming contract, not updating it"
+            );
+        }
+
+        Some(decrypted_preimage)
+    }
+
+    /// Maps a decrypted preimage to [`DecryptedPreimage::Invalid`] unless it's the one `incoming`
+    /// sells and satisfies the preimage policy
+    fn check_decrypted_preimage(
+        &self,
+        incoming: &IncomingContract,
+        preimage_vec: &[u8],
+    ) -> DecryptedPreimage {
+        if incoming.hash != self.payment_hash(preimage_vec) {
+            return DecryptedPreimage::Invalid;
+        }
+
+        let preimage = match <[u8; 32]>::try_from(preimage_vec) {
+            Ok(preimage) => Preimage(preimage),
+            Err(_) => {
+                return match self.cfg.preimage_policy {
+                    PreimagePolicy::Schnorr32 => DecryptedPreimage::Invalid,
+                    PreimagePolicy::RawHashPreimage => {
+                        DecryptedPreimage::Raw(preimage_vec.to_vec())
+                    }
+                }
+            }
+        };
+        let preimage_valid = match self.cfg.preimage_policy {
+            PreimagePolicy::Schnorr32 => preimage.to_public_key().is_ok(),
+            PreimagePolicy::RawHashPreimage => true,
+        };
+        if preimage_valid {
+            DecryptedPreimage::Some(preimage)
+        } else {
+            DecryptedPreimage::Invalid
+        }
+    }
+
+    /// Returns the agreed upon fallback key set decryption shares of `contract_id`
+    fn agreed_fallback_shares(
+        &self,
+        contract_id: ContractId,
+    ) -> Vec<(PeerId, PreimageDecryptionShare)> {
         self.db
-            .insert_entry(&LightningGatewayKey(gateway.node_pub_key), &gateway)
-            .expect("DB error");
+            .find_by_prefix(&AgreedFallbackShareContractPrefix(contract_id))
+            .map(|res| {
+                let (AgreedFallbackShareKey(_, peer), share) = res.expect("DB error");
+                (peer, share)
+            })
+            .collect()
     }
-}
 
-fn block_height(interconnect: &dyn ModuleInterconect) -> u32 {
-    // This is a future because we are normally reading from a network socket. But for internal
-    // calls the data is available instantly in one go, so we can just block on it.
-    let body = futures::executor::block_on(interconnect.call(
-        "wallet",
-        "/block_height".to_owned(),
-        Default::default(),
-    ))
-    .expect("Wallet module not present or malfunctioning!");
+    /// Decrypts `encrypted` using `shares` of the fallback key set, if one is configured and
+    /// enough of the shares are valid
+    fn decrypt_with_fallback(
+        &self,
+        encrypted: &EncryptedPreimage,
+        shares: Vec<(PeerId, PreimageDecryptionShare)>,
+    ) -> Option<Vec<u8>> {
+        let pub_keys = self.cfg.fallback_pub_keys.as_ref()?;
+        let valid_shares = shares
+            .into_iter()
+            .filter(|(peer, share)| {
+                verify_decryption_share(pub_keys, self.cfg.total_peers, *peer, share, encrypted)
+            })
+            .map(|(peer, share)| (peer.to_usize(), share.0))
+            .collect::<BTreeMap<_, _>>();
+        if valid_shares.len() < self.cfg.threshold {
+            warn!(
+                valid_shares = %valid_shares.len(),
+                shares_needed = %self.cfg.threshold,
+                "Too few fallback decryption shares"
+            );
+            return None;
+        }
 
-    serde_json::from_value(body).expect("Malformed block height response from wallet module!")
-}
+        pub_keys
+            .decrypt(
+                valid_shares.iter().map(|(idx, share)| (*idx, share)),
+                &encrypted.0,
+            )
+            .ok()
+    }
 
-#[derive(Debug, Error, Eq, PartialEq)]
-pub enum LightningModuleError {
-    #[error("The the input contract {0} does not exist")]
-    UnknownContract(ContractId),
-    #[error("The input contract has too little funds, got {0}, input spends {1}")]
-    InsufficientFunds(Amount, Amount),
-    #[error("An outgoing LN contract spend did not provide a preimage")]
-    MissingPreimage,
-    #[error("An outgoing LN contract spend provided a wrong preimage")]
-    InvalidPreimage,
-    #[error("Incoming contract not ready to be spent yet, decryption in progress")]
-    ContractNotReady,
-    #[error("Output contract value may not be zero unless it's an offer output")]
-    ZeroOutput,
-    #[error("Offer contains invalid threshold-encrypted data")]
-    InvalidEncryptedPreimage,
-    #[error(
-        "The incoming LN account requires more funding according to the offer (need {0} got {1})"
-    )]
-    InsufficientIncomingFunding(Amount, Amount),
-    #[error("No offer found for payment hash {0}")]
-    NoOffer(secp256k1::hashes::sha256::Hash),
-    #[error("Only outgoing contracts support cancellation")]
-    NotOutgoingContract,
-    #[error("Cancellation request wasn't properly signed")]
-    InvalidCancellationSignature,
+    /// Counts the epochs in which our proposed decryption shares didn't get agreed on. Shares that
+    /// failed [`MAX_SHARE_PROPOSALS`] times are most likely rejected for good, so they are moved
+    /// to [`FailedShareKey`] instead of being re-proposed forever. Contracts that are ready for
+    /// decryption don't need our share anymore and are skipped.
+    fn track_share_proposals(&self, batch: &mut BatchTx) {
+        for res in self.db.find_by_prefix(&ProposeDecryptionShareKeyPrefix) {
+            let (ProposeDecryptionShareKey(contract_id), share) = res.expect("DB error");
+
+            let agreed = self
+                .db
+                .get_value(&AgreedDecryptionShareKey(contract_id, self.our_peer_id))
+                .expect("DB error")
+                .is_some();
+            if agreed {
+                batch.append_maybe_delete(ShareProposalAttemptKey(contract_id));
+                continue;
+            }
+            let ready = self
+                .db
+                .get_value(&DecryptionReadyKey(contract_id))
+                .expect("DB error")
+                .is_some();
+            if ready {
+                continue;
+            }
+
+            let attempts = self
+                .db
+                .get_value(&ShareProposalAttemptKey(contract_id))
+                .expect("DB error")
+                .unwrap_or(0)
+                + 1;
+            if attempts < MAX_SHARE_PROPOSALS {
+                batch.append_insert(ShareProposalAttemptKey(contract_id), attempts);
+                continue;
+            }
+
+            warn!(%contract_id, %attempts, "Decryption share was never agreed on, giving up");
+            batch.append_delete(ProposeDecryptionShareKey(contract_id));
+            batch.append_maybe_delete(ShareProposalAttemptKey(contract_id));
+            batch.append_insert(FailedShareKey(contract_id), share);
+        }
+    }
+
+    /// Discards freeze votes that didn't reach the threshold within [`MAX_FREEZE_VOTE_EPOCHS`] or
+    /// whose contract doesn't exist anymore, together with our own proposal for them
+    fn expire_freeze_votes(&self, batch: &mut BatchTx) {
+        let current_epoch = self.current_epoch();
+        for res in self.db.find_by_prefix(&FreezeVoteEpochKeyPrefix) {
+            let (FreezeVoteEpochKey(contract_id), first_vote_epoch) = res.expect("DB error");
+            let expired = current_epoch.saturating_sub(first_vote_epoch) >= MAX_FREEZE_VOTE_EPOCHS;
+            if !expired && self.get_contract_account(contract_id).is_some() {
+                continue;
+            }
+
+            info!(%contract_id, "Discarding freeze votes that didn't reach the threshold");
+            for res in self
+                .db
+                .find_by_prefix(&AgreedFreezeContractPrefix(contract_id))
+            {
+                let (key, _) = res.expect("DB error");
+                batch.append_delete(key);
+            }
+            batch.append_delete(FreezeVoteEpochKey(contract_id));
+            batch.append_maybe_delete(ProposeFreezeKey(contract_id));
+        }
+
+        // Our votes for contracts that don't exist are never agreed on
+        for res in self.db.find_by_prefix(&ProposeFreezeKeyPrefix) {
+            let (ProposeFreezeKey(contract_id), _) = res.expect("DB error");
+            if self.get_contract_account(contract_id).is_none() {
+                batch.append_maybe_delete(ProposeFreezeKey(contract_id));
+            }
+        }
+    }
+
+    /// Whether `peer` received a share of the threshold key set
+    fn is_in_key_set(&self, peer: PeerId) -> bool {
+        peer.to_usize() < self.cfg.total_peers
+    }
+
+    /// Maps decryption shares to their key share index, dropping shares of peers outside of the
+    /// threshold key set since feeding these into the aggregation would make it produce garbage.
+    fn key_set_decryption_shares<'a>(
+        &self,
+        shares: &'a HashMap<PeerId, PreimageDecryptionShare>,
+    ) -> Vec<(usize, &'a threshold_crypto::DecryptionShare)> {
+        shares
+            .iter()
+            .filter(|(peer, _)| {
+                let in_key_set = self.is_in_key_set(**peer);
+                if !in_key_set {
+                    error!(%peer, "Dropping decryption share of peer outside of the key set");
+                }
+                in_key_set
+            })
+            .map(|(peer, share)| (peer.to_usize(), &share.0))
+            .collect()
+    }
+
+    fn validate_decryption_share(
+        &self,
+        peer: PeerId,
+        share: &PreimageDecryptionShare,
+        message: &EncryptedPreimage,
+    ) -> bool {
+        verify_decryption_share(
+            &self.cfg.threshold_pub_keys,
+            self.cfg.total_peers,
+            peer,
+            share,
+            message,
+        )
+    }
+
+    /// Checks the consistency of the module's database state and panics if it is violated. Since
+    /// this scans all contracts and decryption shares it is only available in debug builds, where
+    /// the server runs it once the batch of every consensus epoch was applied to catch consensus
+    /// bugs early.
+    #[cfg(debug_assertions)]
+    pub fn check_invariants(&self) {
+        let contracts: HashMap<ContractId, ContractAccount> = self
+            .db
+            .find_by_prefix(&ContractKeyPrefix)
+            .map(|res| {
+                let (ContractKey(contract_id), account) = res.expect("DB error");
+                (contract_id, account)
+            })
+            .collect();
+
+        for res in self.db.find_by_prefix(&AgreedDecryptionShareKeyPrefix) {
+            let (AgreedDecryptionShareKey(contract_id, peer), _) = res.expect("DB error");
+            assert!(
+                matches!(
+                    contracts.get(&contract_id),
+                    Some(ContractAccount {
+                        contract: FundedContract::Incoming(_),
+                        ..
+                    })
+                ),
+                "Decryption share of peer {} references non-existent incoming contract {}",
+                peer,
+                contract_id
+            );
+        }
+
+        for res in self.db.find_by_prefix(&ProposeDecryptionShareKeyPrefix) {
+            let (ProposeDecryptionShareKey(contract_id), _) = res.expect("DB error");
+            if let Some(ContractAccount {
+                contract: FundedContract::Incoming(incoming),
+                ..
+            }) = contracts.get(&contract_id)
+            {
+                assert_eq!(
+                    incoming.contract.decrypted_preimage,
+                    DecryptedPreimage::Pending,
+                    "Decrypted contract {} still has a proposed decryption share",
+                    contract_id
+                );
+            }
+        }
+
+        contracts
+            .values()
+            .try_fold(0u64, |total, account| {
+                total.checked_add(account.amount.milli_sat)
+            })
+            .expect("Total contract balance overflowed");
+    }
+
+    pub fn get_offer(
+        &self,
+        payment_hash: bitcoin_hashes::sha256::Hash,
+    ) -> Option<IncomingContractOffer> {
+        self.db
+            .get_value(&OfferKey(payment_hash))
+            .expect("DB error")
+    }
+
+    pub fn get_offers(&self) -> Vec<IncomingContractOffer> {
+        self.db
+            .find_by_prefix(&OfferKeyPrefix)
+            .map(|res| res.expect("DB error").1)
+            .collect()
+    }
+
+    /// Returns the ids of offers that were registered before block height `older_than_height` and
+    /// haven't been funded yet, e.g. to prune them. Offers registered before their creation height
+    /// was tracked are never considered stale.
+    pub fn stale_offers(&self, older_than_height: u32) -> Vec<OfferId> {
+        self.db
+            .find_by_prefix(&OfferKeyPrefix)
+            .map(|res| res.expect("DB error").1.id())
+            .filter(|offer_id| {
+                self.db
+                    .get_value(&OfferCreatedKey(*offer_id))
+                    .expect("DB error")
+                    .map_or(false, |created_height| created_height < older_than_height)
+            })
+            .collect()
+    }
+
+    /// Returns up to `limit` offers following the offer `after` (or the first ones if `None`) in
+    /// database order, together with the cursor to fetch the next page with, if there is one.
+    pub fn get_offers_paged(
+        &self,
+        after: Option<OfferId>,
+        limit: usize,
+    ) -> (Vec<IncomingContractOffer>, Option<OfferId>) {
+        // Offers are keyed by their hash, so we can seek to the cursor's key and only have to skip
+        // the cursor itself. The all-zero hash is the smallest possible key.
+        let start = after.map_or([0; 32], OfferId::into_inner);
+        let mut offers = self
+            .db
+            .find_by_prefix_from(
+                &OfferKeyPrefix,
+                &OfferKey(bitcoin_hashes::sha256::Hash::from_inner(start)),
+            )
+            .map(|res| res.expect("DB error").1)
+            .skip_while(|offer| Some(offer.id()) == after)
+            .take(limit + 1)
+            .collect::<Vec<_>>();
+
+        if offers.len() <= limit {
+            return (offers, None);
+        }
+
+        offers.truncate(limit);
+        let next = offers.last().map(IncomingContractOffer::id).or(after);
+        (offers, next)
+    }
+
+    /// Returns the highest consensus block height seen so far, including `block_height`.
+    ///
+    /// Timelocks are checked against this height instead of the current consensus height, so a
+    /// reorg lowering the height can't make a refundable outgoing contract unrefundable again. The
+    /// tradeoff is that a refund may succeed based on blocks that were later reorged out.
+    fn max_seen_block_height(db: ReadOnlyDb, block_height: u32) -> u32 {
+        db.get_value(&MaxSeenBlockHeightKey)
+            .expect("DB error")
+            .map_or(block_height, |max_seen| max_seen.max(block_height))
+    }
+
+    /// Records the consensus block height of the epoch being processed. The module can't access
+    /// it from its consensus hooks, so the transaction layer has to call this at the beginning of
+    /// every epoch. Otherwise outputs would be validated against the height seen by the last
+    /// spent input, or 0 if there was none.
+    pub fn record_block_height(&self, dbtx: &mut DatabaseTransaction<'_>, block_height: u32) {
+        let max_seen = Self::max_seen_block_height(self.db.read_only(), block_height);
+        dbtx.insert_entry(&MaxSeenBlockHeightKey, &max_seen)
+            .expect("DB Error");
+    }
+
+    /// Records the number of the consensus epoch being processed, which the consensus hooks don't
+    /// receive either. The transaction layer has to call this at the beginning of every epoch so
+    /// spent contracts are indexed under the epoch clients see in the epoch history.
+    pub fn record_consensus_epoch(&self, dbtx: &mut DatabaseTransaction<'_>, epoch: u64) {
+        dbtx.insert_entry(&CurrentEpochKey, &epoch)
+            .expect("DB Error");
+    }
+
+    /// Validates `input` as if the consensus block height was `block_height`. This allows wallets
+    /// to simulate e.g. whether an outgoing contract will be refundable at a certain height.
+    pub fn validate_input_at<'a>(
+        &self,
+        input: &'a ContractInput,
+        block_height: u32,
+    ) -> Result<InputMeta<'a>, LightningModuleError> {
+        self.validate_input_with_source(input, block_height)
+            .map(|(meta, _)| meta)
+    }
+
+    /// Like [`FederationModule::apply_output`], but additionally returns what applying `output`
+    /// did, e.g. to emit events for it.
+    pub fn apply_output_with_effect<'a>(
+        &'a self,
+        mut batch: BatchTx<'a>,
+        output: &'a ContractOrOfferOutput,
+        out_point: OutPoint,
+    ) -> Result<(TransactionItemAmount, ApplyOutputEffect), LightningModuleError> {
+        let amount = self.validate_output(output)?;
+
+        let effect = match output {
+            ContractOrOfferOutput::Contract(contract) => {
+                self.apply_contract_output(&mut batch, contract, amount.amount, out_point)
+            }
+            ContractOrOfferOutput::OfferAndContract(offer, contract) => {
+                // The offer has to exist before the contract funding it is processed
+                self.register_offer(&mut batch, offer);
+                self.apply_contract_output(&mut batch, contract, amount.amount, out_point)
+            }
+            ContractOrOfferOutput::Offer(offer) => {
+                batch.append_insert_new(
+                    ContractUpdateKey(out_point),
+                    OutputOutcome::Offer { id: offer.id() },
+                );
+                // TODO: sanity-check encrypted preimage size
+                self.register_offer(&mut batch, offer);
+
+                ApplyOutputEffect::OffersRegistered(vec![offer.id()])
+            }
+            ContractOrOfferOutput::OfferBatch(offers) => {
+                batch.append_insert_new(
+                    ContractUpdateKey(out_point),
+                    OutputOutcome::OfferBatch {
+                        ids: offers.iter().map(|offer| offer.id()).collect(),
+                    },
+                );
+                for offer in offers {
+                    self.register_offer(&mut batch, offer);
+                }
+
+                ApplyOutputEffect::OffersRegistered(offers.iter().map(|offer| offer.id()).collect())
+            }
+            ContractOrOfferOutput::CancelOutgoing { contract, .. } => {
+                let updated_contract_account = {
+                    let mut contract_account = self
+                        .get_contract_account(*contract)
+                        .expect("Contract exists if output is valid");
+
+                    let outgoing_contract = match &mut contract_account.contract {
+                        FundedContract::Outgoing(contract) => contract,
+                        _ => {
+                            panic!("Contract type was checked in validate_output");
+                        }
+                    };
+
+                    outgoing_contract.cancelled = true;
+
+                    contract_account
+                };
+
+                self.invalidate_contract(*contract);
+                batch.append_insert(ContractKey(*contract), updated_contract_account);
+
+                ApplyOutputEffect::OutgoingCancelled(*contract)
+            }
+        };
+
+        batch.commit();
+        Ok((amount, effect))
+    }
+
+    /// Validates the rules spanning all of a transaction's `inputs` to the module, which
+    /// [`FederationModule::validate_input`] can't check since it only sees one input at a time.
+    /// The transaction layer has to call this in addition to validating each input.
+    pub fn validate_inputs<'a>(
+        &self,
+        inputs: impl Iterator<Item = &'a ContractInput>,
+    ) -> Result<(), LightningModuleError> {
+        if let Some(max_inputs) = self.cfg.max_inputs_per_tx {
+            let input_count = inputs.count();
+            if input_count > max_inputs {
+                return Err(LightningModuleError::TooManyInputs(input_count, max_inputs));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like [`LightningModule::validate_input_at`], but additionally returns which kind of
+    /// contract spend `input` is, e.g. to charge different fees for refunds and claims.
+    pub fn validate_input_with_source<'a>(
+        &self,
+        input: &'a ContractInput,
+        block_height: u32,
+    ) -> Result<(InputMeta<'a>, ContractInputSource), LightningModuleError> {
+        self.check_input(self.db.read_only(), input, block_height)
+    }
+
+    /// Validates `input` reading the module's state only through `db`
+    fn check_input<'a>(
+        &self,
+        db: ReadOnlyDb,
+        input: &'a ContractInput,
+        block_height: u32,
+    ) -> Result<(InputMeta<'a>, ContractInputSource), LightningModuleError> {
+        let account: ContractAccount = self
+            .cached_contract_account(db, input.contract_id)
+            .ok_or(LightningModuleError::UnknownContract(input.contract_id))?;
+
+        if Self::frozen(db, input.contract_id) {
+            return Err(LightningModuleError::ContractFrozen(input.contract_id));
+        }
+
+        if account.amount < input.amount {
+            return Err(LightningModuleError::InsufficientFunds {
+                contract: input.contract_id,
+                available: account.amount,
+                requested: input.amount,
+            });
+        }
+
+        // An input not even covering its own fee can't contribute anything to the transaction
+        if input
+            .amount
+            .checked_sub(self.cfg.fee_consensus.contract_input)
+            .is_none()
+        {
+            return Err(LightningModuleError::AmountUnderflow(
+                input.amount,
+                self.cfg.fee_consensus.contract_input,
+            ));
+        }
+
+        if !self.cfg.allow_partial_spends && account.amount != input.amount {
+            return Err(LightningModuleError::PartialSpendDisallowed(
+                account.amount,
+                input.amount,
+            ));
+        }
+
+        if let Some(change_contract) = &input.change_contract {
+            // Only account contracts can be funded without an output, and funding the spent
+            // contract itself would count its remaining balance twice
+            let valid_target = matches!(change_contract, Contract::Account(_))
+                && change_contract.contract_id() != input.contract_id;
+            if !valid_target {
+                return Err(LightningModuleError::InvalidChangeContract);
+            }
+            self.validate_contract_output(
+                db,
+                &ContractOutput {
+                    amount: account.amount - input.amount,
+                    contract: change_contract.clone(),
+                },
+                None,
+            )?;
+        }
+
+        let (pub_keys, source) = match account.contract {
+            FundedContract::Outgoing(outgoing) => {
+                if outgoing.timelock > block_height && !outgoing.cancelled {
+                    // If the timelock hasn't expired yet …
+                    let preimage = match &input.witness {
+                        ContractWitness::Preimage(preimage) => preimage,
+                        _ => return Err(LightningModuleError::MissingPreimage),
+                    };
+                    let preimage_hash = self.payment_hash(&preimage.0);
+
+                    // … and the spender provides a valid preimage …
+                    if preimage_hash != outgoing.hash {
+                        return Err(LightningModuleError::InvalidPreimage);
+                    }
+
+                    // … then the contract account can be spent using the gateway key,
+                    (
+                        vec![outgoing.gateway_key],
+                        ContractInputSource::OutgoingClaim,
+                    )
+                } else {
+                    // otherwise the user can claim the funds back.
+                    (vec![outgoing.user_key], ContractInputSource::OutgoingRefund)
+                }
+            }
+            FundedContract::Account(acc_contract) => (
+                account_signers(&acc_contract, &input.witness)?,
+                ContractInputSource::AccountSpend,
+            ),
+            FundedContract::Incoming(incoming) => match incoming.contract.decrypted_preimage {
+                // The gateway may reclaim the funds if decryption didn't finish in time
+                DecryptedPreimage::Pending
+                    if incoming
+                        .contract
+                        .incoming_refund_height
+                        .map_or(false, |height| height <= block_height) =>
+                {
+                    (
+                        vec![incoming.contract.gateway_key],
+                        ContractInputSource::IncomingRefund,
+                    )
+                }
+                // Once the preimage has been decrypted …
+                DecryptedPreimage::Pending => {
+                    return Err(LightningModuleError::ContractNotReady);
+                }
+                // … either the user may spend the funds since they sold a valid preimage …
+                DecryptedPreimage::Some(preimage) if incoming.contract.recipients.is_empty() => {
+                    match preimage.to_public_key() {
+                        Ok(pub_key) => (vec![pub_key], ContractInputSource::IncomingClaim),
+                        // Nobody could ever claim the funds, so they are refunded to the gateway
+                        Err(_) => (
+                            vec![incoming.contract.gateway_key],
+                            ContractInputSource::IncomingRefund,
+                        ),
+                    }
+                }
+                // A raw preimage without recipients can't lock the contract either, which only
+                // happens if the preimage policy was changed after funding
+                DecryptedPreimage::Raw(_) if incoming.contract.recipients.is_empty() => (
+                    vec![incoming.contract.gateway_key],
+                    ContractInputSource::IncomingRefund,
+                ),
+                // … or, if the funds are split or the preimage is raw, each recipient may spend up
+                // to its share …
+                DecryptedPreimage::Some(_) | DecryptedPreimage::Raw(_) => {
+                    let recipient = input
+                        .recipient
+                        .ok_or(LightningModuleError::MissingRecipient)?;
+                    let share = incoming
+                        .contract
+                        .recipients
+                        .iter()
+                        .find(|(key, _)| *key == recipient)
+                        .map(|(_, amount)| *amount)
+                        .ok_or(LightningModuleError::UnknownRecipient(recipient))?;
+                    let available = share.saturating_sub(Self::recipient_claimed(
+                        db,
+                        input.contract_id,
+                        recipient,
+                    ));
+                    if input.amount > available {
+                        return Err(LightningModuleError::RecipientShareExceeded(
+                            available,
+                            input.amount,
+                        ));
+                    }
+                    (vec![recipient], ContractInputSource::IncomingClaim)
+                }
+                // … or the gateway may claim back funds for not receiving the advertised preimage.
+                // Since only the gateway key is returned the user can never spend an invalid
+                // contract, their signature fails the transaction's signature check.
+                DecryptedPreimage::Invalid => (
+                    vec![incoming.contract.gateway_key],
+                    ContractInputSource::IncomingRefund,
+                ),
+            },
+        };
+
+        let meta = InputMeta {
+            amount: TransactionItemAmount {
+                amount: input.amount,
+                fee: self.cfg.fee_consensus.contract_input,
+            },
+            puk_keys: Box::new(pub_keys.into_iter()),
+        };
+        Ok((meta, source))
+    }
+
+    /// Estimates after how many consensus epochs following its funding the preimage of an incoming
+    /// contract will be decrypted.
+    ///
+    /// The protocol timing is as follows:
+    ///   1. In the funding epoch `apply_output` stores our decryption share to be proposed.
+    ///   2. The share becomes part of our next consensus proposal, so all peers' shares are agreed
+    ///      upon in `begin_consensus_epoch` of the following epoch.
+    ///   3. In `end_consensus_epoch` of that same epoch the shares are combined if at least
+    ///      `threshold` of them are valid.
+    ///
+    /// So if enough peers are online decryption finishes one epoch after funding, plus the
+    /// configured `decrypt_delay_epochs`. Shares missing from a proposal delay it by further
+    /// epochs, which isn't accounted for yet.
+    pub fn estimated_decryption_epochs(&self) -> u32 {
+        1 + self.cfg.decrypt_delay_epochs
+    }
+
+    /// Returns the number of bytes all stored offers occupy in their consensus encoding, which is
+    /// also the format they are stored in.
+    pub fn offer_storage_bytes(&self) -> usize {
+        self.get_offers()
+            .iter()
+            .map(|offer| {
+                offer
+                    .consensus_encode(&mut std::io::sink())
+                    .expect("Writing to sink can't fail")
+            })
+            .sum()
+    }
+
+    /// Estimates how many bytes applying `outputs` would write to the database by summing the
+    /// encoded sizes of the inserted keys and values, e.g. to reject oversized offer batches
+    /// upfront. Deletions aren't counted and each output is assumed to be valid and applied on
+    /// its own, i.e. outputs funding the same contract or registering the same offer are counted
+    /// repeatedly.
+    pub fn estimate_batch_bytes(&self, outputs: &[ContractOrOfferOutput]) -> usize {
+        // Out points are encoded with a fixed size, so any will do
+        let out_point = OutPoint {
+            txid: TransactionId::from_inner([0; 32]),
+            out_idx: 0,
+        };
+
+        outputs
+            .iter()
+            .map(|output| match output {
+                ContractOrOfferOutput::Contract(contract) => {
+                    self.estimate_contract_output_bytes(contract, out_point)
+                }
+                ContractOrOfferOutput::OfferAndContract(offer, contract) => {
+                    self.estimate_offer_bytes(offer)
+                        + self.estimate_contract_output_bytes(contract, out_point)
+                }
+                ContractOrOfferOutput::Offer(offer) => {
+                    entry_bytes(
+                        &ContractUpdateKey(out_point),
+                        &OutputOutcome::Offer { id: offer.id() },
+                    ) + self.estimate_offer_bytes(offer)
+                }
+                ContractOrOfferOutput::OfferBatch(offers) => {
+                    entry_bytes(
+                        &ContractUpdateKey(out_point),
+                        &OutputOutcome::OfferBatch {
+                            ids: offers.iter().map(|offer| offer.id()).collect(),
+                        },
+                    ) + offers
+                        .iter()
+                        .map(|offer| self.estimate_offer_bytes(offer))
+                        .sum::<usize>()
+                }
+                ContractOrOfferOutput::CancelOutgoing { contract, .. } => self
+                    .get_contract_account(*contract)
+                    .map_or(0, |account| entry_bytes(&ContractKey(*contract), &account)),
+            })
+            .sum()
+    }
+
+    /// Bytes written by [`LightningModule::register_offer`]
+    fn estimate_offer_bytes(&self, offer: &IncomingContractOffer) -> usize {
+        if self.get_offer(offer.hash).is_some() {
+            return 0;
+        }
+
+        entry_bytes(&OfferKey(offer.hash), offer) + entry_bytes(&OfferCreatedKey(offer.id()), &0)
+    }
+
+    /// Bytes written by [`LightningModule::apply_contract_output`]
+    fn estimate_contract_output_bytes(
+        &self,
+        contract: &ContractOutput,
+        out_point: OutPoint,
+    ) -> usize {
+        let contract_id = contract.contract.contract_id();
+        let account = self
+            .get_contract_account(contract_id)
+            .unwrap_or_else(|| ContractAccount {
+                amount: contract.amount,
+                contract: contract
+                    .contract
+                    .clone()
+                    .to_funded(out_point, self.current_epoch()),
+            });
+
+        let mut bytes = entry_bytes(&ContractKey(contract_id), &account)
+            + entry_bytes(
+                &ContractUpdateKey(out_point),
+                &OutputOutcome::Contract {
+                    id: contract_id,
+                    outcome: contract.contract.to_outcome(),
+                },
+            );
+        if self.contract_created_height(contract_id).is_none() {
+            bytes += entry_bytes(&ContractCreatedKey(contract_id), &0);
+        }
+
+        if let Contract::Incoming(incoming) = &contract.contract {
+            let offer_id = OfferId::from_hash(incoming.hash);
+            if let Some(share) = self
+                .cfg
+                .threshold_sec_key
+                .decrypt_share(&incoming.encrypted_preimage.0)
+            {
+                let share = PreimageDecryptionShare(share);
+                bytes += entry_bytes(&ProposeDecryptionShareKey(contract_id), &share);
+                if self.cfg.fallback_sec_key.is_some() {
+                    // Shares of both key sets are group elements of the same size
+                    bytes += entry_bytes(&ProposeFallbackShareKey(contract_id), &share);
+                }
+            }
+            bytes += entry_bytes(&FundedOfferKey(offer_id), &contract_id)
+                + entry_bytes(&PaymentHashIndexKey(incoming.hash), &contract_id)
+                + entry_bytes(&SpentOfferKey(offer_id), &());
+        }
+
+        bytes
+    }
+
+    /// Returns the id of the incoming contract funded for the payment `hash`, even if it was spent
+    /// already
+    pub fn incoming_contract_by_hash(
+        &self,
+        hash: &bitcoin_hashes::sha256::Hash,
+    ) -> Option<ContractId> {
+        self.db
+            .get_value(&PaymentHashIndexKey(*hash))
+            .expect("DB error")
+    }
+
+    /// Returns the contracts funded by out points in the inclusive range `start..=end`, ordered by
+    /// out point (see [`OutPoint`]'s `Ord` impl)
+    pub fn contracts_funded_between(
+        &self,
+        start: OutPoint,
+        end: OutPoint,
+    ) -> Vec<(OutPoint, ContractId)> {
+        let mut contracts = self
+            .db
+            .find_by_prefix(&ContractUpdateKeyPrefix)
+            .filter_map(|res| {
+                let (ContractUpdateKey(out_point), outcome) = res.expect("DB error");
+                match outcome {
+                    OutputOutcome::Contract { id, .. } if (start..=end).contains(&out_point) => {
+                        Some((out_point, id))
+                    }
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        contracts.sort();
+        contracts
+    }
+
+    /// Returns the id of the incoming contract that funded the offer `id`, if any
+    pub fn offer_funding_contract(&self, id: OfferId) -> Option<ContractId> {
+        self.db.get_value(&FundedOfferKey(id)).expect("DB error")
+    }
+
+    pub fn get_contract_account(&self, contract_id: ContractId) -> Option<ContractAccount> {
+        self.cached_contract_account(self.db.read_only(), contract_id)
+    }
+
+    /// Reads the contract account `contract_id` from `db`, going through the contract cache if
+    /// enabled
+    fn cached_contract_account(
+        &self,
+        db: ReadOnlyDb,
+        contract_id: ContractId,
+    ) -> Option<ContractAccount> {
+        let read_db = || db.get_value(&ContractKey(contract_id)).expect("DB error");
+
+        let cache = match &self.contract_cache {
+            Some(cache) => cache,
+            None => return read_db(),
+        };
+        let mut cache = cache.lock().expect("lock poisoned");
+        if cache.written.contains(&contract_id) {
+            return read_db();
+        }
+        if let Some(account) = cache.accounts.get(&contract_id) {
+            return Some(account.clone());
+        }
+
+        let account = read_db()?;
+        cache.accounts.put(contract_id, account.clone());
+        Some(account)
+    }
+
+    /// Waits until the preimage sold by the incoming contract `id` has been decrypted and
+    /// returns it.
+    ///
+    /// The contract doesn't have to be funded yet when calling this. Decryptions are published
+    /// by the consensus epoch that performs them, so the database is only read once per
+    /// published epoch. Raw preimages that aren't 32 bytes long are returned as
+    /// [`AwaitError::RawPreimage`].
+    pub async fn await_decryption(
+        &self,
+        id: ContractId,
+        timeout: Duration,
+    ) -> Result<Preimage, AwaitError> {
+        let mut decryptions = self.decryptions.subscribe();
+        task::timeout(timeout, async {
+            loop {
+                // Decryptions are published before the epoch's batch is applied, so they may not
+                // be visible in the database yet
+                let published = decryptions.borrow_and_update().get(&id).cloned();
+                let decrypted_preimage = match published {
+                    Some(decrypted_preimage) => decrypted_preimage,
+                    None => match self
+                        .get_contract_account(id)
+                        .map(|account| account.contract)
+                    {
+                        Some(FundedContract::Incoming(incoming)) => {
+                            incoming.contract.decrypted_preimage
+                        }
+                        Some(_) => return Err(AwaitError::NotIncomingContract),
+                        None => DecryptedPreimage::Pending,
+                    },
+                };
+                match decrypted_preimage {
+                    DecryptedPreimage::Pending => {}
+                    DecryptedPreimage::Some(preimage) => return Ok(preimage),
+                    DecryptedPreimage::Raw(preimage) => {
+                        return Err(AwaitError::RawPreimage(preimage))
+                    }
+                    DecryptedPreimage::Invalid => return Err(AwaitError::InvalidPreimage),
+                }
+
+                decryptions
+                    .changed()
+                    .await
+                    .expect("The module owns the sender");
+            }
+        })
+        .await
+        .map_err(|_| AwaitError::Timeout)?
+    }
+
+    /// Sums up the balances of all contract accounts by contract type
+    pub fn liabilities(&self) -> LnLiabilities {
+        let mut liabilities = LnLiabilities::default();
+        for res in self.db.find_by_prefix(&ContractKeyPrefix) {
+            let (_, account) = res.expect("DB error");
+            let category = match account.contract {
+                FundedContract::Outgoing(_) => &mut liabilities.outgoing,
+                FundedContract::Incoming(incoming) => match incoming.contract.decrypted_preimage {
+                    DecryptedPreimage::Pending => &mut liabilities.incoming_pending,
+                    DecryptedPreimage::Some(_)
+                    | DecryptedPreimage::Raw(_)
+                    | DecryptedPreimage::Invalid => &mut liabilities.incoming_claimable,
+                },
+                FundedContract::Account(_) => &mut liabilities.account,
+            };
+            *category += account.amount;
+        }
+        liabilities
+    }
+
+    /// Counts the contract accounts by contract type, e.g. for a health overview that doesn't need
+    /// the balances of [`LightningModule::liabilities`]
+    pub fn contract_counts(&self) -> ContractCounts {
+        let mut counts = ContractCounts::default();
+        for res in self.db.find_by_prefix(&ContractKeyPrefix) {
+            let (_, account) = res.expect("DB error");
+            let count = match account.contract {
+                FundedContract::Account(_) => &mut counts.account,
+                FundedContract::Outgoing(_) => &mut counts.outgoing,
+                FundedContract::Incoming(_) => &mut counts.incoming,
+            };
+            *count += 1;
+        }
+        counts
+    }
+
+    /// Returns the key of the gateway that can reclaim the incoming contract `id` since its preimage
+    /// decrypted to an invalid one. Only recorded if `auto_refund_invalid` is enabled in the config.
+    pub fn invalid_preimage_refund_key(&self, id: ContractId) -> Option<secp256k1::XOnlyPublicKey> {
+        self.db
+            .get_value(&InvalidPreimageRefundableKey(id))
+            .expect("DB error")
+    }
+
+    /// Returns the block height at which the contract `id` was first funded, e.g. for clients
+    /// displaying a transaction history
+    pub fn contract_created_height(&self, id: ContractId) -> Option<u32> {
+        self.db
+            .get_value(&ContractCreatedKey(id))
+            .expect("DB error")
+    }
+
+    /// Returns the out points of all funded incoming contracts whose preimage is still being
+    /// decrypted, e.g. for clients tracking multiple receives at once
+    pub fn pending_incoming_outpoints(&self) -> Vec<OutPoint> {
+        self.pending_incoming_contracts()
+            .map(|incoming| incoming.out_point)
+            .collect()
+    }
+
+    /// Counts the funded incoming contracts whose preimage is still being decrypted. Decryption
+    /// normally finishes within [`LightningModule::estimated_decryption_epochs`], so a sustained
+    /// high value indicates offline guardians or decryption shares not being gossiped.
+    pub fn decryption_backlog(&self) -> usize {
+        self.pending_incoming_contracts().count()
+    }
+
+    /// Returns the distinct payment hashes of all funded incoming contracts whose preimage is
+    /// still being decrypted, allowing gateways to reconcile them with the HTLCs they hold
+    pub fn pending_payment_hashes(&self) -> Vec<bitcoin_hashes::sha256::Hash> {
+        self.pending_incoming_contracts()
+            .map(|incoming| incoming.contract.hash)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    fn pending_incoming_contracts(&self) -> impl Iterator<Item = FundedIncomingContract> + '_ {
+        self.db
+            .find_by_prefix(&ContractKeyPrefix)
+            .filter_map(|res| {
+                let (_, account) = res.expect("DB error");
+                match account.contract {
+                    FundedContract::Incoming(incoming) => Some(incoming),
+                    _ => None,
+                }
+            })
+            .filter(|incoming| incoming.contract.decrypted_preimage == DecryptedPreimage::Pending)
+    }
+
+    /// Returns the contract funded by the output at `out_point` if it still exists, allowing clients
+    /// that only know the out point to look up the full contract
+    pub fn contract_at_outpoint(&self, out_point: OutPoint) -> Option<FundedContract> {
+        match self.output_status(out_point)? {
+            OutputOutcome::Contract { id, .. } => self
+                .get_contract_account(id)
+                .map(|account| account.contract),
+            OutputOutcome::Offer { .. } | OutputOutcome::OfferBatch { .. } => None,
+        }
+    }
+
+    /// Returns the current outcome of the contract `id`, e.g. for clients that don't know the out
+    /// point that funded it. Fully spent contracts that were removed have no outcome anymore.
+    pub fn contract_outcome(&self, id: ContractId) -> Option<ContractOutcome> {
+        self.get_contract_account(id)
+            .map(|account| account.contract.to_outcome())
+    }
+
+    /// Like [`FederationModule::output_status`], but distinguishes unknown outputs from ones
+    /// whose outcome may still change
+    pub fn output_status_detailed(&self, out_point: OutPoint) -> OutputStatus {
+        match self.output_status(out_point) {
+            None => OutputStatus::Unknown,
+            Some(outcome) if outcome.is_final() => OutputStatus::Final(outcome),
+            Some(outcome) => OutputStatus::Pending(outcome),
+        }
+    }
+
+    /// Deletes all account contracts holding less than `threshold`, which can't be spent
+    /// economically anymore, and moves their balance to the fee accumulator. Frozen contracts are
+    /// left untouched. Returns the total amount swept.
+    pub fn sweep_dust(&self, mut batch: BatchTx, threshold: Amount) -> Amount {
+        let dust = self
+            .db
+            .find_by_prefix(&ContractKeyPrefix)
+            .map(|res| res.expect("DB error"))
+            .filter(|(ContractKey(id), account)| {
+                account.contract.as_account().is_some()
+                    && account.amount < threshold
+                    && !self.is_frozen(*id)
+            })
+            .collect::<Vec<_>>();
+
+        let mut swept = Amount::ZERO;
+        for (ContractKey(contract_id), account) in dust {
+            trace!(%contract_id, amount = %account.amount, "Sweeping dust");
+            swept += account.amount;
+            self.invalidate_contract(contract_id);
+            self.delete_spent_contract(&mut batch, contract_id, &account);
+        }
+        batch.append_insert(FeeAccumulatorKey, self.accumulated_fees() + swept);
+        batch.commit();
+
+        swept
+    }
+
+    /// Returns the total amount moved to the fee accumulator
+    pub fn accumulated_fees(&self) -> Amount {
+        self.db
+            .get_value(&FeeAccumulatorKey)
+            .expect("DB error")
+            .unwrap_or(Amount::ZERO)
+    }
+
+    /// Decrypts the preimage of the incoming contract `id` from the decryption shares agreed upon
+    /// so far. This has to be triggered by the operator if automatic decryption is disabled.
+    pub fn decrypt_contract(
+        &self,
+        id: ContractId,
+        mut batch: BatchTx,
+    ) -> Result<DecryptedPreimage, LightningModuleError> {
+        let incoming_contract = match self.get_contract_account(id) {
+            Some(ContractAccount {
+                contract: FundedContract::Incoming(incoming),
+                ..
+            }) => incoming.contract,
+            Some(_) => return Err(LightningModuleError::NotIncomingContract),
+            None => return Err(LightningModuleError::UnknownContract(id)),
+        };
+
+        let shares = self.agreed_decryption_shares(id);
+        let peers = shares.iter().map(|(peer, _)| *peer).collect();
+        let valid_shares: HashMap<PeerId, PreimageDecryptionShare> = shares
+            .into_iter()
+            .filter(|(peer, share)| {
+                self.validate_decryption_share(*peer, share, &incoming_contract.encrypted_preimage)
+            })
+            .collect();
+
+        if valid_shares.len() < self.cfg.threshold {
+            return Err(LightningModuleError::NotEnoughDecryptionShares(
+                valid_shares.len(),
+                self.cfg.threshold,
+            ));
+        }
+
+        let decrypted_preimage = self
+            .decrypt_preimage(id, peers, &valid_shares, &mut batch)
+            .ok_or(LightningModuleError::DecryptionFailed)?;
+        batch.commit();
+        Ok(decrypted_preimage)
+    }
+
+    /// Returns our own decryption share for the incoming contract `id` while it is still pending,
+    /// so operators can decrypt the preimage with [`decrypt_offline`] if consensus halted
+    pub fn export_decryption_share(
+        &self,
+        id: ContractId,
+    ) -> Option<(PeerId, PreimageDecryptionShare)> {
+        let proposed = self
+            .db
+            .get_value(&ProposeDecryptionShareKey(id))
+            .expect("DB error");
+        let failed = || self.db.get_value(&FailedShareKey(id)).expect("DB error");
+        proposed
+            .or_else(failed)
+            .map(|share| (self.our_peer_id, share))
+    }
+
+    /// Returns the randomness beacon of the current round consensus, if there was a round yet
+    pub fn randomness_beacon(&self, interconnect: &dyn ModuleInterconect) -> Option<[u8; 32]> {
+        randomness_beacon(interconnect)
+    }
+
+    /// Returns the fee rate in sats per kvB agreed on in the current round consensus of the wallet
+    /// module, if there was a round yet
+    pub fn current_fee_rate(&self, interconnect: &dyn ModuleInterconect) -> Option<u64> {
+        fee_rate(interconnect)
+    }
+
+    /// Orders competing incoming contracts funding the same offer by hashing their ids with the
+    /// randomness `beacon`, so no gateway is favored by the order in which its funding arrived.
+    ///
+    /// Outputs are applied one at a time and without access to the beacon, so the server uses this
+    /// to order the epoch's transactions before filtering conflicts, which only lets the first of
+    /// several fundings of the same offer through.
+    pub fn order_competing_fundings(
+        beacon: &[u8; 32],
+        mut contract_ids: Vec<ContractId>,
+    ) -> Vec<ContractId> {
+        contract_ids.sort_by_cached_key(|contract_id| {
+            let mut engine = bitcoin_hashes::sha256::Hash::engine();
+            engine.input(beacon);
+            engine.input(&contract_id[..]);
+            bitcoin_hashes::sha256::Hash::from_engine(engine)
+        });
+        contract_ids
+    }
+
+    /// Like [`LightningModule::order_competing_fundings`], but draws the beacon from `rng`, so the
+    /// same seed always results in the same order.
+    ///
+    /// The RNGs passed to the consensus functions are local to each guardian, so in consensus the
+    /// server seeds `rng` from the wallet's randomness beacon instead.
+    pub fn order_competing_fundings_with_rng(
+        rng: &mut impl RngCore,
+        contract_ids: Vec<ContractId>,
+    ) -> Vec<ContractId> {
+        let mut beacon = [0; 32];
+        rng.fill_bytes(&mut beacon);
+        Self::order_competing_fundings(&beacon, contract_ids)
+    }
+
+    /// Proposes to the other guardians to freeze (or unfreeze if `freeze` is `false`) the contract
+    /// `contract_id`. Frozen contracts can't be spent until unfrozen again.
+    pub fn propose_freeze(&self, contract_id: ContractId, freeze: bool) {
+        self.db
+            .insert_entry(&ProposeFreezeKey(contract_id), &freeze)
+            .expect("DB error");
+    }
+
+    /// Hashes `preimage` with the configured [`config::PreimageHash`]. Contracts are locked to and
+    /// offers are keyed by this hash, so integrations should derive payment hashes using it.
+    pub fn payment_hash(&self, preimage: &[u8]) -> bitcoin_hashes::sha256::Hash {
+        self.cfg.preimage_hash.hash(preimage)
+    }
+
+    /// Checks if `preimage` unlocks the outgoing contract `id` without spending it, e.g. for
+    /// gateways to verify a preimage before building a claim transaction
+    pub fn preimage_matches(
+        &self,
+        id: ContractId,
+        preimage: &Preimage,
+    ) -> Result<bool, LightningModuleError> {
+        let contract_account = self
+            .get_contract_account(id)
+            .ok_or(LightningModuleError::UnknownContract(id))?;
+
+        match contract_account.contract {
+            FundedContract::Outgoing(outgoing) => {
+                Ok(self.payment_hash(&preimage.0) == outgoing.hash)
+            }
+            _ => Err(LightningModuleError::NotOutgoingContract),
+        }
+    }
+
+    /// Returns the encrypted preimage of the incoming contract `id`, e.g. for clients that gathered
+    /// decryption shares themselves and want to decrypt it independently
+    pub fn encrypted_preimage(&self, id: ContractId) -> Option<EncryptedPreimage> {
+        match self.get_contract_account(id)?.contract {
+            FundedContract::Incoming(incoming) => Some(incoming.contract.encrypted_preimage),
+            _ => None,
+        }
+    }
+
+    /// Checks decryption shares received outside of consensus, e.g. via gossip, against the
+    /// encrypted preimages of their contracts. Shares for unknown or non-incoming contracts are
+    /// invalid. Returns the validity of each share in the order they were passed in.
+    pub fn validate_shares(
+        &self,
+        shares: &[(PeerId, ContractId, PreimageDecryptionShare)],
+    ) -> Vec<bool> {
+        let mut encrypted_preimages = HashMap::new();
+        shares
+            .iter()
+            .map(|(peer, contract_id, share)| {
+                encrypted_preimages
+                    .entry(*contract_id)
+                    .or_insert_with(|| self.encrypted_preimage(*contract_id))
+                    .as_ref()
+                    .map_or(false, |encrypted_preimage| {
+                        self.validate_decryption_share(*peer, share, encrypted_preimage)
+                    })
+            })
+            .collect()
+    }
+
+    pub fn is_frozen(&self, contract_id: ContractId) -> bool {
+        Self::frozen(self.db.read_only(), contract_id)
+    }
+
+    fn frozen(db: ReadOnlyDb, contract_id: ContractId) -> bool {
+        db.get_value(&FrozenContractKey(contract_id))
+            .expect("DB error")
+            .is_some()
+    }
+
+    /// Number of the consensus epoch currently being processed, as recorded by
+    /// [`LightningModule::record_consensus_epoch`]. Without a server recording it, e.g. in tests,
+    /// the module counts the epochs it ended itself.
+    fn current_epoch(&self) -> u64 {
+        self.db
+            .get_value(&CurrentEpochKey)
+            .expect("DB error")
+            .unwrap_or(0)
+    }
+
+    /// Returns the ids of all contracts that were spent from in consensus epoch `epoch`
+    pub fn contracts_spent_in_epoch(&self, epoch: u64) -> Vec<ContractId> {
+        self.db
+            .find_by_prefix(&EpochSpentContractPrefix(epoch))
+            .map(|res| res.expect("DB error").0 .1)
+            .collect()
+    }
+
+    /// Amount the `recipient` of a split incoming contract already spent from it
+    fn recipient_claimed(
+        db: ReadOnlyDb,
+        contract_id: ContractId,
+        recipient: secp256k1::XOnlyPublicKey,
+    ) -> Amount {
+        db.get_value(&RecipientClaimedKey(contract_id, recipient))
+            .expect("DB error")
+            .unwrap_or(Amount::ZERO)
+    }
+
+    /// Returns how often each contract's preimage was attempted to be decrypted after it had
+    /// already been decrypted, which hints at decryption shares not being cleaned up
+    pub fn redundant_decryption_attempts(&self) -> Vec<(ContractId, u64)> {
+        self.db
+            .find_by_prefix(&RedundantDecryptionAttemptKeyPrefix)
+            .map(|res| {
+                let (RedundantDecryptionAttemptKey(contract_id), attempts) = res.expect("DB error");
+                (contract_id, attempts)
+            })
+            .collect()
+    }
+
+    /// Returns how often spending the contract `id` failed validation, which may indicate an attack
+    /// on it. Counts at most [`MAX_SPEND_ATTEMPTS`].
+    ///
+    /// The counter is local to this guardian, only kept in memory and any client can raise it by
+    /// submitting failing inputs, so it's only a hint for operators and not agreed on by the
+    /// federation.
+    pub fn spend_attempts(&self, id: ContractId) -> u64 {
+        self.spend_attempts
+            .lock()
+            .expect("lock poisoned")
+            .get(&id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Only attempts on existing contracts are counted and the count is bounded, so failing inputs
+    /// can't be used to exhaust our memory
+    fn record_failed_spend_attempt(&self, db: ReadOnlyDb, id: ContractId) {
+        if self.cached_contract_account(db, id).is_none() {
+            return;
+        }
+
+        let mut spend_attempts = self.spend_attempts.lock().expect("lock poisoned");
+        let attempts = spend_attempts.entry(id).or_insert(0);
+        *attempts = (*attempts + 1).min(MAX_SPEND_ATTEMPTS);
+    }
+
+    /// Sums up the routing fees the gateway with key `gateway_key` earned by claiming outgoing
+    /// contracts
+    pub fn gateway_earnings(&self, gateway_key: &secp256k1::XOnlyPublicKey) -> Amount {
+        self.db
+            .find_by_prefix(&GatewayEarningsKeyPrefix(*gateway_key))
+            .map(|res| res.expect("DB error").1)
+            .sum()
+    }
+
+    /// Returns the peers out of `all_peers` whose decryption share for the incoming contract `id`
+    /// hasn't been agreed upon yet, e.g. to find guardians stalling the decryption.
+    pub fn missing_decryption_peers(&self, id: ContractId, all_peers: &[PeerId]) -> Vec<PeerId> {
+        all_peers
+            .iter()
+            .copied()
+            .filter(|&peer| {
+                self.db
+                    .get_value(&AgreedDecryptionShareKey(id, peer))
+                    .expect("DB error")
+                    .is_none()
+            })
+            .collect()
+    }
+
+    pub fn list_gateways(&self) -> Vec<LightningGateway> {
+        self.db
+            .find_by_prefix(&LightningGatewayKeyPrefix)
+            .map(|res| res.expect("DB error").1)
+            .collect()
+    }
+
+    pub fn register_gateway(&self, gateway: LightningGateway) {
+        self.db
+            .insert_entry(&LightningGatewayKey(gateway.node_pub_key), &gateway)
+            .expect("DB error");
+    }
+}
+
+/// Sorts decryption shares by contract id. Ties are broken by the encoded share, so the order is
+/// total even in case there are several shares for one contract.
+fn sort_decryption_shares(shares: &mut [DecryptionShareCI]) {
+    shares.sort_by_cached_key(|ci| {
+        let mut share_bytes = Vec::new();
+        ci.share
+            .consensus_encode(&mut share_bytes)
+            .expect("Writing to a Vec can't fail");
+        (ci.contract_id, share_bytes)
+    });
+}
+
+/// Returns the keys that have to sign the spend of `account`. For shared custody accounts these are
+/// the signers named by the `witness`, so the transaction's aggregate signature check enforces the
+/// account's threshold.
+fn account_signers(
+    account: &AccountContract,
+    witness: &ContractWitness,
+) -> Result<Vec<secp256k1::XOnlyPublicKey>, LightningModuleError> {
+    if account.multisig.is_none() {
+        return Ok(vec![account.key]);
+    }
+
+    let signers = match witness {
+        ContractWitness::Signers(signers) => signers,
+        _ => return Err(LightningModuleError::MissingSigners),
+    };
+    let keys = account.keys();
+    if let Some(unknown) = signers.iter().find(|signer| !keys.contains(signer)) {
+        return Err(LightningModuleError::UnknownSigner(*unknown));
+    }
+
+    // Naming a key twice must not count towards the threshold
+    let signers = signers.iter().copied().collect::<BTreeSet<_>>();
+    if signers.len() < account.threshold() {
+        return Err(LightningModuleError::NotEnoughSigners(
+            signers.len(),
+            account.threshold(),
+        ));
+    }
+    Ok(signers.into_iter().collect())
+}
+
+fn block_height(interconnect: &dyn ModuleInterconect) -> u32 {
+    // This is a future because we are normally reading from a network socket. But for internal
+    // calls the data is available instantly in one go, so we can just block on it.
+    let body = futures::executor::block_on(interconnect.call(
+        "wallet",
+        "/block_height".to_owned(),
+        Default::default(),
+    ))
+    .expect("Wallet module not present or malfunctioning!");
+
+    serde_json::from_value(body).expect("Malformed block height response from wallet module!")
+}
+
+fn randomness_beacon(interconnect: &dyn ModuleInterconect) -> Option<[u8; 32]> {
+    let body = futures::executor::block_on(interconnect.call(
+        "wallet",
+        "/randomness_beacon".to_owned(),
+        Default::default(),
+    ))
+    .expect("Wallet module not present or malfunctioning!");
+
+    serde_json::from_value(body).expect("Malformed randomness beacon response from wallet module!")
+}
+
+fn fee_rate(interconnect: &dyn ModuleInterconect) -> Option<u64> {
+    let body = futures::executor::block_on(interconnect.call(
+        "wallet",
+        "/fee_rate".to_owned(),
+        Default::default(),
+    ))
+    .expect("Wallet module not present or malfunctioning!");
+
+    serde_json::from_value(body).expect("Malformed fee rate response from wallet module!")
+}
+
+/// Checks that `share` is `peer`'s decryption share of `message`.
+///
+/// Invalid shares are dropped from the agreed set at the end of the epoch, so all guardians have to
+/// come to the same decision. This only takes the consensus config as arguments to rule out any
+/// dependency on local state like the database or the guardian's own secret key share.
+fn verify_decryption_share(
+    pub_keys: &threshold_crypto::PublicKeySet,
+    total_peers: usize,
+    peer: PeerId,
+    share: &PreimageDecryptionShare,
+    message: &EncryptedPreimage,
+) -> bool {
+    // Public key shares can be derived for any index, so shares of non-members could verify
+    if peer.to_usize() >= total_peers {
+        warn!(%peer, "Received decryption share from a peer outside of the federation");
+        return false;
+    }
+
+    pub_keys
+        .public_key_share(peer.to_usize())
+        .verify_decryption_share(&share.0, &message.0)
+}
+
+/// Number of bytes the database entry `key` with `value` occupies
+fn entry_bytes<K>(key: &K, value: &K::Value) -> usize
+where
+    K: DatabaseKeyPrefix + DatabaseKeyPrefixConst,
+{
+    DatabaseKeyPrefix::to_bytes(key).len() + SerializableDatabaseValue::to_bytes(value).len()
+}
+
+/// Decrypts `encrypted` from decryption shares gathered outside of consensus, e.g. exported using
+/// [`LightningModule::export_decryption_share`]. Invalid shares are ignored.
+pub fn decrypt_offline(
+    pub_keys: &threshold_crypto::PublicKeySet,
+    shares: Vec<(PeerId, PreimageDecryptionShare)>,
+    encrypted: &EncryptedPreimage,
+) -> Result<[u8; 32], LightningModuleError> {
+    let valid_shares: BTreeMap<usize, threshold_crypto::DecryptionShare> = shares
+        .into_iter()
+        .filter(|(peer, share)| {
+            let valid = pub_keys
+                .public_key_share(peer.to_usize())
+                .verify_decryption_share(&share.0, &encrypted.0);
+            if !valid {
+                warn!(%peer, "Ignoring invalid decryption share");
+            }
+            valid
+        })
+        .map(|(peer, share)| (peer.to_usize(), share.0))
+        .collect();
+
+    let threshold = pub_keys.threshold() + 1;
+    if valid_shares.len() < threshold {
+        return Err(LightningModuleError::NotEnoughDecryptionShares(
+            valid_shares.len(),
+            threshold,
+        ));
+    }
+
+    pub_keys
+        .decrypt(
+            valid_shares.iter().map(|(idx, share)| (*idx, share)),
+            &encrypted.0,
+        )
+        .map_err(|_| LightningModuleError::DecryptionFailed)?
+        .try_into()
+        .map_err(|_| LightningModuleError::DecryptionFailed)
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum LightningModuleError {
+    #[error("The the input contract {0} does not exist")]
+    UnknownContract(ContractId),
+    /// Breaking change: used to be the tuple variant `InsufficientFunds(available, requested)`
+    /// before the contract id was added, matches on it need to be updated.
+    #[error(
+        "The input contract {contract} has too little funds, got {available}, input spends {requested}"
+    )]
+    InsufficientFunds {
+        contract: ContractId,
+        available: Amount,
+        requested: Amount,
+    },
+    #[error("An outgoing LN contract spend did not provide a preimage")]
+    MissingPreimage,
+    #[error("An outgoing LN contract spend provided a wrong preimage")]
+    InvalidPreimage,
+    #[error("Incoming contract not ready to be spent yet, decryption in progress")]
+    ContractNotReady,
+    #[error("Output contract value may not be zero unless it's an offer output")]
+    ZeroOutput,
+    #[error("Offer contains invalid threshold-encrypted data")]
+    InvalidEncryptedPreimage,
+    #[error(
+        "The incoming LN account requires more funding according to the offer (need {0} got {1})"
+    )]
+    InsufficientIncomingFunding(Amount, Amount),
+    #[error("No offer found for payment hash {0}")]
+    NoOffer(secp256k1::hashes::sha256::Hash),
+    #[error("Only outgoing contracts support this operation")]
+    NotOutgoingContract,
+    #[error("Cancellation request wasn't properly signed")]
+    InvalidCancellationSignature,
+    #[error("Outgoing contract uses the same key for the gateway and the user")]
+    DegenerateOutgoingContract,
+    #[error("Offer batch contains {0} offers, exceeding the maximum batch size")]
+    OfferBatchTooLarge(usize),
+    #[error("Offer batch contains multiple offers for payment hash {0}")]
+    DuplicateOffer(secp256k1::hashes::sha256::Hash),
+    #[error("Offer amount may not be zero")]
+    ZeroOffer,
+    #[error("Only incoming contracts can be decrypted")]
+    NotIncomingContract,
+    #[error("Not enough valid decryption shares (got {0}, need {1})")]
+    NotEnoughDecryptionShares(usize, usize),
+    #[error("Preimage could not be decrypted")]
+    DecryptionFailed,
+    #[error("The contract {0} is frozen")]
+    ContractFrozen(ContractId),
+    #[error("Contract has to be spent entirely, has {0}, input spends {1}")]
+    PartialSpendDisallowed(Amount, Amount),
+    #[error("Contract hash may not be all zeros")]
+    EmptyHash,
+    #[error("Incoming contracts have to be created with a pending preimage decryption")]
+    PreimageAlreadyDecrypted,
+    #[error("The offer can only be funded from block height {0} on")]
+    OfferNotYetActive(u32),
+    #[error("Recipient amounts have to sum up to the contract amount {0}, got {1}")]
+    InvalidRecipientSplit(Amount, Amount),
+    #[error(
+        "Spending an incoming contract with multiple recipients requires naming the recipient"
+    )]
+    MissingRecipient,
+    #[error("{0} is not a recipient of the incoming contract")]
+    UnknownRecipient(secp256k1::XOnlyPublicKey),
+    #[error("The recipient's remaining share is {0}, input spends {1}")]
+    RecipientShareExceeded(Amount, Amount),
+    #[error("Contract amount {0} is not a multiple of {1}")]
+    AmountNotAligned(Amount, Amount),
+    #[error("The offer {0} was already funded")]
+    OfferAlreadyFunded(OfferId),
+    #[error("Timelock {0} exceeds the maximum timelock {1}")]
+    TimelockTooFar(u32, u32),
+    #[error("A different offer for payment hash {0} is already registered")]
+    OfferConflict(bitcoin_hashes::sha256::Hash),
+    #[error("Amount {0} is smaller than the {1} subtracted from it")]
+    AmountUnderflow(Amount, Amount),
+    #[error("The encrypted preimage doesn't match the one of the offer for payment hash {0}")]
+    PreimageMismatch(bitcoin_hashes::sha256::Hash),
+    #[error("The contract doesn't fund the offer for payment hash {0} it is bundled with")]
+    BundledOfferMismatch(bitcoin_hashes::sha256::Hash),
+    #[error("Offer signature is invalid")]
+    InvalidOfferSignature,
+    #[error("Offers have to be signed by the receiver")]
+    UnsignedOffer,
+    #[error("Account threshold has to be between one and the number of distinct keys")]
+    InvalidAccountMultisig,
+    #[error("Spending a shared custody account requires naming the signers")]
+    MissingSigners,
+    #[error("{0} is not a key of the account")]
+    UnknownSigner(secp256k1::XOnlyPublicKey),
+    #[error("Spend is signed by {0} keys, account requires {1}")]
+    NotEnoughSigners(usize, usize),
+    #[error("Change has to be paid into an account contract other than the spent one")]
+    InvalidChangeContract,
+    #[error("Transaction has {0} contract inputs, at most {1} are allowed")]
+    TooManyInputs(usize, usize),
+    #[error("Incoming contracts have to name their recipients under the raw preimage policy")]
+    MissingClaimKey,
+    #[error("Refund height {0} is below the minimum refund height {1}")]
+    RefundHeightTooEarly(u32, u32),
+    #[error("Incoming contracts can only have multiple recipients if partial spends are allowed")]
+    SplitWithoutPartialSpends,
+}
+
+impl LightningModuleError {
+    /// Maps the error to an HTTP status code for APIs exposing the module:
+    ///
+    /// * `400` if the request itself is malformed, e.g. carries an invalid preimage or signature
+    ///   or targets the wrong contract type
+    /// * `404` if a referenced contract or offer doesn't exist
+    /// * `409` if the request conflicts with the current contract state, e.g. the contract is
+    ///   frozen or still being decrypted
+    /// * `422` if the request is well-formed but violates an amount or offer rule
+    /// * `500` if the federation failed to process a valid request
+    pub fn status_code(&self) -> u16 {
+        match self {
+            LightningModuleError::MissingPreimage
+            | LightningModuleError::InvalidPreimage
+            | LightningModuleError::InvalidEncryptedPreimage
+            | LightningModuleError::NotOutgoingContract
+            | LightningModuleError::NotIncomingContract
+            | LightningModuleError::InvalidCancellationSignature
+            | LightningModuleError::InvalidOfferSignature
+            | LightningModuleError::EmptyHash
+            | LightningModuleError::PreimageAlreadyDecrypted
+            | LightningModuleError::MissingRecipient
+            | LightningModuleError::UnknownRecipient(_)
+            | LightningModuleError::MissingSigners
+            | LightningModuleError::UnknownSigner(_) => 400,
+            LightningModuleError::UnknownContract(_) | LightningModuleError::NoOffer(_) => 404,
+            LightningModuleError::ContractNotReady
+            | LightningModuleError::NotEnoughDecryptionShares(_, _)
+            | LightningModuleError::ContractFrozen(_)
+            | LightningModuleError::OfferNotYetActive(_)
+            | LightningModuleError::OfferAlreadyFunded(_)
+            | LightningModuleError::OfferConflict(_) => 409,
+            LightningModuleError::InsufficientFunds { .. }
+            | LightningModuleError::ZeroOutput
+            | LightningModuleError::InsufficientIncomingFunding(_, _)
+            | LightningModuleError::DegenerateOutgoingContract
+            | LightningModuleError::InvalidAccountMultisig
+            | LightningModuleError::InvalidChangeContract
+            | LightningModuleError::TooManyInputs(_, _)
+            | LightningModuleError::MissingClaimKey
+            | LightningModuleError::NotEnoughSigners(_, _)
+            | LightningModuleError::OfferBatchTooLarge(_)
+            | LightningModuleError::DuplicateOffer(_)
+            | LightningModuleError::ZeroOffer
+            | LightningModuleError::PartialSpendDisallowed(_, _)
+            | LightningModuleError::InvalidRecipientSplit(_, _)
+            | LightningModuleError::RecipientShareExceeded(_, _)
+            | LightningModuleError::AmountNotAligned(_, _)
+            | LightningModuleError::TimelockTooFar(_, _)
+            | LightningModuleError::RefundHeightTooEarly(_, _)
+            | LightningModuleError::SplitWithoutPartialSpends
+            | LightningModuleError::AmountUnderflow(_, _)
+            | LightningModuleError::PreimageMismatch(_)
+            | LightningModuleError::BundledOfferMismatch(_)
+            | LightningModuleError::UnsignedOffer => 422,
+            LightningModuleError::DecryptionFailed => 500,
+        }
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum AwaitError {
+    #[error("The contract is not an incoming contract")]
+    NotIncomingContract,
+    #[error("The decrypted preimage was invalid")]
+    InvalidPreimage,
+    #[error("The decrypted preimage isn't 32 bytes long and only available raw")]
+    RawPreimage(Vec<u8>),
+    #[error("Timed out waiting for the preimage to be decrypted")]
+    Timeout,
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::num::NonZeroUsize;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use bitcoin_hashes::sha256;
+    use bitcoin_hashes::Hash as BitcoinHash;
+    use fedimint_api::db::batch::{BatchItem, DbBatch};
+    use fedimint_api::db::mem_impl::MemDatabase;
+    use fedimint_api::db::DatabaseKeyPrefix;
+    use fedimint_api::encoding::Encodable;
+    use fedimint_api::module::interconnect::ModuleInterconect;
+    use fedimint_api::module::ApiError;
+    use fedimint_api::{Amount, FederationModule, OutPoint, PeerId};
+    use secp256k1::rand::rngs::mock::StepRng;
+    use secp256k1::rand::rngs::OsRng;
+    use secp256k1::KeyPair;
+    use threshold_crypto::serde_impl::SerdeSecret;
+    use threshold_crypto::SecretKeySet;
+
+    use crate::config::{FeeConsensus, LightningModuleConfig, PreimageHash, PreimagePolicy};
+    use crate::contracts::account::{AccountContract, AccountMultisig};
+    use crate::contracts::incoming::{
+        IncomingContract, IncomingContractOffer, OfferId, OfferSignature,
+    };
+    use crate::contracts::outgoing::OutgoingContract;
+    use crate::contracts::{
+        Contract, ContractId, ContractOutcome, DecryptedPreimage, EncryptedPreimage,
+        FundedContract, IdentifyableContract, OutgoingContractOutcome, Preimage,
+        PreimageDecryptionShare,
+    };
+    use crate::db::{
+        AgreedDecryptionShareKey, AgreedDecryptionShareKeyPrefix,
+        AgreedFallbackShareContractPrefix, AgreedFallbackShareKey, AgreedFreezeContractPrefix,
+        AgreedFreezeKey, ContractCreatedKey, ContractKey, ContractUpdateKey, DecryptionReadyKey,
+        DecryptionReadyKeyPrefix, EpochSpentContractKey, FailedShareKey, FreezeVoteEpochKey,
+        FrozenContractKey, FundedOfferKey, InvalidPreimageRefundableKey, OfferKey,
+        PaymentHashIndexKey, ProposeDecryptionShareKey, ProposeFallbackShareKey, ProposeFreezeKey,
+        RedundantDecryptionAttemptKey, ShareProposalAttemptKey,
+    };
+    use crate::{
+        decrypt_offline, sort_decryption_shares, ApplyOutputEffect, AwaitError, ContractAccount,
+        ContractBalanceChanged, ContractCounts, ContractInput, ContractInputSource,
+        ContractOrOfferOutput, ContractOutput, ContractWitness, DecryptionShareCI,
+        LightningConsensusItem, LightningModule, LightningModuleError, LnLiabilities,
+        OutputOutcome, OutputStatus, MAX_FREEZE_VOTE_EPOCHS, MAX_SHARE_PROPOSALS,
+        MIN_INCOMING_REFUND_DELTA,
+    };
+
+    const PEERS: usize = 4;
+    const THRESHOLD: usize = 3;
+
+    fn build_modules() -> (SecretKeySet, Vec<LightningModule>) {
+        build_modules_with(|_| {})
+    }
+
+    /// Builds the modules of a federation after applying `patch_cfg` to each peer's config
+    fn build_modules_with(
+        patch_cfg: impl Fn(&mut LightningModuleConfig),
+    ) -> (SecretKeySet, Vec<LightningModule>) {
+        let sks = SecretKeySet::random(THRESHOLD - 1, &mut OsRng);
+
+        let modules = (0..PEERS)
+            .map(|peer| {
+                let mut cfg = LightningModuleConfig {
+                    threshold_pub_keys: sks.public_keys(),
+                    threshold_sec_key: SerdeSecret(sks.secret_key_share(peer)),
+                    threshold: THRESHOLD,
+                    total_peers: PEERS,
+                    fee_consensus: FeeConsensus::default(),
+                    preimage_policy: PreimagePolicy::default(),
+                    preimage_hash: PreimageHash::default(),
+                    auto_decrypt: true,
+                    allow_partial_spends: true,
+                    decrypt_delay_epochs: 0,
+                    amount_granularity: None,
+                    max_timelock_delta: None,
+                    max_inputs_per_tx: None,
+                    auto_refund_invalid: false,
+                    require_signed_offers: false,
+                    fallback_pub_keys: None,
+                    fallback_sec_key: None,
+                };
+                patch_cfg(&mut cfg);
+                LightningModule::new(cfg, MemDatabase::new().into())
+            })
+            .collect();
+
+        (sks, modules)
+    }
+
+    fn peers() -> HashSet<PeerId> {
+        (0..PEERS as u16).map(PeerId::from).collect()
+    }
+
+    fn user_preimage() -> Preimage {
+        let user_key = KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng);
+        Preimage(user_key.x_only_public_key().0.serialize())
+    }
+
+    fn decryption_share(
+        module: &LightningModule,
+        encrypted_preimage: &EncryptedPreimage,
+    ) -> PreimageDecryptionShare {
+        PreimageDecryptionShare(
+            module
+                .cfg
+                .threshold_sec_key
+                .decrypt_share(&encrypted_preimage.0)
+                .expect("valid ciphertext"),
+        )
+    }
+
+    /// Writes `contract` funded with 42 sat directly into the module's database, bypassing
+    /// transaction processing
+    fn fund_contract(module: &LightningModule, contract: &Contract) -> OutPoint {
+        let out_point = OutPoint {
+            txid: sha256::Hash::hash(&contract.contract_id()[..]).into(),
+            out_idx: 0,
+        };
+
+        module
+            .db
+            .insert_entry(
+                &ContractKey(contract.contract_id()),
+                &ContractAccount {
+                    amount: Amount::from_sat(42),
+                    contract: contract
+                        .clone()
+                        .to_funded(out_point, module.current_epoch()),
+                },
+            )
+            .expect("DB error");
+        module
+            .db
+            .insert_entry(
+                &ContractUpdateKey(out_point),
+                &OutputOutcome::Contract {
+                    id: contract.contract_id(),
+                    outcome: contract.to_outcome(),
+                },
+            )
+            .expect("DB error");
+
+        out_point
+    }
+
+    /// Writes a funded incoming contract directly into the module's database, bypassing
+    /// transaction processing
+    fn fund_incoming(module: &LightningModule, preimage: Preimage) -> (IncomingContract, OutPoint) {
+        let gateway_key = KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng);
+        let incoming = IncomingContract {
+            hash: module.cfg.preimage_hash.hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            decrypted_preimage: DecryptedPreimage::Pending,
+            gateway_key: gateway_key.x_only_public_key().0,
+            recipients: vec![],
+            incoming_refund_height: None,
+        };
+        let out_point = fund_contract(module, &Contract::Incoming(incoming.clone()));
+
+        (incoming, out_point)
+    }
+
+    /// Stores the decryption shares of all `modules` for `incoming` as agreed in `module` and
+    /// marks the contract as ready for decryption
+    fn agree_decryption_shares(
+        module: &LightningModule,
+        modules: &[LightningModule],
+        incoming: &IncomingContract,
+    ) {
+        for (peer, member) in modules.iter().enumerate() {
+            module
+                .db
+                .insert_entry(
+                    &AgreedDecryptionShareKey(incoming.contract_id(), PeerId::from(peer as u16)),
+                    &decryption_share(member, &incoming.encrypted_preimage),
+                )
+                .expect("DB error");
+        }
+        module
+            .db
+            .insert_entry(&DecryptionReadyKey(incoming.contract_id()), &())
+            .expect("DB error");
+    }
+
+    /// Funds an incoming contract selling `preimage` and runs the decryption, returning the result
+    async fn decrypt_incoming(
+        module: &LightningModule,
+        modules: &[LightningModule],
+        preimage: Preimage,
+    ) -> DecryptedPreimage {
+        let (incoming, _) = fund_incoming(module, preimage);
+        agree_decryption_shares(module, modules, &incoming);
+        end_epoch(module).await;
+
+        match module
+            .get_contract_account(incoming.contract_id())
+            .expect("contract exists")
+            .contract
+        {
+            FundedContract::Incoming(incoming) => incoming.contract.decrypted_preimage,
+            _ => unreachable!("funded an incoming contract"),
+        }
+    }
+
+    /// Answers block height requests of the LN module as if no block was mined yet
+    struct GenesisInterconnect;
+
+    #[async_trait::async_trait]
+    impl ModuleInterconect for GenesisInterconnect {
+        async fn call(
+            &self,
+            _module: &'static str,
+            path: String,
+            _data: serde_json::Value,
+        ) -> Result<serde_json::Value, ApiError> {
+            assert_eq!(path, "/block_height");
+            Ok(serde_json::to_value(0u32).expect("encoding error"))
+        }
+    }
+
+    fn apply_input(
+        module: &LightningModule,
+        input: &ContractInput,
+    ) -> Result<(), LightningModuleError> {
+        let mut batch = DbBatch::new();
+        module.apply_input(&GenesisInterconnect, batch.transaction(), input, &())?;
+        module.db.apply_batch(batch).expect("DB error");
+        Ok(())
+    }
+
+    /// Records `block_height` like the transaction layer does at the beginning of every epoch
+    fn record_block_height(module: &LightningModule, block_height: u32) {
+        let mut dbtx = module.db.begin_transaction();
+        module.record_block_height(&mut dbtx, block_height);
+        dbtx.commit_tx().expect("DB error");
+    }
+
+    async fn end_epoch(module: &LightningModule) {
+        let mut batch = DbBatch::new();
+        module
+            .end_consensus_epoch(&peers(), batch.transaction(), OsRng)
+            .await;
+        module.db.apply_batch(batch).expect("DB error");
+
+        #[cfg(debug_assertions)]
+        module.check_invariants();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test_log::test]
+    #[should_panic(expected = "references non-existent incoming contract")]
+    fn test_invariant_violation_panics() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let encrypted_preimage =
+            EncryptedPreimage::new(user_preimage(), &module.cfg.threshold_pub_keys.public_key());
+        let unknown_contract = ContractId::from_hash(sha256::Hash::hash(b"foo"));
+        module
+            .db
+            .insert_entry(
+                &AgreedDecryptionShareKey(unknown_contract, PeerId::from(0)),
+                &decryption_share(module, &encrypted_preimage),
+            )
+            .expect("DB error");
+
+        module.check_invariants();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_out_of_key_set_share_excluded() {
+        let (sks, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let (incoming, out_point) = fund_incoming(module, preimage.clone());
+        let contract_id = incoming.contract_id();
+        agree_decryption_shares(module, &modules, &incoming);
+
+        // A cryptographically valid share for an index that was never dealt to a peer
+        let out_of_range_share = PreimageDecryptionShare(
+            sks.secret_key_share(PEERS + 3)
+                .decrypt_share(&incoming.encrypted_preimage.0)
+                .expect("valid ciphertext"),
+        );
+        module
+            .db
+            .insert_entry(
+                &AgreedDecryptionShareKey(contract_id, PeerId::from(PEERS as u16 + 3)),
+                &out_of_range_share,
+            )
+            .expect("DB error");
+
+        let shares = module
+            .db
+            .find_by_prefix(&AgreedDecryptionShareKeyPrefix)
+            .map(|res| {
+                let (AgreedDecryptionShareKey(_, peer), share) = res.expect("DB error");
+                (peer, share)
+            })
+            .collect();
+        let key_set_indices = module
+            .key_set_decryption_shares(&shares)
+            .into_iter()
+            .map(|(idx, _)| idx)
+            .collect::<HashSet<_>>();
+        assert_eq!(key_set_indices, (0..PEERS).collect());
+
+        end_epoch(module).await;
+
+        assert_eq!(
+            module.output_status(out_point),
+            Some(OutputOutcome::Contract {
+                id: contract_id,
+                outcome: ContractOutcome::Incoming(DecryptedPreimage::Some(preimage)),
+            })
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_preimage_policy_schnorr() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        assert_eq!(
+            decrypt_incoming(module, &modules, preimage.clone()).await,
+            DecryptedPreimage::Some(preimage)
+        );
+
+        // Exceeds the field size, so it's never a valid public key
+        let raw_preimage = Preimage([0xff; 32]);
+        assert_eq!(
+            decrypt_incoming(module, &modules, raw_preimage).await,
+            DecryptedPreimage::Invalid
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_preimage_policy_raw_hash() {
+        let (_, modules) =
+            build_modules_with(|cfg| cfg.preimage_policy = PreimagePolicy::RawHashPreimage);
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        assert_eq!(
+            decrypt_incoming(module, &modules, preimage.clone()).await,
+            DecryptedPreimage::Some(preimage)
+        );
+
+        let raw_preimage = Preimage([0xff; 32]);
+        assert_eq!(
+            decrypt_incoming(module, &modules, raw_preimage.clone()).await,
+            DecryptedPreimage::Some(raw_preimage)
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_raw_preimage_claim() {
+        let (_, modules) =
+            build_modules_with(|cfg| cfg.preimage_policy = PreimagePolicy::RawHashPreimage);
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+        let recipient = key();
+
+        let preimage = b"not a 32 byte preimage".to_vec();
+        let incoming = |recipients| IncomingContract {
+            hash: module.payment_hash(&preimage),
+            encrypted_preimage: EncryptedPreimage::encrypt(
+                &preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            decrypted_preimage: DecryptedPreimage::Pending,
+            gateway_key: key(),
+            recipients,
+            incoming_refund_height: None,
+        };
+
+        // Without recipients nobody could claim the contract
+        let output = ContractOrOfferOutput::Contract(ContractOutput {
+            amount: Amount::from_sat(42),
+            contract: Contract::Incoming(incoming(vec![])),
+        });
+        assert_eq!(
+            module.validate_output(&output).map(|_| ()),
+            Err(LightningModuleError::MissingClaimKey)
+        );
+
+        let incoming = incoming(vec![(recipient, Amount::from_sat(42))]);
+        let contract = Contract::Incoming(incoming.clone());
+        fund_contract(module, &contract);
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+
+        assert_eq!(
+            module
+                .await_decryption(contract.contract_id(), Duration::from_secs(10))
+                .await,
+            Err(AwaitError::RawPreimage(preimage))
+        );
+
+        let input = ContractInput {
+            contract_id: contract.contract_id(),
+            amount: Amount::from_sat(42),
+            witness: ContractWitness::None,
+            recipient: Some(recipient),
+            change_contract: None,
+        };
+        assert_eq!(
+            module
+                .validate_input_at(&input, 0)
+                .map(|meta| meta.puk_keys.collect::<Vec<_>>()),
+            Ok(vec![recipient])
+        );
+        assert_eq!(apply_input(module, &input), Ok(()));
+        assert_eq!(
+            module
+                .get_contract_account(contract.contract_id())
+                .map(|account| account.amount),
+            Some(Amount::ZERO)
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_await_decryption() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let (incoming, _) = fund_incoming(module, preimage.clone());
+
+        let decrypt = async {
+            fedimint_api::task::sleep(Duration::from_millis(200)).await;
+            agree_decryption_shares(module, &modules, &incoming);
+            end_epoch(module).await;
+        };
+        let (result, _) = tokio::join!(
+            module.await_decryption(incoming.contract_id(), Duration::from_secs(10)),
+            decrypt
+        );
+        assert_eq!(result, Ok(preimage));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_await_decryption_published() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let (incoming, _) = fund_incoming(module, preimage.clone());
+        agree_decryption_shares(module, &modules, &incoming);
+
+        // The epoch's batch isn't applied, so the preimage is only known from the publication
+        let mut batch = DbBatch::new();
+        module
+            .end_consensus_epoch(&peers(), batch.transaction(), OsRng)
+            .await;
+        assert_eq!(
+            module
+                .await_decryption(incoming.contract_id(), Duration::from_secs(10))
+                .await,
+            Ok(preimage)
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_await_decryption_invalid() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, Preimage([0xff; 32]));
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+
+        assert_eq!(
+            module
+                .await_decryption(incoming.contract_id(), Duration::from_secs(10))
+                .await,
+            Err(AwaitError::InvalidPreimage)
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_await_decryption_timeout() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+
+        assert_eq!(
+            module
+                .await_decryption(incoming.contract_id(), Duration::from_millis(300))
+                .await,
+            Err(AwaitError::Timeout)
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_input_source() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+        let source = |contract_id: ContractId, witness: ContractWitness, block_height: u32| {
+            let input = ContractInput {
+                contract_id,
+                amount: Amount::from_sat(42),
+                witness,
+                recipient: None,
+                change_contract: None,
+            };
+            module
+                .validate_input_with_source(&input, block_height)
+                .map(|(_, source)| source)
+        };
+
+        let account = Contract::Account(AccountContract {
+            key: key(),
+            multisig: None,
+        });
+        fund_contract(module, &account);
+        assert_eq!(
+            source(account.contract_id(), ContractWitness::None, 0),
+            Ok(ContractInputSource::AccountSpend)
+        );
+
+        let preimage = Preimage([42; 32]);
+        let outgoing = Contract::Outgoing(OutgoingContract {
+            hash: sha256::Hash::hash(&preimage.0),
+            gateway_key: key(),
+            timelock: 42,
+            user_key: key(),
+            invoice: "not enforced yet".to_string(),
+            invoice_amount: Amount::from_sat(40),
+            cancelled: false,
+        });
+        fund_contract(module, &outgoing);
+        assert_eq!(
+            source(
+                outgoing.contract_id(),
+                ContractWitness::Preimage(preimage),
+                0
+            ),
+            Ok(ContractInputSource::OutgoingClaim)
+        );
+        assert_eq!(
+            source(outgoing.contract_id(), ContractWitness::None, 42),
+            Ok(ContractInputSource::OutgoingRefund)
+        );
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+        assert_eq!(
+            source(incoming.contract_id(), ContractWitness::None, 0),
+            Ok(ContractInputSource::IncomingClaim)
+        );
+
+        let (invalid_incoming, _) = fund_incoming(module, Preimage([0xff; 32]));
+        agree_decryption_shares(module, &modules, &invalid_incoming);
+        end_epoch(module).await;
+        assert_eq!(
+            source(invalid_incoming.contract_id(), ContractWitness::None, 0),
+            Ok(ContractInputSource::IncomingRefund)
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_decrypt_offline() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let (incoming, _) = fund_incoming(module, preimage.clone());
+        for member in &modules {
+            member
+                .db
+                .insert_entry(
+                    &ProposeDecryptionShareKey(incoming.contract_id()),
+                    &decryption_share(member, &incoming.encrypted_preimage),
+                )
+                .expect("DB error");
+        }
+
+        let shares = modules
+            .iter()
+            .take(THRESHOLD)
+            .map(|member| {
+                member
+                    .export_decryption_share(incoming.contract_id())
+                    .expect("share was proposed")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decrypt_offline(
+                &module.cfg.threshold_pub_keys,
+                shares[..THRESHOLD - 1].to_vec(),
+                &incoming.encrypted_preimage
+            ),
+            Err(LightningModuleError::NotEnoughDecryptionShares(
+                THRESHOLD - 1,
+                THRESHOLD
+            ))
+        );
+        let offline_preimage = decrypt_offline(
+            &module.cfg.threshold_pub_keys,
+            shares,
+            &incoming.encrypted_preimage,
+        )
+        .expect("enough valid shares");
+
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+        match module
+            .get_contract_account(incoming.contract_id())
+            .expect("contract exists")
+            .contract
+        {
+            FundedContract::Incoming(funded) => assert_eq!(
+                funded.contract.decrypted_preimage,
+                DecryptedPreimage::Some(Preimage(offline_preimage))
+            ),
+            _ => panic!("Expected incoming contract"),
+        }
+        assert_eq!(offline_preimage, preimage.0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_decrypt_delay() {
+        let (_, modules) = build_modules();
+        let preimage = user_preimage();
+        assert_eq!(
+            decrypt_incoming(&modules[0], &modules, preimage.clone()).await,
+            DecryptedPreimage::Some(preimage.clone())
+        );
+
+        let (_, modules) = build_modules_with(|cfg| cfg.decrypt_delay_epochs = 1);
+        let module = &modules[0];
+        let (incoming, _) = fund_incoming(module, preimage.clone());
+        // Without a delay the shares would be agreed upon and combined in the next epoch
+        end_epoch(module).await;
+        agree_decryption_shares(module, &modules, &incoming);
+        let decrypted_preimage = || match module
+            .get_contract_account(incoming.contract_id())
+            .expect("contract exists")
+            .contract
+        {
+            FundedContract::Incoming(funded) => funded.contract.decrypted_preimage,
+            _ => panic!("Expected incoming contract"),
+        };
+
+        end_epoch(module).await;
+        assert_eq!(decrypted_preimage(), DecryptedPreimage::Pending);
+
+        end_epoch(module).await;
+        assert_eq!(decrypted_preimage(), DecryptedPreimage::Some(preimage));
+    }
+
+    #[test_log::test]
+    fn test_estimated_decryption_epochs() {
+        let (_, modules) = build_modules();
+        assert_eq!(modules[0].estimated_decryption_epochs(), 1);
+
+        let (_, modules) = build_modules_with(|cfg| cfg.decrypt_delay_epochs = 2);
+        assert_eq!(modules[0].estimated_decryption_epochs(), 3);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_empty_decryption_share_group() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        module
+            .db
+            .insert_entry(&DecryptionReadyKey(incoming.contract_id()), &())
+            .expect("DB error");
+
+        let mut batch = DbBatch::new();
+        let bad_peers = module
+            .end_consensus_epoch(&peers(), batch.transaction(), OsRng)
+            .await;
+        module.db.apply_batch(batch).expect("DB error");
+
+        assert!(bad_peers.is_empty());
+        match module
+            .get_contract_account(incoming.contract_id())
+            .expect("contract exists")
+            .contract
+        {
+            FundedContract::Incoming(funded) => assert_eq!(
+                funded.contract.decrypted_preimage,
+                DecryptedPreimage::Pending
+            ),
+            _ => panic!("Expected incoming contract"),
+        }
+    }
+
+    #[test_log::test]
+    fn test_missing_decryption_peers() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        for (peer, member) in modules.iter().enumerate().take(3) {
+            module
+                .db
+                .insert_entry(
+                    &AgreedDecryptionShareKey(incoming.contract_id(), PeerId::from(peer as u16)),
+                    &decryption_share(member, &incoming.encrypted_preimage),
+                )
+                .expect("DB error");
+        }
+
+        let all_peers = (0..PEERS as u16).map(PeerId::from).collect::<Vec<_>>();
+        assert_eq!(
+            module.missing_decryption_peers(incoming.contract_id(), &all_peers),
+            vec![PeerId::from(3)]
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_liabilities() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+
+        fund_contract(
+            module,
+            &Contract::Account(AccountContract {
+                key: key(),
+                multisig: None,
+            }),
+        );
+        fund_contract(
+            module,
+            &Contract::Outgoing(OutgoingContract {
+                hash: sha256::Hash::hash(b"preimage"),
+                gateway_key: key(),
+                timelock: 42,
+                user_key: key(),
+                invoice: "not enforced yet".to_string(),
+                invoice_amount: Amount::from_sat(40),
+                cancelled: false,
+            }),
+        );
+        fund_incoming(module, user_preimage());
+        decrypt_incoming(module, &modules, user_preimage()).await;
+        decrypt_incoming(module, &modules, Preimage([0xff; 32])).await;
+
+        assert_eq!(
+            module.liabilities(),
+            LnLiabilities {
+                outgoing: Amount::from_sat(42),
+                incoming_pending: Amount::from_sat(42),
+                incoming_claimable: Amount::from_sat(84),
+                account: Amount::from_sat(42),
+            }
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_manual_decryption() {
+        let (_, modules) = build_modules_with(|cfg| cfg.auto_decrypt = false);
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let (incoming, out_point) = fund_incoming(module, preimage.clone());
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+
+        let contract_outcome = |outcome| {
+            Some(OutputOutcome::Contract {
+                id: incoming.contract_id(),
+                outcome: ContractOutcome::Incoming(outcome),
+            })
+        };
+        assert_eq!(
+            module.output_status(out_point),
+            contract_outcome(DecryptedPreimage::Pending)
+        );
+        assert_eq!(
+            module.missing_decryption_peers(
+                incoming.contract_id(),
+                &peers().into_iter().collect::<Vec<_>>()
+            ),
+            vec![]
+        );
+
+        let mut batch = DbBatch::new();
+        assert_eq!(
+            module.decrypt_contract(incoming.contract_id(), batch.transaction()),
+            Ok(DecryptedPreimage::Some(preimage.clone()))
+        );
+        module.db.apply_batch(batch).expect("DB error");
+
+        assert_eq!(
+            module.output_status(out_point),
+            contract_outcome(DecryptedPreimage::Some(preimage))
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_only_ready_contracts_decrypted() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (ready, ready_out_point) = fund_incoming(module, user_preimage());
+        let ready_shares = modules.iter().enumerate().map(|(peer, member)| {
+            (
+                PeerId::from(peer as u16),
+                LightningConsensusItem::DecryptionShare(DecryptionShareCI {
+                    contract_id: ready.contract_id(),
+                    peer: PeerId::from(peer as u16),
+                    share: decryption_share(member, &ready.encrypted_preimage),
+                }),
+            )
+        });
+
+        // Each of these contracts only receives shares of the first `THRESHOLD - 1` peers
+        let pending = (0..10)
+            .map(|_| fund_incoming(module, user_preimage()))
+            .collect::<Vec<_>>();
+        let pending_shares = pending.iter().flat_map(|(incoming, _)| {
+            modules
+                .iter()
+                .take(THRESHOLD - 1)
+                .enumerate()
+                .map(|(peer, member)| {
+                    (
+                        PeerId::from(peer as u16),
+                        LightningConsensusItem::DecryptionShare(DecryptionShareCI {
+                            contract_id: incoming.contract_id(),
+                            peer: PeerId::from(peer as u16),
+                            share: decryption_share(member, &incoming.encrypted_preimage),
+                        }),
+                    )
+                })
+        });
+
+        let mut dbtx = module.db.begin_transaction();
+        module
+            .begin_consensus_epoch(
+                &mut dbtx,
+                ready_shares.chain(pending_shares).collect(),
+                OsRng,
+            )
+            .await;
+        dbtx.commit_tx().expect("DB error");
+
+        let ready_contracts = module
+            .db
+            .find_by_prefix(&DecryptionReadyKeyPrefix)
+            .map(|res| res.expect("DB error").0 .0)
+            .collect::<Vec<_>>();
+        assert_eq!(ready_contracts, vec![ready.contract_id()]);
+
+        // Attempting to decrypt any of the pending contracts would report the peers that didn't
+        // contribute shares
+        let mut batch = DbBatch::new();
+        let bad_peers = module
+            .end_consensus_epoch(&peers(), batch.transaction(), OsRng)
+            .await;
+        module.db.apply_batch(batch).expect("DB error");
+        assert!(bad_peers.is_empty());
+
+        assert!(matches!(
+            module.output_status(ready_out_point),
+            Some(OutputOutcome::Contract {
+                outcome: ContractOutcome::Incoming(DecryptedPreimage::Some(_)),
+                ..
+            })
+        ));
+        for (_, out_point) in pending {
+            assert!(matches!(
+                module.output_status(out_point),
+                Some(OutputOutcome::Contract {
+                    outcome: ContractOutcome::Incoming(DecryptedPreimage::Pending),
+                    ..
+                })
+            ));
+        }
+        assert_eq!(
+            module.db.find_by_prefix(&DecryptionReadyKeyPrefix).count(),
+            0
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_encrypt_preimage() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let encrypted_preimage =
+            EncryptedPreimage::encrypt(&preimage.0, &module.cfg.threshold_pub_keys.public_key());
+        assert!(encrypted_preimage.0.verify());
+
+        let gateway_key = KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng);
+        let incoming = IncomingContract {
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage,
+            decrypted_preimage: DecryptedPreimage::Pending,
+            gateway_key: gateway_key.x_only_public_key().0,
+            recipients: vec![],
+            incoming_refund_height: None,
+        };
+        let out_point = fund_contract(module, &Contract::Incoming(incoming.clone()));
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+
+        assert_eq!(
+            module.output_status(out_point),
+            Some(OutputOutcome::Contract {
+                id: incoming.contract_id(),
+                outcome: ContractOutcome::Incoming(DecryptedPreimage::Some(preimage)),
+            })
+        );
+    }
+
+    #[test_log::test]
+    fn test_order_competing_fundings() {
+        let beacon = [42; 32];
+        let contract_ids = (0..10u8)
+            .map(|idx| ContractId::from_hash(sha256::Hash::hash(&[idx])))
+            .collect::<Vec<_>>();
+
+        let ordered = LightningModule::order_competing_fundings(&beacon, contract_ids.clone());
+        let reordered = LightningModule::order_competing_fundings(
+            &beacon,
+            contract_ids.iter().rev().cloned().collect(),
+        );
+        assert_eq!(ordered, reordered);
+        assert_eq!(
+            ordered.iter().collect::<HashSet<_>>(),
+            contract_ids.iter().collect::<HashSet<_>>()
+        );
+    }
+
+    #[test_log::test]
+    fn test_order_competing_fundings_with_rng() {
+        let contract_ids = (0..10u8)
+            .map(|idx| ContractId::from_hash(sha256::Hash::hash(&[idx])))
+            .collect::<Vec<_>>();
+        let order = |seed: u64| {
+            LightningModule::order_competing_fundings_with_rng(
+                &mut StepRng::new(seed, 1),
+                contract_ids.clone(),
+            )
+        };
+
+        assert_eq!(order(42), order(42));
+        assert_ne!(order(42), order(21));
+    }
+
+    #[test_log::test]
+    fn test_strict_spends() {
+        let (_, modules) = build_modules_with(|cfg| cfg.allow_partial_spends = false);
+        let module = &modules[0];
+
+        let account = Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        });
+        fund_contract(module, &account);
+        let input = |amount| ContractInput {
+            contract_id: account.contract_id(),
+            amount,
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract: None,
+        };
+
+        assert_eq!(
+            apply_input(module, &input(Amount::from_sat(21))),
+            Err(LightningModuleError::PartialSpendDisallowed(
+                Amount::from_sat(42),
+                Amount::from_sat(21)
+            ))
+        );
+        assert!(module.get_contract_account(account.contract_id()).is_some());
+
+        assert_eq!(apply_input(module, &input(Amount::from_sat(42))), Ok(()));
+        assert_eq!(module.get_contract_account(account.contract_id()), None);
+    }
+
+    #[test]
+    fn test_error_status_codes() {
+        let contract_id = ContractId::from_hash(sha256::Hash::hash(b"contract"));
+        let hash = sha256::Hash::hash(b"hash");
+        let amount = Amount::from_sat(42);
+
+        let cases = [
+            (LightningModuleError::UnknownContract(contract_id), 404),
+            (
+                LightningModuleError::InsufficientFunds {
+                    contract: contract_id,
+                    available: amount,
+                    requested: amount,
+                },
+                422,
+            ),
+            (LightningModuleError::MissingPreimage, 400),
+            (LightningModuleError::InvalidPreimage, 400),
+            (LightningModuleError::ContractNotReady, 409),
+            (LightningModuleError::ZeroOutput, 422),
+            (LightningModuleError::InvalidEncryptedPreimage, 400),
+            (
+                LightningModuleError::InsufficientIncomingFunding(amount, amount),
+                422,
+            ),
+            (LightningModuleError::NoOffer(hash), 404),
+            (LightningModuleError::NotOutgoingContract, 400),
+            (LightningModuleError::InvalidCancellationSignature, 400),
+            (LightningModuleError::DegenerateOutgoingContract, 422),
+            (LightningModuleError::OfferBatchTooLarge(42), 422),
+            (LightningModuleError::DuplicateOffer(hash), 422),
+            (LightningModuleError::ZeroOffer, 422),
+            (LightningModuleError::NotIncomingContract, 400),
+            (LightningModuleError::NotEnoughDecryptionShares(1, 3), 409),
+            (LightningModuleError::DecryptionFailed, 500),
+            (LightningModuleError::ContractFrozen(contract_id), 409),
+            (
+                LightningModuleError::PartialSpendDisallowed(amount, amount),
+                422,
+            ),
+            (LightningModuleError::EmptyHash, 400),
+            (LightningModuleError::PreimageAlreadyDecrypted, 400),
+            (LightningModuleError::OfferNotYetActive(42), 409),
+            (
+                LightningModuleError::InvalidRecipientSplit(amount, amount),
+                422,
+            ),
+            (LightningModuleError::MissingRecipient, 400),
+            (
+                LightningModuleError::UnknownRecipient(
+                    KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                        .x_only_public_key()
+                        .0,
+                ),
+                400,
+            ),
+            (
+                LightningModuleError::RecipientShareExceeded(amount, amount),
+                422,
+            ),
+            (LightningModuleError::AmountNotAligned(amount, amount), 422),
+            (LightningModuleError::TimelockTooFar(42, 21), 422),
+            (LightningModuleError::AmountUnderflow(amount, amount), 422),
+            (
+                LightningModuleError::PreimageMismatch(sha256::Hash::hash(b"offer")),
+                422,
+            ),
+            (
+                LightningModuleError::BundledOfferMismatch(sha256::Hash::hash(b"offer")),
+                422,
+            ),
+            (LightningModuleError::InvalidOfferSignature, 400),
+            (LightningModuleError::UnsignedOffer, 422),
+            (LightningModuleError::InvalidAccountMultisig, 422),
+            (LightningModuleError::MissingSigners, 400),
+            (
+                LightningModuleError::UnknownSigner(
+                    KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                        .x_only_public_key()
+                        .0,
+                ),
+                400,
+            ),
+            (LightningModuleError::NotEnoughSigners(1, 2), 422),
+            (LightningModuleError::InvalidChangeContract, 422),
+            (LightningModuleError::TooManyInputs(3, 2), 422),
+            (LightningModuleError::MissingClaimKey, 422),
+            (LightningModuleError::RefundHeightTooEarly(21, 42), 422),
+            (LightningModuleError::SplitWithoutPartialSpends, 422),
+            (
+                LightningModuleError::OfferConflict(sha256::Hash::hash(b"offer")),
+                409,
+            ),
+            (
+                LightningModuleError::OfferAlreadyFunded(OfferId::from_hash(sha256::Hash::hash(
+                    b"offer",
+                ))),
+                409,
+            ),
+        ];
+
+        for (error, status_code) in cases {
+            assert_eq!(error.status_code(), status_code, "{:?}", error);
+        }
+    }
+
+    #[test_log::test]
+    fn test_preimage_matches() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+
+        let preimage = Preimage([42; 32]);
+        let outgoing = Contract::Outgoing(OutgoingContract {
+            hash: sha256::Hash::hash(&preimage.0),
+            gateway_key: key(),
+            timelock: 42,
+            user_key: key(),
+            invoice: "not enforced yet".to_string(),
+            invoice_amount: Amount::from_sat(40),
+            cancelled: false,
+        });
+        fund_contract(module, &outgoing);
+        assert_eq!(
+            module.preimage_matches(outgoing.contract_id(), &preimage),
+            Ok(true)
+        );
+        assert_eq!(
+            module.preimage_matches(outgoing.contract_id(), &Preimage([21; 32])),
+            Ok(false)
+        );
+
+        let account = Contract::Account(AccountContract {
+            key: key(),
+            multisig: None,
+        });
+        fund_contract(module, &account);
+        assert_eq!(
+            module.preimage_matches(account.contract_id(), &preimage),
+            Err(LightningModuleError::NotOutgoingContract)
+        );
+    }
+
+    #[test_log::test]
+    fn test_encrypted_preimage() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        assert_eq!(
+            module.encrypted_preimage(incoming.contract_id()),
+            Some(incoming.encrypted_preimage.clone())
+        );
+
+        let account = Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        });
+        fund_contract(module, &account);
+        assert_eq!(module.encrypted_preimage(account.contract_id()), None);
+        assert_eq!(
+            module.encrypted_preimage(ContractId::from_hash(sha256::Hash::hash(b"unknown"))),
+            None
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_full_spend_deletion_order() {
+        let (_, modules) = build_modules_with(|cfg| cfg.allow_partial_spends = false);
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        let contract_id = incoming.contract_id();
+        let offer_id = OfferId::from_hash(incoming.hash);
+        module
+            .db
+            .insert_entry(&FundedOfferKey(offer_id), &contract_id)
+            .expect("DB error");
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+
+        // Shares arriving after the decryption are kept until the contract is spent
+        for peer in [3u16, 1] {
+            module
+                .db
+                .insert_entry(
+                    &AgreedDecryptionShareKey(contract_id, PeerId::from(peer)),
+                    &decryption_share(&modules[peer as usize], &incoming.encrypted_preimage),
+                )
+                .expect("DB error");
+        }
+        // Unfreeze votes that didn't reach the threshold yet
+        for peer in [2u16, 0] {
+            module
+                .db
+                .insert_entry(&AgreedFreezeKey(contract_id, PeerId::from(peer)), &false)
+                .expect("DB error");
+        }
+
+        // Counted failed spend attempts are forgotten too
+        assert!(module
+            .validate_input(
+                &GenesisInterconnect,
+                &(),
+                &ContractInput {
+                    contract_id,
+                    amount: Amount::from_sat(21),
+                    witness: ContractWitness::None,
+                    recipient: None,
+                    change_contract: None,
+                },
+            )
+            .is_err());
+        assert_eq!(module.spend_attempts(contract_id), 1);
+
+        let mut batch = DbBatch::new();
+        module
+            .apply_input(
+                &GenesisInterconnect,
+                batch.transaction(),
+                &ContractInput {
+                    contract_id,
+                    amount: Amount::from_sat(42),
+                    witness: ContractWitness::None,
+                    recipient: None,
+                    change_contract: None,
+                },
+                &(),
+            )
+            .expect("input is valid");
+
+        let deletions = Vec::<BatchItem>::from(batch)
+            .into_iter()
+            .filter_map(|item| match item {
+                BatchItem::DeleteElement(key) | BatchItem::MaybeDeleteElement(key) => {
+                    Some(key.to_bytes())
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // The offer funding index is kept
+        assert_eq!(
+            deletions,
+            vec![
+                ContractKey(contract_id).to_bytes(),
+                ProposeDecryptionShareKey(contract_id).to_bytes(),
+                AgreedDecryptionShareKey(contract_id, PeerId::from(1)).to_bytes(),
+                AgreedDecryptionShareKey(contract_id, PeerId::from(3)).to_bytes(),
+                DecryptionReadyKey(contract_id).to_bytes(),
+                ShareProposalAttemptKey(contract_id).to_bytes(),
+                FailedShareKey(contract_id).to_bytes(),
+                ProposeFallbackShareKey(contract_id).to_bytes(),
+                RedundantDecryptionAttemptKey(contract_id).to_bytes(),
+                InvalidPreimageRefundableKey(contract_id).to_bytes(),
+                ProposeFreezeKey(contract_id).to_bytes(),
+                AgreedFreezeKey(contract_id, PeerId::from(0)).to_bytes(),
+                AgreedFreezeKey(contract_id, PeerId::from(2)).to_bytes(),
+                FreezeVoteEpochKey(contract_id).to_bytes(),
+                FrozenContractKey(contract_id).to_bytes(),
+                ContractCreatedKey(contract_id).to_bytes(),
+            ]
+        );
+        assert_eq!(module.spend_attempts(contract_id), 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_full_spend_removes_contract_keys() {
+        let (_, modules) = build_modules_with(|cfg| cfg.allow_partial_spends = false);
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        let contract_id = incoming.contract_id();
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+
+        // Leave behind every kind of per-contract key
+        let share = decryption_share(module, &incoming.encrypted_preimage);
+        let mut dbtx = module.db.begin_transaction();
+        dbtx.insert_entry(&ProposeDecryptionShareKey(contract_id), &share)
+            .expect("DB error");
+        dbtx.insert_entry(
+            &AgreedDecryptionShareKey(contract_id, PeerId::from(1)),
+            &share,
+        )
+        .expect("DB error");
+        dbtx.insert_entry(&DecryptionReadyKey(contract_id), &())
+            .expect("DB error");
+        dbtx.insert_entry(&ShareProposalAttemptKey(contract_id), &1)
+            .expect("DB error");
+        dbtx.insert_entry(&FailedShareKey(contract_id), &share)
+            .expect("DB error");
+        dbtx.insert_entry(&ProposeFallbackShareKey(contract_id), &share)
+            .expect("DB error");
+        dbtx.insert_entry(
+            &AgreedFallbackShareKey(contract_id, PeerId::from(2)),
+            &share,
+        )
+        .expect("DB error");
+        dbtx.insert_entry(&RedundantDecryptionAttemptKey(contract_id), &1)
+            .expect("DB error");
+        dbtx.insert_entry(
+            &InvalidPreimageRefundableKey(contract_id),
+            &incoming.gateway_key,
+        )
+        .expect("DB error");
+        dbtx.insert_entry(&ContractCreatedKey(contract_id), &0)
+            .expect("DB error");
+        dbtx.insert_entry(&ProposeFreezeKey(contract_id), &true)
+            .expect("DB error");
+        dbtx.insert_entry(&AgreedFreezeKey(contract_id, PeerId::from(1)), &true)
+            .expect("DB error");
+        dbtx.insert_entry(&FreezeVoteEpochKey(contract_id), &0)
+            .expect("DB error");
+        dbtx.commit_tx().expect("DB error");
+
+        let spend_epoch = module.current_epoch();
+        apply_input(
+            module,
+            &ContractInput {
+                contract_id,
+                amount: Amount::from_sat(42),
+                witness: ContractWitness::None,
+                recipient: None,
+                change_contract: None,
+            },
+        )
+        .expect("input is valid");
+
+        // Only the epoch history still references the contract by its id
+        let contract_id_bytes = &contract_id[..];
+        let remaining = module
+            .db
+            .raw_find_by_prefix(&[])
+            .map(|res| res.expect("DB error").0)
+            .filter(|key| {
+                key.windows(contract_id_bytes.len())
+                    .any(|window| window == contract_id_bytes)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            remaining,
+            vec![EpochSpentContractKey(spend_epoch, contract_id).to_bytes()]
+        );
+    }
+
+    #[test_log::test]
+    fn test_validate_structure() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+
+        assert_eq!(
+            Contract::Account(AccountContract {
+                key: key(),
+                multisig: None
+            })
+            .validate_structure(),
+            Ok(())
+        );
+
+        let preimage = user_preimage();
+        let incoming = IncomingContract {
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage.clone(),
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            decrypted_preimage: DecryptedPreimage::Pending,
+            gateway_key: key(),
+            recipients: vec![],
+            incoming_refund_height: None,
+        };
+        assert_eq!(
+            Contract::Incoming(incoming.clone()).validate_structure(),
+            Ok(())
+        );
+        assert_eq!(
+            Contract::Incoming(IncomingContract {
+                hash: sha256::Hash::from_inner([0; 32]),
+                ..incoming.clone()
+            })
+            .validate_structure(),
+            Err(LightningModuleError::EmptyHash)
+        );
+        assert_eq!(
+            Contract::Incoming(IncomingContract {
+                decrypted_preimage: DecryptedPreimage::Some(preimage.clone()),
+                ..incoming
+            })
+            .validate_structure(),
+            Err(LightningModuleError::PreimageAlreadyDecrypted)
+        );
+
+        let outgoing = OutgoingContract {
+            hash: sha256::Hash::hash(&preimage.0),
+            gateway_key: key(),
+            timelock: 42,
+            user_key: key(),
+            invoice: "not enforced yet".to_string(),
+            invoice_amount: Amount::from_sat(40),
+            cancelled: false,
+        };
+        assert_eq!(
+            Contract::Outgoing(outgoing.clone()).validate_structure(),
+            Ok(())
+        );
+        assert_eq!(
+            Contract::Outgoing(OutgoingContract {
+                user_key: outgoing.gateway_key,
+                ..outgoing
+            })
+            .validate_structure(),
+            Err(LightningModuleError::DegenerateOutgoingContract)
+        );
+    }
+
+    #[test_log::test]
+    fn test_contract_cache() {
+        let (_, modules) = build_modules();
+        let module = LightningModule::new(modules[0].cfg.clone(), MemDatabase::new().into())
+            .with_contract_cache(NonZeroUsize::new(16).expect("not zero"));
+
+        let account = Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        });
+        let contract_id = account.contract_id();
+        fund_contract(&module, &account);
+        let funded_account = module.get_contract_account(contract_id);
+        assert_eq!(
+            funded_account.as_ref().map(|a| a.amount),
+            Some(Amount::from_sat(42))
+        );
+
+        // Writing to the database behind the module's back shows that the second read is a hit
+        module
+            .db
+            .insert_entry(
+                &ContractKey(contract_id),
+                &ContractAccount {
+                    amount: Amount::from_sat(1),
+                    contract: account.clone().to_funded(
+                        OutPoint {
+                            txid: sha256::Hash::hash(b"").into(),
+                            out_idx: 0,
+                        },
+                        0,
+                    ),
+                },
+            )
+            .expect("DB error");
+        assert_eq!(module.get_contract_account(contract_id), funded_account);
+        module.reset_contract_cache();
+
+        // Funding the contract again invalidates the cached account
+        assert_eq!(
+            module.get_contract_account(contract_id).map(|a| a.amount),
+            Some(Amount::from_sat(1))
+        );
+        let mut batch = DbBatch::new();
+        module
+            .apply_output(
+                batch.transaction(),
+                &ContractOrOfferOutput::Contract(ContractOutput {
+                    amount: Amount::from_sat(10),
+                    contract: account,
+                }),
+                OutPoint {
+                    txid: sha256::Hash::hash(b"funding").into(),
+                    out_idx: 0,
+                },
+            )
+            .expect("output is valid");
+        module.db.apply_batch(batch).expect("DB error");
+        assert_eq!(
+            module.get_contract_account(contract_id).map(|a| a.amount),
+            Some(Amount::from_sat(11))
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_misattributed_decryption_share_rejected() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        let share = |peer: u16| {
+            LightningConsensusItem::DecryptionShare(DecryptionShareCI {
+                contract_id: incoming.contract_id(),
+                peer: PeerId::from(peer),
+                share: decryption_share(&modules[peer as usize], &incoming.encrypted_preimage),
+            })
+        };
+
+        let mut dbtx = module.db.begin_transaction();
+        module
+            .begin_consensus_epoch(
+                &mut dbtx,
+                vec![(PeerId::from(1), share(1)), (PeerId::from(2), share(3))],
+                OsRng,
+            )
+            .await;
+        dbtx.commit_tx().expect("DB error");
+
+        let share_peers = module
+            .agreed_decryption_shares(incoming.contract_id())
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect::<Vec<_>>();
+        assert_eq!(share_peers, vec![PeerId::from(1)]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_redundant_decryption_attempts() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+        assert_eq!(module.redundant_decryption_attempts(), vec![]);
+
+        // Shares that weren't cleaned up make the contract look ready for decryption again
+        for _ in 0..2 {
+            agree_decryption_shares(module, &modules, &incoming);
+            end_epoch(module).await;
+        }
+        assert_eq!(
+            module.redundant_decryption_attempts(),
+            vec![(incoming.contract_id(), 2)]
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_preimage_hash() {
+        let preimage = user_preimage();
+        let sha256d_hash =
+            sha256::Hash::from_inner(bitcoin_hashes::sha256d::Hash::hash(&preimage.0).into_inner());
+        let outgoing = |hash| {
+            let key = || {
+                KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                    .x_only_public_key()
+                    .0
+            };
+            Contract::Outgoing(OutgoingContract {
+                hash,
+                gateway_key: key(),
+                timelock: 42,
+                user_key: key(),
+                invoice: "not enforced yet".to_string(),
+                invoice_amount: Amount::from_sat(40),
+                cancelled: false,
+            })
+        };
+
+        for (preimage_hash, expected_hash) in [
+            (PreimageHash::Sha256, sha256::Hash::hash(&preimage.0)),
+            (PreimageHash::Sha256d, sha256d_hash),
+        ] {
+            let (_, modules) = build_modules_with(|cfg| cfg.preimage_hash = preimage_hash);
+            let module = &modules[0];
+            assert_eq!(preimage_hash.hash(&preimage.0), expected_hash);
+
+            let matching = outgoing(expected_hash);
+            fund_contract(module, &matching);
+            assert_eq!(
+                module.preimage_matches(matching.contract_id(), &preimage),
+                Ok(true)
+            );
+
+            // A contract locked using the other hash function can't be claimed
+            let other_hash = match preimage_hash {
+                PreimageHash::Sha256 => sha256d_hash,
+                PreimageHash::Sha256d => sha256::Hash::hash(&preimage.0),
+            };
+            let mismatching = outgoing(other_hash);
+            fund_contract(module, &mismatching);
+            assert_eq!(
+                module.preimage_matches(mismatching.contract_id(), &preimage),
+                Ok(false)
+            );
+
+            assert_eq!(
+                decrypt_incoming(module, &modules, preimage.clone()).await,
+                DecryptedPreimage::Some(preimage.clone())
+            );
+        }
+    }
+
+    #[test_log::test]
+    fn test_offer_not_before() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: Some(100),
+            signature: None,
+        };
+        module
+            .db
+            .insert_entry(&OfferKey(offer.hash), &offer)
+            .expect("DB error");
+        let funding = ContractOrOfferOutput::Contract(ContractOutput {
+            amount: Amount::from_sat(42),
+            contract: Contract::Incoming(IncomingContract {
+                hash: offer.hash,
+                encrypted_preimage: offer.encrypted_preimage.clone(),
+                decrypted_preimage: DecryptedPreimage::Pending,
+                gateway_key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                    .x_only_public_key()
+                    .0,
+                recipients: vec![],
+                incoming_refund_height: None,
+            }),
+        });
+
+        let validate_at = |block_height: u32| {
+            record_block_height(module, block_height);
+            module.validate_output(&funding).map(|_| ())
+        };
+        assert_eq!(
+            validate_at(99),
+            Err(LightningModuleError::OfferNotYetActive(100))
+        );
+        assert_eq!(validate_at(100), Ok(()));
+        assert_eq!(validate_at(150), Ok(()));
+    }
+
+    #[test_log::test]
+    fn test_non_member_share_invalid() {
+        let (sks, modules) = build_modules();
+        let module = &modules[0];
+        let (incoming, _) = fund_incoming(module, user_preimage());
+
+        let share = |idx: usize| {
+            PreimageDecryptionShare(
+                sks.secret_key_share(idx)
+                    .decrypt_share(&incoming.encrypted_preimage.0)
+                    .expect("valid ciphertext"),
+            )
+        };
+
+        assert!(module.validate_decryption_share(
+            PeerId::from(1),
+            &share(1),
+            &incoming.encrypted_preimage
+        ));
+        assert!(!module.validate_decryption_share(
+            PeerId::from(PEERS as u16 + 3),
+            &share(PEERS + 3),
+            &incoming.encrypted_preimage
+        ));
+        assert!(!module.validate_decryption_share(
+            PeerId::from(u16::MAX),
+            &share(usize::from(u16::MAX)),
+            &incoming.encrypted_preimage
+        ));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_split_incoming_contract() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+        let (alice, bob) = (key(), key());
+
+        let preimage = user_preimage();
+        let incoming = IncomingContract {
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            decrypted_preimage: DecryptedPreimage::Pending,
+            gateway_key: key(),
+            recipients: vec![(alice, Amount::from_sat(30)), (bob, Amount::from_sat(12))],
+            incoming_refund_height: None,
+        };
+        let contract = Contract::Incoming(incoming.clone());
+        fund_contract(module, &contract);
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+
+        let input = |recipient, sats| ContractInput {
+            contract_id: contract.contract_id(),
+            amount: Amount::from_sat(sats),
+            witness: ContractWitness::None,
+            recipient,
+            change_contract: None,
+        };
+        let keys = |input: &ContractInput| {
+            module
+                .validate_input_at(input, 0)
+                .map(|meta| meta.puk_keys.collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            keys(&input(None, 30)),
+            Err(LightningModuleError::MissingRecipient)
+        );
+        let stranger = key();
+        assert_eq!(
+            keys(&input(Some(stranger), 30)),
+            Err(LightningModuleError::UnknownRecipient(stranger))
+        );
+        assert_eq!(
+            keys(&input(Some(bob), 30)),
+            Err(LightningModuleError::RecipientShareExceeded(
+                Amount::from_sat(12),
+                Amount::from_sat(30)
+            ))
+        );
+
+        assert_eq!(keys(&input(Some(alice), 30)), Ok(vec![alice]));
+        assert_eq!(apply_input(module, &input(Some(alice), 30)), Ok(()));
+        assert_eq!(
+            keys(&input(Some(alice), 1)),
+            Err(LightningModuleError::RecipientShareExceeded(
+                Amount::ZERO,
+                Amount::from_sat(1)
+            ))
+        );
+
+        assert_eq!(keys(&input(Some(bob), 12)), Ok(vec![bob]));
+        assert_eq!(apply_input(module, &input(Some(bob), 12)), Ok(()));
+        assert_eq!(
+            module
+                .get_contract_account(contract.contract_id())
+                .map(|account| account.amount),
+            Some(Amount::ZERO)
+        );
+    }
+
+    #[test_log::test]
+    fn test_invalid_recipient_split() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+
+        let preimage = user_preimage();
+        let output = ContractOrOfferOutput::Contract(ContractOutput {
+            amount: Amount::from_sat(42),
+            contract: Contract::Incoming(IncomingContract {
+                hash: sha256::Hash::hash(&preimage.0),
+                encrypted_preimage: EncryptedPreimage::new(
+                    preimage,
+                    &module.cfg.threshold_pub_keys.public_key(),
+                ),
+                decrypted_preimage: DecryptedPreimage::Pending,
+                gateway_key: key(),
+                recipients: vec![(key(), Amount::from_sat(30)), (key(), Amount::from_sat(10))],
+                incoming_refund_height: None,
+            }),
+        });
+
+        assert_eq!(
+            module.validate_output(&output).map(|_| ()),
+            Err(LightningModuleError::InvalidRecipientSplit(
+                Amount::from_sat(42),
+                Amount::from_sat(40)
+            ))
+        );
+    }
+
+    #[test_log::test]
+    fn test_split_requires_partial_spends() {
+        let (_, modules) = build_modules_with(|cfg| cfg.allow_partial_spends = false);
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+
+        let preimage = user_preimage();
+        let output = |recipients| {
+            ContractOrOfferOutput::Contract(ContractOutput {
+                amount: Amount::from_sat(42),
+                contract: Contract::Incoming(IncomingContract {
+                    hash: sha256::Hash::hash(&preimage.0),
+                    encrypted_preimage: EncryptedPreimage::new(
+                        preimage.clone(),
+                        &module.cfg.threshold_pub_keys.public_key(),
+                    ),
+                    decrypted_preimage: DecryptedPreimage::Pending,
+                    gateway_key: key(),
+                    recipients,
+                    incoming_refund_height: None,
+                }),
+            })
+        };
+
+        assert_eq!(
+            module
+                .validate_output(&output(vec![
+                    (key(), Amount::from_sat(30)),
+                    (key(), Amount::from_sat(12))
+                ]))
+                .map(|_| ()),
+            Err(LightningModuleError::SplitWithoutPartialSpends)
+        );
+        // A single recipient spends the contract entirely, so only the missing offer is reported
+        assert_eq!(
+            module
+                .validate_output(&output(vec![(key(), Amount::from_sat(42))]))
+                .map(|_| ()),
+            Err(LightningModuleError::NoOffer(sha256::Hash::hash(
+                &preimage.0
+            )))
+        );
+    }
+
+    #[test_log::test]
+    fn test_amount_granularity() {
+        let (_, modules) =
+            build_modules_with(|cfg| cfg.amount_granularity = Some(Amount::from_sat(1)));
+        let module = &modules[0];
+        let output = |amount| {
+            ContractOrOfferOutput::Contract(ContractOutput {
+                amount,
+                contract: Contract::Account(AccountContract {
+                    key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                        .x_only_public_key()
+                        .0,
+                    multisig: None,
+                }),
+            })
+        };
+
+        assert!(module
+            .validate_output(&output(Amount::from_sat(42)))
+            .is_ok());
+        assert_eq!(
+            module
+                .validate_output(&output(Amount::from_msat(42_500)))
+                .map(|_| ()),
+            Err(LightningModuleError::AmountNotAligned(
+                Amount::from_msat(42_500),
+                Amount::from_sat(1)
+            ))
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_pending_incoming_outpoints() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (_, first) = fund_incoming(module, user_preimage());
+        let (_, second) = fund_incoming(module, user_preimage());
+        let preimage = user_preimage();
+        assert_eq!(
+            decrypt_incoming(module, &modules, preimage.clone()).await,
+            DecryptedPreimage::Some(preimage)
+        );
+
+        let pending = module
+            .pending_incoming_outpoints()
+            .into_iter()
+            .collect::<HashSet<_>>();
+        assert_eq!(pending, HashSet::from([first, second]));
+    }
+
+    #[test_log::test]
+    fn test_offer_already_funded() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let apply_output = |output: &ContractOrOfferOutput, out_idx: u64| {
+            let mut batch = DbBatch::new();
+            module
+                .apply_output(
+                    batch.transaction(),
+                    output,
+                    OutPoint {
+                        txid: sha256::Hash::hash(b"tx").into(),
+                        out_idx,
+                    },
+                )
+                .expect("output is valid");
+            module.db.apply_batch(batch).expect("DB error");
+        };
+
+        let preimage = user_preimage();
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        let registration = ContractOrOfferOutput::Offer(offer.clone());
+        apply_output(&registration, 0);
+        apply_output(
+            &ContractOrOfferOutput::Contract(ContractOutput {
+                amount: Amount::from_sat(42),
+                contract: Contract::Incoming(IncomingContract {
+                    hash: offer.hash,
+                    encrypted_preimage: offer.encrypted_preimage.clone(),
+                    decrypted_preimage: DecryptedPreimage::Pending,
+                    gateway_key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                        .x_only_public_key()
+                        .0,
+                    recipients: vec![],
+                    incoming_refund_height: None,
+                }),
+            }),
+            1,
+        );
+        assert_eq!(module.get_offer(offer.hash), None);
+
+        assert_eq!(
+            module.validate_output(&registration).map(|_| ()),
+            Err(LightningModuleError::OfferAlreadyFunded(offer.id()))
+        );
+        assert_eq!(
+            module
+                .validate_output(&ContractOrOfferOutput::OfferBatch(vec![offer.clone()]))
+                .map(|_| ()),
+            Err(LightningModuleError::OfferAlreadyFunded(offer.id()))
+        );
+    }
+
+    #[test_log::test]
+    fn test_decryption_share_order() {
+        let (_, modules) = build_modules();
+        let (incoming, _) = fund_incoming(&modules[0], user_preimage());
+        let share_ci = |member: &LightningModule| DecryptionShareCI {
+            contract_id: incoming.contract_id(),
+            peer: PeerId::from(0),
+            share: decryption_share(member, &incoming.encrypted_preimage),
+        };
+        let encoded = |ci: &DecryptionShareCI| {
+            let mut bytes = Vec::new();
+            ci.share.consensus_encode(&mut bytes).unwrap();
+            bytes
+        };
+
+        let mut forward = vec![share_ci(&modules[0]), share_ci(&modules[1])];
+        let mut backward = forward.iter().rev().cloned().collect::<Vec<_>>();
+        sort_decryption_shares(&mut forward);
+        sort_decryption_shares(&mut backward);
+
+        assert_eq!(forward, backward);
+        assert!(encoded(&forward[0]) < encoded(&forward[1]));
+    }
+
+    #[test_log::test]
+    fn test_contract_created_height() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let account = Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        });
+        let fund_at = |block_height: u32| {
+            record_block_height(module, block_height);
+            let mut batch = DbBatch::new();
+            module
+                .apply_output(
+                    batch.transaction(),
+                    &ContractOrOfferOutput::Contract(ContractOutput {
+                        amount: Amount::from_sat(42),
+                        contract: account.clone(),
+                    }),
+                    OutPoint {
+                        txid: sha256::Hash::hash(&block_height.to_be_bytes()).into(),
+                        out_idx: 0,
+                    },
+                )
+                .expect("output is valid");
+            module.db.apply_batch(batch).expect("DB error");
+        };
+
+        assert_eq!(module.contract_created_height(account.contract_id()), None);
+        fund_at(100);
+        fund_at(200);
+        assert_eq!(
+            module.contract_created_height(account.contract_id()),
+            Some(100)
+        );
+    }
+
+    #[test_log::test]
+    fn test_max_timelock_delta() {
+        let (_, modules) = build_modules_with(|cfg| cfg.max_timelock_delta = Some(1000));
+        let module = &modules[0];
+        record_block_height(module, 100);
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+        let output = |timelock| {
+            ContractOrOfferOutput::Contract(ContractOutput {
+                amount: Amount::from_sat(42),
+                contract: Contract::Outgoing(OutgoingContract {
+                    hash: sha256::Hash::hash(&[42; 32]),
+                    gateway_key: key(),
+                    timelock,
+                    user_key: key(),
+                    invoice: "not enforced yet".to_string(),
+                    invoice_amount: Amount::from_sat(40),
+                    cancelled: false,
+                }),
+            })
+        };
+
+        assert!(module.validate_output(&output(244)).is_ok());
+        assert!(module.validate_output(&output(1100)).is_ok());
+        assert_eq!(
+            module.validate_output(&output(u32::MAX)).map(|_| ()),
+            Err(LightningModuleError::TimelockTooFar(u32::MAX, 1100))
+        );
+    }
+
+    #[test_log::test]
+    fn test_recorded_block_height() {
+        // No input was ever spent, so only the recorded consensus height is known
+        let (_, modules) = build_modules_with(|cfg| cfg.max_timelock_delta = Some(1000));
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+        let output = ContractOrOfferOutput::Contract(ContractOutput {
+            amount: Amount::from_sat(42),
+            contract: Contract::Outgoing(OutgoingContract {
+                hash: sha256::Hash::hash(&[42; 32]),
+                gateway_key: key(),
+                timelock: 750_500,
+                user_key: key(),
+                invoice: "not enforced yet".to_string(),
+                invoice_amount: Amount::from_sat(40),
+                cancelled: false,
+            }),
+        });
+
+        record_block_height(module, 750_000);
+        assert!(module.validate_output(&output).is_ok());
+
+        // A reorg doesn't lower the height outputs are validated against
+        record_block_height(module, 749_000);
+        assert!(module.validate_output(&output).is_ok());
+    }
+
+    #[test_log::test]
+    fn test_offer_resubmission() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let register = |offer: &IncomingContractOffer, out_idx: u64| {
+            let mut batch = DbBatch::new();
+            module.apply_output(
+                batch.transaction(),
+                &ContractOrOfferOutput::Offer(offer.clone()),
+                OutPoint {
+                    txid: sha256::Hash::hash(b"tx").into(),
+                    out_idx,
+                },
+            )?;
+            module.db.apply_batch(batch).expect("DB error");
+            Ok::<_, LightningModuleError>(())
+        };
+
+        let preimage = user_preimage();
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        assert_eq!(register(&offer, 0), Ok(()));
+        assert_eq!(register(&offer, 1), Ok(()));
+        assert_eq!(module.get_offers(), vec![offer.clone()]);
+
+        let conflicting = IncomingContractOffer {
+            amount: Amount::from_sat(21),
+            ..offer.clone()
+        };
+        assert_eq!(
+            register(&conflicting, 2),
+            Err(LightningModuleError::OfferConflict(offer.hash))
+        );
+        let rescheduled = IncomingContractOffer {
+            not_before: Some(42),
+            ..offer.clone()
+        };
+        assert_eq!(
+            register(&rescheduled, 3),
+            Err(LightningModuleError::OfferConflict(offer.hash))
+        );
+        assert_eq!(module.get_offers(), vec![offer]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_auto_refund_invalid() {
+        for auto_refund_invalid in [false, true] {
+            let (_, modules) =
+                build_modules_with(|cfg| cfg.auto_refund_invalid = auto_refund_invalid);
+            let module = &modules[0];
+
+            // Exceeds the field size, so it's never a valid public key
+            let (incoming, _) = fund_incoming(module, Preimage([0xff; 32]));
+            agree_decryption_shares(module, &modules, &incoming);
+            end_epoch(module).await;
+
+            assert_eq!(
+                module.invalid_preimage_refund_key(incoming.contract_id()),
+                auto_refund_invalid.then_some(incoming.gateway_key)
+            );
+        }
+    }
+
+    #[test_log::test]
+    fn test_input_fee_underflow() {
+        let (_, modules) = build_modules_with(|cfg| {
+            cfg.fee_consensus.contract_input = Amount::from_sat(100);
+        });
+        let module = &modules[0];
+        let account = Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        });
+        fund_contract(module, &account);
+
+        let input = ContractInput {
+            contract_id: account.contract_id(),
+            amount: Amount::from_sat(42),
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract: None,
+        };
+        assert_eq!(
+            module.validate_input_with_source(&input, 0).map(|_| ()),
+            Err(LightningModuleError::AmountUnderflow(
+                Amount::from_sat(42),
+                Amount::from_sat(100)
+            ))
+        );
+    }
+
+    #[test_log::test]
+    fn test_stale_offers() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let register_at = |block_height: u32| {
+            record_block_height(module, block_height);
+            let preimage = user_preimage();
+            let offer = IncomingContractOffer {
+                amount: Amount::from_sat(42),
+                hash: sha256::Hash::hash(&preimage.0),
+                encrypted_preimage: EncryptedPreimage::new(
+                    preimage,
+                    &module.cfg.threshold_pub_keys.public_key(),
+                ),
+                expiry_time: None,
+                denomination_hint: None,
+                not_before: None,
+                signature: None,
+            };
+            let mut batch = DbBatch::new();
+            module
+                .apply_output(
+                    batch.transaction(),
+                    &ContractOrOfferOutput::Offer(offer.clone()),
+                    OutPoint {
+                        txid: sha256::Hash::hash(&block_height.to_be_bytes()).into(),
+                        out_idx: 0,
+                    },
+                )
+                .expect("output is valid");
+            module.db.apply_batch(batch).expect("DB error");
+            offer.id()
+        };
+
+        let old = register_at(100);
+        let recent = register_at(200);
+
+        assert_eq!(module.stale_offers(100), vec![]);
+        assert_eq!(module.stale_offers(150), vec![old]);
+        let mut all = module.stale_offers(250);
+        all.sort();
+        let mut expected = vec![old, recent];
+        expected.sort();
+        assert_eq!(all, expected);
+    }
+
+    #[test_log::test]
+    fn test_spend_attempts() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+
+        let preimage = Preimage([42; 32]);
+        let outgoing = Contract::Outgoing(OutgoingContract {
+            hash: sha256::Hash::hash(&preimage.0),
+            gateway_key: key(),
+            timelock: 42,
+            user_key: key(),
+            invoice: "not enforced yet".to_string(),
+            invoice_amount: Amount::from_sat(40),
+            cancelled: false,
+        });
+        fund_contract(module, &outgoing);
+        let validate = |contract_id: ContractId, witness: ContractWitness| {
+            let input = ContractInput {
+                contract_id,
+                amount: Amount::from_sat(42),
+                witness,
+                recipient: None,
+                change_contract: None,
+            };
+            module
+                .validate_input(&GenesisInterconnect, &(), &input)
+                .map(|_| ())
+        };
+
+        assert!(validate(outgoing.contract_id(), ContractWitness::None).is_err());
+        for _ in 0..2 {
+            assert_eq!(
+                validate(
+                    outgoing.contract_id(),
+                    ContractWitness::Preimage(Preimage([21; 32]))
+                ),
+                Err(LightningModuleError::InvalidPreimage)
+            );
+        }
+        assert_eq!(
+            validate(outgoing.contract_id(), ContractWitness::Preimage(preimage)),
+            Ok(())
+        );
+        assert_eq!(module.spend_attempts(outgoing.contract_id()), 3);
+
+        // Unknown contracts aren't tracked
+        let unknown = ContractId::from_hash(sha256::Hash::hash(b"unknown"));
+        assert!(validate(unknown, ContractWitness::None).is_err());
+        assert_eq!(module.spend_attempts(unknown), 0);
+    }
+
+    #[test_log::test]
+    fn test_contract_at_outpoint() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let contract = Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        });
+        let out_point = fund_contract(module, &contract);
+
+        assert_eq!(
+            module.contract_at_outpoint(out_point),
+            Some(contract.to_funded(out_point, module.current_epoch()))
+        );
+        assert_eq!(
+            module.contract_at_outpoint(OutPoint {
+                txid: sha256::Hash::hash(b"unknown").into(),
+                out_idx: 0,
+            }),
+            None
+        );
+    }
+
+    #[test_log::test]
+    fn test_sweep_dust() {
+        let (_, modules) = build_modules();
+        let module = &LightningModule::new(modules[0].cfg.clone(), MemDatabase::new().into())
+            .with_contract_cache(NonZeroUsize::new(16).expect("not zero"));
+        let fund_account = |amount: Amount| {
+            let contract = Contract::Account(AccountContract {
+                key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                    .x_only_public_key()
+                    .0,
+                multisig: None,
+            });
+            fund_contract(module, &contract);
+            let mut account = module.get_contract_account(contract.contract_id()).unwrap();
+            account.amount = amount;
+            module
+                .db
+                .insert_entry(&ContractKey(contract.contract_id()), &account)
+                .expect("DB error");
+            contract.contract_id()
+        };
+
+        let dust = [
+            fund_account(Amount::from_msat(3)),
+            fund_account(Amount::ZERO),
+        ];
+        let frozen = fund_account(Amount::from_msat(5));
+        module
+            .db
+            .insert_entry(&FrozenContractKey(frozen), &())
+            .expect("DB error");
+        let regular = fund_account(Amount::from_sat(1));
+
+        // Swept accounts may not be served from the cache afterwards
+        for id in dust {
+            assert!(module.get_contract_account(id).is_some());
+        }
+
+        let mut batch = DbBatch::new();
+        assert_eq!(
+            module.sweep_dust(batch.transaction(), Amount::from_msat(10)),
+            Amount::from_msat(3)
+        );
+        module.db.apply_batch(batch).expect("DB error");
+
+        for id in dust {
+            assert!(module.get_contract_account(id).is_none());
+        }
+        assert!(module.get_contract_account(frozen).is_some());
+        assert!(module.get_contract_account(regular).is_some());
+        assert_eq!(module.accumulated_fees(), Amount::from_msat(3));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_inconsistent_outcome_skipped() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let (incoming, out_point) = fund_incoming(module, preimage.clone());
+        module
+            .db
+            .insert_entry(
+                &ContractUpdateKey(out_point),
+                &OutputOutcome::Contract {
+                    id: incoming.contract_id(),
+                    outcome: ContractOutcome::Outgoing(OutgoingContractOutcome {}),
+                },
+            )
+            .expect("DB error");
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+
+        // The contract is still decrypted, only the outcome stays as it was
+        let decrypted = module
+            .get_contract_account(incoming.contract_id())
+            .and_then(|account| account.contract.as_incoming().cloned())
+            .map(|incoming| incoming.contract.decrypted_preimage);
+        assert_eq!(decrypted, Some(DecryptedPreimage::Some(preimage)));
+        assert_eq!(
+            module.output_status(out_point),
+            Some(OutputOutcome::Contract {
+                id: incoming.contract_id(),
+                outcome: ContractOutcome::Outgoing(OutgoingContractOutcome {}),
+            })
+        );
+    }
+
+    #[test_log::test]
+    fn test_current_fee_rate() {
+        struct FeeRateInterconnect(Option<u64>);
+
+        #[async_trait::async_trait]
+        impl ModuleInterconect for FeeRateInterconnect {
+            async fn call(
+                &self,
+                module: &'static str,
+                path: String,
+                _data: serde_json::Value,
+            ) -> Result<serde_json::Value, ApiError> {
+                assert_eq!(module, "wallet");
+                assert_eq!(path, "/fee_rate");
+                Ok(serde_json::to_value(self.0).expect("encoding error"))
+            }
+        }
+
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        assert_eq!(
+            module.current_fee_rate(&FeeRateInterconnect(Some(1000))),
+            Some(1000)
+        );
+        assert_eq!(module.current_fee_rate(&FeeRateInterconnect(None)), None);
+    }
+
+    #[test_log::test]
+    fn test_incoming_refund_height() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let gateway_key = KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+            .x_only_public_key()
+            .0;
+        let incoming_with_refund_height = |height| IncomingContract {
+            hash: module.cfg.preimage_hash.hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage.clone(),
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            decrypted_preimage: DecryptedPreimage::Pending,
+            gateway_key,
+            recipients: vec![],
+            incoming_refund_height: Some(height),
+        };
+
+        // A refund height that is already reached would let the gateway reclaim the funds right
+        // away while still learning the preimage
+        record_block_height(module, 10);
+        let output = |height| {
+            ContractOrOfferOutput::Contract(ContractOutput {
+                amount: Amount::from_sat(42),
+                contract: Contract::Incoming(incoming_with_refund_height(height)),
+            })
+        };
+        assert_eq!(
+            module.validate_output(&output(10)).map(|_| ()),
+            Err(LightningModuleError::RefundHeightTooEarly(
+                10,
+                10 + MIN_INCOMING_REFUND_DELTA
+            ))
+        );
+        assert_eq!(
+            module
+                .validate_output(&output(10 + MIN_INCOMING_REFUND_DELTA))
+                .map(|_| ()),
+            Err(LightningModuleError::NoOffer(
+                incoming_with_refund_height(10).hash
+            ))
+        );
+
+        let incoming = Contract::Incoming(incoming_with_refund_height(10));
+        fund_contract(module, &incoming);
+        let input = ContractInput {
+            contract_id: incoming.contract_id(),
+            amount: Amount::from_sat(42),
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract: None,
+        };
+
+        assert_eq!(
+            module
+                .validate_input_with_source(&input, 9)
+                .map(|(_, source)| source),
+            Err(LightningModuleError::ContractNotReady)
+        );
+
+        let (meta, source) = module
+            .validate_input_with_source(&input, 10)
+            .expect("refund height reached");
+        assert_eq!(source, ContractInputSource::IncomingRefund);
+        assert_eq!(meta.puk_keys.collect::<Vec<_>>(), vec![gateway_key]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_pending_payment_hashes() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (first, _) = fund_incoming(module, user_preimage());
+        let (second, _) = fund_incoming(module, user_preimage());
+        let preimage = user_preimage();
+        assert_eq!(
+            decrypt_incoming(module, &modules, preimage.clone()).await,
+            DecryptedPreimage::Some(preimage)
+        );
+
+        let pending = module
+            .pending_payment_hashes()
+            .into_iter()
+            .collect::<HashSet<_>>();
+        assert_eq!(pending, HashSet::from([first.hash, second.hash]));
+    }
+
+    #[test_log::test]
+    fn test_apply_output_effect() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let apply_output = |output: &ContractOrOfferOutput, out_idx: u64| {
+            let mut batch = DbBatch::new();
+            let (_, effect) = module
+                .apply_output_with_effect(
+                    batch.transaction(),
+                    output,
+                    OutPoint {
+                        txid: sha256::Hash::hash(b"tx").into(),
+                        out_idx,
+                    },
+                )
+                .expect("output is valid");
+            module.db.apply_batch(batch).expect("DB error");
+            effect
+        };
+
+        let preimage = user_preimage();
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        assert_eq!(
+            apply_output(&ContractOrOfferOutput::Offer(offer.clone()), 0),
+            ApplyOutputEffect::OffersRegistered(vec![offer.id()])
+        );
+
+        let account = ContractOrOfferOutput::Contract(ContractOutput {
+            amount: Amount::from_sat(42),
+            contract: Contract::Account(AccountContract {
+                key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                    .x_only_public_key()
+                    .0,
+                multisig: None,
+            }),
+        });
+        let contract_id = match &account {
+            ContractOrOfferOutput::Contract(output) => output.contract.contract_id(),
+            _ => unreachable!("created a contract output"),
+        };
+        assert_eq!(
+            apply_output(&account, 1),
+            ApplyOutputEffect::ContractFunded(contract_id)
+        );
+        assert_eq!(
+            apply_output(&account, 2),
+            ApplyOutputEffect::ContractToppedUp(contract_id)
+        );
+        assert_eq!(
+            module
+                .get_contract_account(contract_id)
+                .map(|account| account.amount),
+            Some(Amount::from_sat(84))
+        );
+    }
+
+    #[test_log::test]
+    fn test_preimage_mismatch() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        module
+            .db
+            .insert_entry(&OfferKey(offer.hash), &offer)
+            .expect("DB error");
+
+        let funding = |encrypted_preimage: EncryptedPreimage| {
+            ContractOrOfferOutput::Contract(ContractOutput {
+                amount: Amount::from_sat(42),
+                contract: Contract::Incoming(IncomingContract {
+                    hash: offer.hash,
+                    encrypted_preimage,
+                    decrypted_preimage: DecryptedPreimage::Pending,
+                    gateway_key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                        .x_only_public_key()
+                        .0,
+                    recipients: vec![],
+                    incoming_refund_height: None,
+                }),
+            })
+        };
+
+        let other_ciphertext =
+            EncryptedPreimage::new(user_preimage(), &module.cfg.threshold_pub_keys.public_key());
+        assert_eq!(
+            module
+                .validate_output(&funding(other_ciphertext))
+                .map(|_| ()),
+            Err(LightningModuleError::PreimageMismatch(offer.hash))
+        );
+        assert_eq!(
+            module
+                .validate_output(&funding(offer.encrypted_preimage.clone()))
+                .map(|_| ()),
+            Ok(())
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_contract_counts() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+
+        assert_eq!(module.contract_counts(), ContractCounts::default());
+
+        fund_contract(
+            module,
+            &Contract::Account(AccountContract {
+                key: key(),
+                multisig: None,
+            }),
+        );
+        fund_contract(
+            module,
+            &Contract::Outgoing(OutgoingContract {
+                hash: sha256::Hash::hash(b"preimage"),
+                gateway_key: key(),
+                timelock: 42,
+                user_key: key(),
+                invoice: "not enforced yet".to_string(),
+                invoice_amount: Amount::from_sat(40),
+                cancelled: false,
+            }),
+        );
+        fund_incoming(module, user_preimage());
+        decrypt_incoming(module, &modules, user_preimage()).await;
+        decrypt_incoming(module, &modules, Preimage([0xff; 32])).await;
+
+        assert_eq!(
+            module.contract_counts(),
+            ContractCounts {
+                account: 1,
+                outgoing: 1,
+                incoming: 3,
+            }
+        );
+    }
+
+    #[test_log::test]
+    fn test_offer_and_contract() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        let incoming = |hash: sha256::Hash| ContractOutput {
+            amount: Amount::from_sat(42),
+            contract: Contract::Incoming(IncomingContract {
+                hash,
+                encrypted_preimage: offer.encrypted_preimage.clone(),
+                decrypted_preimage: DecryptedPreimage::Pending,
+                gateway_key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                    .x_only_public_key()
+                    .0,
+                recipients: vec![],
+                incoming_refund_height: None,
+            }),
+        };
+
+        let mismatched = ContractOrOfferOutput::OfferAndContract(
+            offer.clone(),
+            incoming(sha256::Hash::hash(b"other")),
+        );
+        assert_eq!(
+            module.validate_output(&mismatched).map(|_| ()),
+            Err(LightningModuleError::BundledOfferMismatch(offer.hash))
+        );
+
+        let contract = incoming(offer.hash);
+        let contract_id = contract.contract.contract_id();
+        let mut batch = DbBatch::new();
+        let (_, effect) = module
+            .apply_output_with_effect(
+                batch.transaction(),
+                &ContractOrOfferOutput::OfferAndContract(offer.clone(), contract),
+                OutPoint {
+                    txid: sha256::Hash::hash(b"tx").into(),
+                    out_idx: 0,
+                },
+            )
+            .expect("output is valid");
+        module.db.apply_batch(batch).expect("DB error");
+
+        assert_eq!(effect, ApplyOutputEffect::DecryptionStarted(contract_id));
+        assert_eq!(module.get_offer(offer.hash), None);
+        assert_eq!(module.offer_funding_contract(offer.id()), Some(contract_id));
+        assert_eq!(
+            module
+                .validate_output(&ContractOrOfferOutput::Offer(offer.clone()))
+                .map(|_| ()),
+            Err(LightningModuleError::OfferAlreadyFunded(offer.id()))
+        );
+        assert_eq!(
+            module
+                .get_contract_account(contract_id)
+                .map(|account| account.amount),
+            Some(Amount::from_sat(42))
+        );
+        assert!(module.export_decryption_share(contract_id).is_some());
+    }
+
+    #[test_log::test]
+    fn test_balance_sink() {
+        let (_, modules) = build_modules();
+        let events = Arc::new(Mutex::new(vec![]));
+        let sink_events = events.clone();
+        let module = modules
+            .into_iter()
+            .next()
+            .unwrap()
+            .with_balance_sink(Box::new(move |event| {
+                sink_events.lock().unwrap().push(event)
+            }));
+
+        let key = KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng);
+        let contract = Contract::Account(AccountContract {
+            key: key.x_only_public_key().0,
+            multisig: None,
+        });
+        let mut batch = DbBatch::new();
+        module
+            .apply_output(
+                batch.transaction(),
+                &ContractOrOfferOutput::Contract(ContractOutput {
+                    amount: Amount::from_sat(42),
+                    contract: contract.clone(),
+                }),
+                OutPoint {
+                    txid: sha256::Hash::hash(b"tx").into(),
+                    out_idx: 0,
+                },
+            )
+            .expect("output is valid");
+        module.db.apply_batch(batch).expect("DB error");
+
+        apply_input(
+            &module,
+            &ContractInput {
+                contract_id: contract.contract_id(),
+                amount: Amount::from_sat(12),
+                witness: ContractWitness::None,
+                recipient: None,
+                change_contract: None,
+            },
+        )
+        .expect("input is valid");
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ContractBalanceChanged {
+                    id: contract.contract_id(),
+                    old: Amount::ZERO,
+                    new: Amount::from_sat(42),
+                },
+                ContractBalanceChanged {
+                    id: contract.contract_id(),
+                    old: Amount::from_sat(42),
+                    new: Amount::from_sat(30),
+                },
+            ]
+        );
+    }
+
+    #[test_log::test]
+    fn test_validate_shares() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        let share =
+            |member: usize| decryption_share(&modules[member], &incoming.encrypted_preimage);
+        let unknown_contract = ContractId::from_hash(sha256::Hash::hash(b"unknown"));
+
+        assert_eq!(
+            module.validate_shares(&[
+                (PeerId::from(0), incoming.contract_id(), share(0)),
+                (PeerId::from(1), incoming.contract_id(), share(1)),
+                // Attributed to the wrong peer
+                (PeerId::from(3), incoming.contract_id(), share(2)),
+                (PeerId::from(2), unknown_contract, share(2)),
+            ]),
+            vec![true, true, false, false]
+        );
+    }
+
+    #[test_log::test]
+    fn test_offer_deleted_last() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        module
+            .db
+            .insert_entry(&OfferKey(offer.hash), &offer)
+            .expect("DB error");
+        let contract = Contract::Incoming(IncomingContract {
+            hash: offer.hash,
+            encrypted_preimage: offer.encrypted_preimage.clone(),
+            decrypted_preimage: DecryptedPreimage::Pending,
+            gateway_key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            recipients: vec![],
+            incoming_refund_height: None,
+        });
+
+        let mut batch = DbBatch::new();
+        module
+            .apply_output(
+                batch.transaction(),
+                &ContractOrOfferOutput::Contract(ContractOutput {
+                    amount: Amount::from_sat(42),
+                    contract: contract.clone(),
+                }),
+                OutPoint {
+                    txid: sha256::Hash::hash(b"tx").into(),
+                    out_idx: 0,
+                },
+            )
+            .expect("output is valid");
+
+        let keys = Vec::<BatchItem>::from(batch)
+            .into_iter()
+            .map(|item| match item {
+                BatchItem::InsertNewElement(element) | BatchItem::InsertElement(element) => {
+                    element.key.to_bytes()
+                }
+                BatchItem::DeleteElement(key) | BatchItem::MaybeDeleteElement(key) => {
+                    key.to_bytes()
+                }
+            })
+            .collect::<Vec<_>>();
+        let position = |key: Vec<u8>| keys.iter().position(|item| *item == key);
+        let contract_insert =
+            position(ContractKey(contract.contract_id()).to_bytes()).expect("contract is inserted");
+        let offer_delete = position(OfferKey(offer.hash).to_bytes()).expect("offer is deleted");
+        assert!(contract_insert < offer_delete);
+        assert_eq!(offer_delete, keys.len() - 1);
+    }
+
+    #[test]
+    fn test_signed_offers() {
+        let (_, modules) = build_modules_with(|cfg| cfg.require_signed_offers = true);
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        let sign = |offer: &IncomingContractOffer, key: &KeyPair| {
+            let signature =
+                secp256k1::global::SECP256K1.sign_schnorr(&offer.signing_message().into(), key);
+            IncomingContractOffer {
+                signature: Some(OfferSignature {
+                    receiver_key: key.x_only_public_key().0,
+                    signature,
+                }),
+                ..offer.clone()
+            }
+        };
+        let receiver_key = KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng);
+
+        assert_eq!(
+            module.validate_offer(module.db.read_only(), &offer),
+            Err(LightningModuleError::UnsignedOffer)
+        );
+
+        let signed = sign(&offer, &receiver_key);
+        assert!(module
+            .validate_offer(module.db.read_only(), &signed)
+            .is_ok());
+
+        // A signature doesn't carry over to an offer with different terms
+        let tampered = IncomingContractOffer {
+            amount: Amount::from_sat(43),
+            ..signed.clone()
+        };
+        assert_eq!(
+            module.validate_offer(module.db.read_only(), &tampered),
+            Err(LightningModuleError::InvalidOfferSignature)
+        );
+
+        let (_, lenient_modules) = build_modules();
+        let lenient = &lenient_modules[0];
+        assert!(lenient
+            .validate_offer(lenient.db.read_only(), &offer)
+            .is_ok());
+        assert_eq!(
+            lenient.validate_offer(lenient.db.read_only(), &tampered),
+            Err(LightningModuleError::InvalidOfferSignature)
+        );
+    }
+
+    #[test]
+    fn test_incoming_contract_by_hash() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let hash = module.cfg.preimage_hash.hash(&preimage.0);
+        assert_eq!(module.incoming_contract_by_hash(&hash), None);
+
+        let (contract, _) = fund_incoming(module, preimage);
+        assert_eq!(
+            module.incoming_contract_by_hash(&hash),
+            Some(contract.contract_id())
+        );
+        assert_eq!(
+            module
+                .db
+                .get_value(&PaymentHashIndexKey(hash))
+                .expect("DB error"),
+            Some(contract.contract_id())
+        );
+    }
+
+    #[test]
+    fn test_share_validity_independent_of_local_state() {
+        let (_, modules) = build_modules();
+        let (foreign_sks, _) = build_modules();
+
+        // Same consensus config, but different secret key shares and databases
+        let guardians = modules[..2]
+            .iter()
+            .map(|member| LightningModule::new(member.cfg.clone(), MemDatabase::new().into()))
+            .collect::<Vec<_>>();
+        let (incoming, _) = fund_incoming(&guardians[0], user_preimage());
+        let message = &incoming.encrypted_preimage;
+
+        let mut cases: Vec<(PeerId, PreimageDecryptionShare, bool)> = modules
+            .iter()
+            .enumerate()
+            .map(|(peer, member)| {
+                (
+                    PeerId::from(peer as u16),
+                    decryption_share(member, message),
+                    true,
+                )
+            })
+            .collect();
+        cases.push((
+            PeerId::from(1),
+            decryption_share(&modules[0], message),
+            false,
+        ));
+        cases.push((
+            PeerId::from(PEERS as u16),
+            PreimageDecryptionShare(
+                foreign_sks
+                    .secret_key_share(PEERS)
+                    .decrypt_share(&message.0)
+                    .expect("valid ciphertext"),
+            ),
+            false,
+        ));
+        cases.push((
+            PeerId::from(0),
+            PreimageDecryptionShare(
+                foreign_sks
+                    .secret_key_share(0)
+                    .decrypt_share(&message.0)
+                    .expect("valid ciphertext"),
+            ),
+            false,
+        ));
+
+        for (peer, share, expected) in cases {
+            let decisions = guardians
+                .iter()
+                .map(|guardian| guardian.validate_decryption_share(peer, &share, message))
+                .collect::<Vec<_>>();
+            assert_eq!(decisions, vec![expected; 2], "share of {}", peer);
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_rejected_share_stops_being_proposed() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        let contract_id = incoming.contract_id();
+        let proposes_share = |items: Vec<LightningConsensusItem>| {
+            items.iter().any(|item| {
+                matches!(
+                    item,
+                    LightningConsensusItem::DecryptionShare(DecryptionShareCI { contract_id: id, .. })
+                        if *id == contract_id
+                )
+            })
+        };
+
+        // Our share is never agreed on, as if other peers rejected it
+        for attempt in 1..MAX_SHARE_PROPOSALS {
+            assert!(proposes_share(module.consensus_proposal(OsRng).await));
+            end_epoch(module).await;
+            assert_eq!(
+                module
+                    .db
+                    .get_value(&ShareProposalAttemptKey(contract_id))
+                    .expect("DB error"),
+                Some(attempt)
+            );
+        }
+        assert!(proposes_share(module.consensus_proposal(OsRng).await));
+        end_epoch(module).await;
+
+        assert!(!proposes_share(module.consensus_proposal(OsRng).await));
+        assert_eq!(
+            module
+                .db
+                .get_value(&FailedShareKey(contract_id))
+                .expect("DB error"),
+            Some(decryption_share(module, &incoming.encrypted_preimage))
+        );
+        assert_eq!(
+            module
+                .db
+                .get_value(&ShareProposalAttemptKey(contract_id))
+                .expect("DB error"),
+            None
+        );
+        assert_eq!(
+            module.export_decryption_share(contract_id),
+            Some((
+                PeerId::from(0),
+                decryption_share(module, &incoming.encrypted_preimage)
+            ))
+        );
+
+        // An agreed share doesn't count as failed attempt
+        let (agreed, _) = fund_incoming(module, user_preimage());
+        module
+            .db
+            .insert_entry(
+                &AgreedDecryptionShareKey(agreed.contract_id(), PeerId::from(0)),
+                &decryption_share(module, &agreed.encrypted_preimage),
+            )
+            .expect("DB error");
+        end_epoch(module).await;
+        assert_eq!(
+            module
+                .db
+                .get_value(&ShareProposalAttemptKey(agreed.contract_id()))
+                .expect("DB error"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_multisig_account() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let keys = (0..3)
+            .map(|_| {
+                KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                    .x_only_public_key()
+                    .0
+            })
+            .collect::<Vec<_>>();
+        let stranger = KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+            .x_only_public_key()
+            .0;
+        let account = AccountContract {
+            key: keys[0],
+            multisig: Some(AccountMultisig {
+                cosigners: keys[1..].to_vec(),
+                threshold: 2,
+            }),
+        };
+        assert_eq!(
+            Contract::Account(account.clone()).validate_structure(),
+            Ok(())
+        );
+        fund_contract(module, &Contract::Account(account.clone()));
+
+        let spend = |witness: ContractWitness| {
+            let input = ContractInput {
+                contract_id: account.contract_id(),
+                amount: Amount::from_sat(42),
+                witness,
+                recipient: None,
+                change_contract: None,
+            };
+            module
+                .validate_input_at(&input, 0)
+                .map(|meta| meta.puk_keys.collect::<BTreeSet<_>>())
+        };
+
+        // Any two of the three keys may spend, and exactly these have to sign
+        for signers in [vec![keys[0], keys[1]], vec![keys[1], keys[2]]] {
+            assert_eq!(
+                spend(ContractWitness::Signers(signers.clone())),
+                Ok(signers.into_iter().collect())
+            );
+        }
+        assert_eq!(
+            spend(ContractWitness::Signers(keys.clone())),
+            Ok(keys.iter().copied().collect())
+        );
+
+        assert_eq!(
+            spend(ContractWitness::None),
+            Err(LightningModuleError::MissingSigners)
+        );
+        assert_eq!(
+            spend(ContractWitness::Signers(vec![keys[2], keys[2]])),
+            Err(LightningModuleError::NotEnoughSigners(1, 2))
+        );
+        assert_eq!(
+            spend(ContractWitness::Signers(vec![keys[0], stranger])),
+            Err(LightningModuleError::UnknownSigner(stranger))
+        );
+
+        for (cosigners, threshold) in [
+            (keys[1..].to_vec(), 0),
+            (keys[1..].to_vec(), 4),
+            (vec![keys[1], keys[1]], 2),
+        ] {
+            let invalid = AccountContract {
+                key: keys[0],
+                multisig: Some(AccountMultisig {
+                    cosigners,
+                    threshold,
+                }),
+            };
+            assert_eq!(
+                Contract::Account(invalid).validate_structure(),
+                Err(LightningModuleError::InvalidAccountMultisig)
+            );
+        }
+    }
+
+    #[test_log::test]
+    fn test_malformed_share_preview() {
+        let (sks, mut modules) = build_modules();
+
+        let preimage = user_preimage();
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: sha256::Hash::hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(preimage, &sks.public_keys().public_key()),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        assert!(offer.encrypted_preimage.0.verify());
+        assert_eq!(
+            modules[0].preview_decryption_share(&offer.encrypted_preimage),
+            Some(decryption_share(&modules[0], &offer.encrypted_preimage))
+        );
+
+        // A secret key share not matching our public key share yields malformed shares for any
+        // ciphertext, even verified ones
+        let module = &mut modules[0];
+        module.cfg.threshold_sec_key = SerdeSecret(sks.secret_key_share(1));
+        assert_eq!(
+            module.preview_decryption_share(&offer.encrypted_preimage),
+            None
+        );
+
+        // The offer is still registered
+        let mut batch = DbBatch::new();
+        module
+            .apply_output(
+                batch.transaction(),
+                &ContractOrOfferOutput::Offer(offer.clone()),
+                OutPoint {
+                    txid: sha256::Hash::hash(b"tx").into(),
+                    out_idx: 0,
+                },
+            )
+            .expect("offer is valid");
+        module.db.apply_batch(batch).expect("DB error");
+        assert_eq!(module.get_offer(offer.hash), Some(offer));
+    }
+
+    #[test_log::test]
+    fn test_change_contract() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        let key = || {
+            KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0
+        };
+
+        let account = Contract::Account(AccountContract {
+            key: key(),
+            multisig: None,
+        });
+        fund_contract(module, &account);
+        let change_account = AccountContract {
+            key: key(),
+            multisig: None,
+        };
+        let change_contract = Contract::Account(change_account.clone());
+        let spend = |change_contract: Option<Contract>| ContractInput {
+            contract_id: account.contract_id(),
+            amount: Amount::from_sat(10),
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract,
+        };
+
+        // Change can't go back into the spent contract or into other contract types
+        let outgoing = Contract::Outgoing(OutgoingContract {
+            hash: sha256::Hash::hash(b"preimage"),
+            gateway_key: key(),
+            timelock: 100,
+            user_key: key(),
+            invoice: String::new(),
+            invoice_amount: Amount::ZERO,
+            cancelled: false,
+        });
+        for invalid in [account.clone(), outgoing] {
+            assert_eq!(
+                module
+                    .validate_input_at(&spend(Some(invalid)), 0)
+                    .map(|_| ()),
+                Err(LightningModuleError::InvalidChangeContract)
+            );
+        }
+
+        apply_input(module, &spend(Some(change_contract.clone()))).expect("input is valid");
+        assert_eq!(module.get_contract_account(account.contract_id()), None);
+        assert_eq!(
+            module.get_contract_account(change_contract.contract_id()),
+            Some(ContractAccount {
+                amount: Amount::from_sat(32),
+                contract: FundedContract::Account(change_account),
+            })
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_decryption_backlog() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+        assert_eq!(module.decryption_backlog(), 0);
+
+        fund_contract(
+            module,
+            &Contract::Account(AccountContract {
+                key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                    .x_only_public_key()
+                    .0,
+                multisig: None,
+            }),
+        );
+        fund_incoming(module, user_preimage());
+        assert_eq!(module.decryption_backlog(), 1);
+
+        // Contracts leave the backlog once decrypted
+        assert!(matches!(
+            decrypt_incoming(module, &modules, user_preimage()).await,
+            DecryptedPreimage::Some(_)
+        ));
+        assert_eq!(module.decryption_backlog(), 1);
+    }
+
+    /// Builds modules that also hold shares of the fallback key set `fallback_sks`
+    fn build_fallback_modules(fallback_sks: &SecretKeySet) -> Vec<LightningModule> {
+        let (_, modules) = build_modules_with(|cfg| {
+            let our_idx = (0..PEERS)
+                .find(|idx| {
+                    cfg.threshold_pub_keys.public_key_share(*idx)
+                        == cfg.threshold_sec_key.public_key_share()
+                })
+                .expect("our key share is part of the key set");
+            cfg.fallback_pub_keys = Some(fallback_sks.public_keys());
+            cfg.fallback_sec_key = Some(SerdeSecret(fallback_sks.secret_key_share(our_idx)));
+        });
+        modules
+    }
+
+    /// Funds an incoming contract whose preimage is encrypted to the fallback key set and stores
+    /// the fallback shares of all `modules` as agreed in `module`
+    fn fund_fallback_incoming(
+        module: &LightningModule,
+        modules: &[LightningModule],
+        fallback_sks: &SecretKeySet,
+        preimage: Preimage,
+    ) -> IncomingContract {
+        let gateway_key = KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng);
+        let incoming = IncomingContract {
+            hash: module.cfg.preimage_hash.hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &fallback_sks.public_keys().public_key(),
+            ),
+            decrypted_preimage: DecryptedPreimage::Pending,
+            gateway_key: gateway_key.x_only_public_key().0,
+            recipients: vec![],
+            incoming_refund_height: None,
+        };
+        fund_contract(module, &Contract::Incoming(incoming.clone()));
+        for (peer, member) in modules.iter().enumerate() {
+            let share = member
+                .cfg
+                .fallback_sec_key
+                .as_ref()
+                .expect("fallback key set is configured")
+                .decrypt_share(&incoming.encrypted_preimage.0)
+                .expect("valid ciphertext");
+            module
+                .db
+                .insert_entry(
+                    &AgreedFallbackShareKey(incoming.contract_id(), PeerId::from(peer as u16)),
+                    &PreimageDecryptionShare(share),
+                )
+                .expect("DB error");
+        }
+        incoming
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_fallback_decryption() {
+        let fallback_sks = SecretKeySet::random(THRESHOLD - 1, &mut OsRng);
+        let modules = build_fallback_modules(&fallback_sks);
+        let module = &modules[0];
+
+        // The preimage is encrypted to the fallback key set, so the primary shares only decrypt it
+        // to garbage
+        let preimage = user_preimage();
+        let incoming = fund_fallback_incoming(module, &modules, &fallback_sks, preimage.clone());
+        agree_decryption_shares(module, &modules, &incoming);
+
+        end_epoch(module).await;
+
+        let decrypted = module
+            .get_contract_account(incoming.contract_id())
+            .and_then(|account| account.contract.as_incoming().cloned())
+            .map(|incoming| incoming.contract.decrypted_preimage);
+        assert_eq!(decrypted, Some(DecryptedPreimage::Some(preimage)));
+        assert_eq!(
+            module
+                .db
+                .find_by_prefix(&AgreedFallbackShareContractPrefix(incoming.contract_id()))
+                .count(),
+            0
+        );
+    }
+
+    #[test_log::test]
+    fn test_fallback_decryption_after_primary_error() {
+        let fallback_sks = SecretKeySet::random(THRESHOLD - 1, &mut OsRng);
+        let modules = build_fallback_modules(&fallback_sks);
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let incoming = fund_fallback_incoming(module, &modules, &fallback_sks, preimage.clone());
+        agree_decryption_shares(module, &modules, &incoming);
+
+        // Too few valid primary shares, so the primary key set fails to decrypt at all
+        let valid_shares = modules
+            .iter()
+            .take(THRESHOLD - 1)
+            .enumerate()
+            .map(|(peer, member)| {
+                (
+                    PeerId::from(peer as u16),
+                    decryption_share(member, &incoming.encrypted_preimage),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let mut batch = DbBatch::new();
+        let mut batch_tx = batch.transaction();
+        assert_eq!(
+            module.decrypt_preimage(
+                incoming.contract_id(),
+                peers().into_iter().collect(),
+                &valid_shares,
+                &mut batch_tx,
+            ),
+            Some(DecryptedPreimage::Some(preimage))
+        );
+        batch_tx.commit();
+        module.db.apply_batch(batch).expect("DB error");
+
+        assert_eq!(
+            module
+                .db
+                .find_by_prefix(&AgreedFallbackShareContractPrefix(incoming.contract_id()))
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_contracts_funded_between() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let mut funded = (0..5)
+            .map(|_| {
+                let contract = Contract::Account(AccountContract {
+                    key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                        .x_only_public_key()
+                        .0,
+                    multisig: None,
+                });
+                (fund_contract(module, &contract), contract.contract_id())
+            })
+            .collect::<Vec<_>>();
+        funded.sort();
+
+        assert_eq!(
+            module.contracts_funded_between(funded[1].0, funded[3].0),
+            funded[1..=3].to_vec()
+        );
+        assert_eq!(
+            module.contracts_funded_between(funded[0].0, funded[4].0),
+            funded
+        );
+        assert!(module
+            .contracts_funded_between(funded[3].0, funded[1].0)
+            .is_empty());
+    }
+
+    #[test_log::test]
+    fn test_max_inputs_per_tx() {
+        let (_, modules) = build_modules_with(|cfg| cfg.max_inputs_per_tx = Some(2));
+        let module = &modules[0];
+
+        let inputs = (0..3)
+            .map(|_| {
+                let account = Contract::Account(AccountContract {
+                    key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                        .x_only_public_key()
+                        .0,
+                    multisig: None,
+                });
+                fund_contract(module, &account);
+                ContractInput {
+                    contract_id: account.contract_id(),
+                    amount: Amount::from_sat(42),
+                    witness: ContractWitness::None,
+                    recipient: None,
+                    change_contract: None,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(module.validate_inputs(inputs[..2].iter()), Ok(()));
+        assert_eq!(
+            module.validate_inputs(inputs.iter()),
+            Err(LightningModuleError::TooManyInputs(3, 2))
+        );
+
+        // Without a cap any number of inputs is fine
+        let (_, uncapped_modules) = build_modules();
+        assert_eq!(uncapped_modules[0].validate_inputs(inputs.iter()), Ok(()));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_share_not_proposed_after_agreement() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let (incoming, _) = fund_incoming(module, user_preimage());
+        let contract_id = incoming.contract_id();
+        let proposes_share = |items: Vec<LightningConsensusItem>| {
+            items.iter().any(|item| {
+                matches!(
+                    item,
+                    LightningConsensusItem::DecryptionShare(DecryptionShareCI { contract_id: id, .. })
+                        if *id == contract_id
+                )
+            })
+        };
+        let agree_share = |peer: usize, member: &LightningModule| {
+            module
+                .db
+                .insert_entry(
+                    &AgreedDecryptionShareKey(contract_id, PeerId::from(peer as u16)),
+                    &decryption_share(member, &incoming.encrypted_preimage),
+                )
+                .expect("DB error");
+        };
+
+        // Below the threshold our share is still needed
+        agree_share(1, &modules[1]);
+        agree_share(2, &modules[2]);
+        assert!(proposes_share(module.consensus_proposal(OsRng).await));
+
+        // An invalid share doesn't count towards the threshold
+        agree_share(3, &modules[2]);
+        assert!(proposes_share(module.consensus_proposal(OsRng).await));
+
+        agree_share(3, &modules[3]);
+        assert!(!proposes_share(module.consensus_proposal(OsRng).await));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_output_status_detailed() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let unknown = OutPoint {
+            txid: sha256::Hash::hash(b"unknown").into(),
+            out_idx: 0,
+        };
+        assert_eq!(
+            module.output_status_detailed(unknown),
+            OutputStatus::Unknown
+        );
+
+        let preimage = user_preimage();
+        let (incoming, out_point) = fund_incoming(module, preimage.clone());
+        assert_eq!(
+            module.output_status_detailed(out_point),
+            OutputStatus::Pending(OutputOutcome::Contract {
+                id: incoming.contract_id(),
+                outcome: ContractOutcome::Incoming(DecryptedPreimage::Pending),
+            })
+        );
+
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+        assert_eq!(
+            module.output_status_detailed(out_point),
+            OutputStatus::Final(OutputOutcome::Contract {
+                id: incoming.contract_id(),
+                outcome: ContractOutcome::Incoming(DecryptedPreimage::Some(preimage)),
+            })
+        );
+
+        // The decryption isn't retried, so an invalid preimage is final as well
+        let (incoming, out_point) = fund_incoming(module, Preimage([0xff; 32]));
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+        assert_eq!(
+            module.output_status_detailed(out_point),
+            OutputStatus::Final(OutputOutcome::Contract {
+                id: incoming.contract_id(),
+                outcome: ContractOutcome::Incoming(DecryptedPreimage::Invalid),
+            })
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_offer_keyed_by_configured_hash() {
+        let (_, modules) = build_modules_with(|cfg| cfg.preimage_hash = PreimageHash::Sha256d);
+        let module = &modules[0];
+        let apply = |output: &ContractOrOfferOutput, out_idx| {
+            module.validate_output(output).expect("output is valid");
+            let mut batch = DbBatch::new();
+            module
+                .apply_output(
+                    batch.transaction(),
+                    output,
+                    OutPoint {
+                        txid: sha256::Hash::hash(b"tx").into(),
+                        out_idx,
+                    },
+                )
+                .expect("output is valid");
+            module.db.apply_batch(batch).expect("DB error");
+        };
+
+        let preimage = user_preimage();
+        let payment_hash = module.payment_hash(&preimage.0);
+        assert_ne!(payment_hash, sha256::Hash::hash(&preimage.0));
+        let offer = IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: payment_hash,
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage.clone(),
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        apply(&ContractOrOfferOutput::Offer(offer.clone()), 0);
+        assert_eq!(module.get_offer(payment_hash), Some(offer.clone()));
+
+        let incoming = IncomingContract {
+            hash: payment_hash,
+            encrypted_preimage: offer.encrypted_preimage.clone(),
+            decrypted_preimage: DecryptedPreimage::Pending,
+            gateway_key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            recipients: vec![],
+            incoming_refund_height: None,
+        };
+        apply(
+            &ContractOrOfferOutput::Contract(ContractOutput {
+                amount: Amount::from_sat(42),
+                contract: Contract::Incoming(incoming.clone()),
+            }),
+            1,
+        );
+        assert_eq!(
+            module.offer_funding_contract(offer.id()),
+            Some(incoming.contract_id())
+        );
+
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+        let decrypted = module
+            .get_contract_account(incoming.contract_id())
+            .and_then(|account| account.contract.as_incoming().cloned())
+            .map(|incoming| incoming.contract.decrypted_preimage);
+        assert_eq!(decrypted, Some(DecryptedPreimage::Some(preimage)));
+    }
+
+    #[test_log::test]
+    fn test_estimate_batch_bytes() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let offer = |preimage: Preimage| IncomingContractOffer {
+            amount: Amount::from_sat(42),
+            hash: module.payment_hash(&preimage.0),
+            encrypted_preimage: EncryptedPreimage::new(
+                preimage,
+                &module.cfg.threshold_pub_keys.public_key(),
+            ),
+            expiry_time: None,
+            denomination_hint: None,
+            not_before: None,
+            signature: None,
+        };
+        let funded_offer = offer(Preimage([1; 32]));
+        let incoming = ContractOutput {
+            amount: Amount::from_sat(42),
+            contract: Contract::Incoming(IncomingContract {
+                hash: funded_offer.hash,
+                encrypted_preimage: funded_offer.encrypted_preimage.clone(),
+                decrypted_preimage: DecryptedPreimage::Pending,
+                gateway_key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                    .x_only_public_key()
+                    .0,
+                recipients: vec![],
+                incoming_refund_height: None,
+            }),
+        };
+        let outputs = vec![
+            ContractOrOfferOutput::OfferBatch(vec![
+                offer(Preimage([2; 32])),
+                offer(Preimage([3; 32])),
+            ]),
+            ContractOrOfferOutput::OfferAndContract(funded_offer, incoming),
+        ];
+
+        let mut batch = DbBatch::new();
+        for (out_idx, output) in outputs.iter().enumerate() {
+            module
+                .apply_output(
+                    batch.transaction(),
+                    output,
+                    OutPoint {
+                        txid: sha256::Hash::hash(b"tx").into(),
+                        out_idx: out_idx as u64,
+                    },
+                )
+                .expect("output is valid");
+        }
+        let written_bytes = Vec::<BatchItem>::from(batch)
+            .into_iter()
+            .map(|item| match item {
+                BatchItem::InsertNewElement(element) | BatchItem::InsertElement(element) => {
+                    element.key.to_bytes().len()
+                        + fedimint_api::db::SerializableDatabaseValue::to_bytes(&*element.value)
+                            .len()
+                }
+                BatchItem::DeleteElement(_) | BatchItem::MaybeDeleteElement(_) => 0,
+            })
+            .sum::<usize>();
+
+        assert_eq!(module.estimate_batch_bytes(&outputs), written_bytes);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_contract_outcome() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let preimage = user_preimage();
+        let (incoming, _) = fund_incoming(module, preimage.clone());
+        assert_eq!(
+            module.contract_outcome(incoming.contract_id()),
+            Some(ContractOutcome::Incoming(DecryptedPreimage::Pending))
+        );
+
+        agree_decryption_shares(module, &modules, &incoming);
+        end_epoch(module).await;
+        assert_eq!(
+            module.contract_outcome(incoming.contract_id()),
+            Some(ContractOutcome::Incoming(DecryptedPreimage::Some(preimage)))
+        );
+
+        let unknown = Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        });
+        assert_eq!(module.contract_outcome(unknown.contract_id()), None);
+    }
+
+    #[test_log::test]
+    fn test_recorded_consensus_epoch() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let contract = Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        });
+        fund_contract(module, &contract);
+
+        let mut dbtx = module.db.begin_transaction();
+        module.record_consensus_epoch(&mut dbtx, 42);
+        dbtx.commit_tx().expect("DB error");
+
+        let input = ContractInput {
+            contract_id: contract.contract_id(),
+            amount: Amount::from_sat(42),
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract: None,
+        };
+        assert_eq!(apply_input(module, &input), Ok(()));
+        assert_eq!(
+            module.contracts_spent_in_epoch(42),
+            vec![contract.contract_id()]
+        );
+        assert!(module.contracts_spent_in_epoch(0).is_empty());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_freeze_votes_expire() {
+        let (_, modules) = build_modules();
+        let module = &modules[0];
+
+        let contract = Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        });
+        fund_contract(module, &contract);
+        let unknown_contract = ContractId::from_hash(sha256::Hash::hash(b"unknown"));
+        let vote_count = |contract_id| {
+            module
+                .db
+                .find_by_prefix(&AgreedFreezeContractPrefix(contract_id))
+                .count()
+        };
+
+        // Our own vote alone doesn't reach the threshold
+        module.propose_freeze(contract.contract_id(), true);
+        let mut dbtx = module.db.begin_transaction();
+        module
+            .begin_consensus_epoch(
+                &mut dbtx,
+                vec![
+                    (
+                        PeerId::from(0),
+                        LightningConsensusItem::FreezeContract(contract.contract_id()),
+                    ),
+                    (
+                        PeerId::from(1),
+                        LightningConsensusItem::FreezeContract(unknown_contract),
+                    ),
+                ],
+                OsRng,
+            )
+            .await;
+        dbtx.commit_tx().expect("DB error");
+        assert_eq!(vote_count(contract.contract_id()), 1);
+        assert_eq!(vote_count(unknown_contract), 0);
+
+        // The agreed vote isn't proposed again
+        assert!(module.consensus_proposal(OsRng).await.is_empty());
+
+        for _ in 0..MAX_FREEZE_VOTE_EPOCHS {
+            end_epoch(module).await;
+        }
+        assert_eq!(vote_count(contract.contract_id()), 0);
+        assert!(!module.is_frozen(contract.contract_id()));
+        assert_eq!(
+            module
+                .db
+                .get_value(&ProposeFreezeKey(contract.contract_id()))
+                .expect("DB error"),
+            None
+        );
+    }
 }
@@ -0,0 +1,196 @@
+//! Snapshots of the LN module's consensus state and diffs between them, used to debug consensus
+//! divergence by comparing the state of two guardians.
+
+use std::collections::BTreeMap;
+
+use bitcoin_hashes::sha256;
+use fedimint_api::PeerId;
+
+use crate::contracts::incoming::IncomingContractOffer;
+use crate::contracts::{ContractId, PreimageDecryptionShare};
+use crate::db::{
+    AgreedDecryptionShareKey, AgreedDecryptionShareKeyPrefix, ContractKey, ContractKeyPrefix,
+    OfferKey, OfferKeyPrefix,
+};
+use crate::{ContractAccount, LightningModule};
+
+/// Contracts, offers and agreed decryption shares of a [`LightningModule`] at a point in time
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LightningModuleSnapshot {
+    pub contracts: BTreeMap<ContractId, ContractAccount>,
+    pub offers: BTreeMap<sha256::Hash, IncomingContractOffer>,
+    pub decryption_shares: BTreeMap<(ContractId, PeerId), PreimageDecryptionShare>,
+}
+
+/// Differences between two [`LightningModuleSnapshot`]s `a` and `b`, see [`diff`]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LnStateDiff {
+    pub contracts: MapDiff<ContractId, ContractAccount>,
+    pub offers: MapDiff<sha256::Hash, IncomingContractOffer>,
+    pub decryption_shares: MapDiff<(ContractId, PeerId), PreimageDecryptionShare>,
+}
+
+/// Differences between two maps `a` and `b`, all entries are ordered by key
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MapDiff<K, V> {
+    pub only_in_a: Vec<K>,
+    pub only_in_b: Vec<K>,
+    /// Keys present in both maps with their differing values in `a` and `b`
+    pub mismatched: Vec<(K, V, V)>,
+}
+
+impl<K, V> Default for MapDiff<K, V> {
+    fn default() -> Self {
+        MapDiff {
+            only_in_a: vec![],
+            only_in_b: vec![],
+            mismatched: vec![],
+        }
+    }
+}
+
+impl<K, V> MapDiff<K, V> {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl LnStateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.contracts.is_empty() && self.offers.is_empty() && self.decryption_shares.is_empty()
+    }
+}
+
+impl LightningModule {
+    /// Reads the module's current consensus state from the database
+    pub fn snapshot(&self) -> LightningModuleSnapshot {
+        LightningModuleSnapshot {
+            contracts: self
+                .db
+                .find_by_prefix(&ContractKeyPrefix)
+                .map(|res| {
+                    let (ContractKey(contract_id), account) = res.expect("DB error");
+                    (contract_id, account)
+                })
+                .collect(),
+            offers: self
+                .db
+                .find_by_prefix(&OfferKeyPrefix)
+                .map(|res| {
+                    let (OfferKey(hash), offer) = res.expect("DB error");
+                    (hash, offer)
+                })
+                .collect(),
+            decryption_shares: self
+                .db
+                .find_by_prefix(&AgreedDecryptionShareKeyPrefix)
+                .map(|res| {
+                    let (AgreedDecryptionShareKey(contract_id, peer), share) =
+                        res.expect("DB error");
+                    ((contract_id, peer), share)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Reports all entries present in only one of the snapshots `a` and `b` as well as entries whose
+/// values differ between them
+pub fn diff(a: &LightningModuleSnapshot, b: &LightningModuleSnapshot) -> LnStateDiff {
+    LnStateDiff {
+        contracts: diff_maps(&a.contracts, &b.contracts),
+        offers: diff_maps(&a.offers, &b.offers),
+        decryption_shares: diff_maps(&a.decryption_shares, &b.decryption_shares),
+    }
+}
+
+fn diff_maps<K, V>(a: &BTreeMap<K, V>, b: &BTreeMap<K, V>) -> MapDiff<K, V>
+where
+    K: Ord + Clone,
+    V: Eq + Clone,
+{
+    let mut diff = MapDiff::default();
+
+    for (key, a_value) in a {
+        match b.get(key) {
+            None => diff.only_in_a.push(key.clone()),
+            Some(b_value) if a_value != b_value => {
+                diff.mismatched
+                    .push((key.clone(), a_value.clone(), b_value.clone()));
+            }
+            Some(_) => {}
+        }
+    }
+    diff.only_in_b = b
+        .keys()
+        .filter(|key| !a.contains_key(key))
+        .cloned()
+        .collect();
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin_hashes::Hash as BitcoinHash;
+    use fedimint_api::{Amount, OutPoint};
+    use secp256k1::rand::rngs::OsRng;
+    use secp256k1::KeyPair;
+
+    use super::{diff, LightningModuleSnapshot};
+    use crate::contracts::account::AccountContract;
+    use crate::contracts::{Contract, IdentifyableContract};
+    use crate::ContractAccount;
+
+    fn account_contract() -> Contract {
+        Contract::Account(AccountContract {
+            key: KeyPair::new(secp256k1::global::SECP256K1, &mut OsRng)
+                .x_only_public_key()
+                .0,
+            multisig: None,
+        })
+    }
+
+    fn funded(contract: &Contract, sats: u64) -> ContractAccount {
+        ContractAccount {
+            amount: Amount::from_sat(sats),
+            contract: contract.clone().to_funded(
+                OutPoint {
+                    txid: bitcoin_hashes::sha256::Hash::hash(b"funding").into(),
+                    out_idx: 0,
+                },
+                0,
+            ),
+        }
+    }
+
+    #[test_log::test]
+    fn test_diff_contract_balance() {
+        let contracts = [account_contract(), account_contract()];
+
+        let mut a = LightningModuleSnapshot::default();
+        for contract in &contracts {
+            a.contracts
+                .insert(contract.contract_id(), funded(contract, 42));
+        }
+        assert!(diff(&a, &a).is_empty());
+
+        let mut b = a.clone();
+        b.contracts
+            .insert(contracts[1].contract_id(), funded(&contracts[1], 21));
+
+        let state_diff = diff(&a, &b);
+        assert!(state_diff.contracts.only_in_a.is_empty());
+        assert!(state_diff.contracts.only_in_b.is_empty());
+        assert_eq!(
+            state_diff.contracts.mismatched,
+            vec![(
+                contracts[1].contract_id(),
+                funded(&contracts[1], 42),
+                funded(&contracts[1], 21)
+            )]
+        );
+        assert!(state_diff.offers.is_empty());
+        assert!(state_diff.decryption_shares.is_empty());
+    }
+}
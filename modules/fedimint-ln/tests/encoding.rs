@@ -0,0 +1,380 @@
+use std::fmt::Debug;
+use std::io::Cursor;
+
+use bitcoin_hashes::sha256;
+use bitcoin_hashes::Hash as BitcoinHash;
+use fedimint_api::encoding::{Decodable, Encodable};
+use fedimint_api::{Amount, OutPoint, PeerId, TransactionId};
+use fedimint_ln::contracts::account::{AccountContract, AccountMultisig};
+use fedimint_ln::contracts::incoming::{
+    FundedIncomingContract, IncomingContract, IncomingContractOffer, OfferId, OfferSignature,
+};
+use fedimint_ln::contracts::outgoing::OutgoingContract;
+use fedimint_ln::contracts::{
+    AccountContractOutcome, Contract, ContractId, ContractOutcome, DecryptedPreimage,
+    EncryptedPreimage, FundedContract, OutgoingContractOutcome, Preimage, PreimageDecryptionShare,
+};
+use fedimint_ln::{
+    ContractAccount, ContractInput, ContractOrOfferOutput, ContractOutput, ContractWitness,
+    DecryptionShareCI, OutputOutcome,
+};
+use proptest::prelude::*;
+use secp256k1::rand::rngs::OsRng;
+use secp256k1::schnorr::Signature;
+use secp256k1::{KeyPair, SecretKey, XOnlyPublicKey};
+use threshold_crypto::SecretKeySet;
+
+fn assert_roundtrip<T>(value: T)
+where
+    T: Encodable + Decodable + Eq + Debug,
+{
+    let mut bytes = Vec::new();
+    let len = value.consensus_encode(&mut bytes).unwrap();
+    assert_eq!(len, bytes.len());
+
+    let mut cursor = Cursor::new(bytes);
+    let decoded = T::consensus_decode(&mut cursor).unwrap();
+    assert_eq!(value, decoded);
+    assert_eq!(cursor.position(), len as u64);
+}
+
+fn hash() -> impl Strategy<Value = sha256::Hash> {
+    any::<[u8; 32]>().prop_map(sha256::Hash::from_inner)
+}
+
+fn contract_id() -> impl Strategy<Value = ContractId> {
+    hash().prop_map(ContractId::from_hash)
+}
+
+fn out_point() -> impl Strategy<Value = OutPoint> {
+    (hash(), any::<u64>()).prop_map(|(hash, out_idx)| OutPoint {
+        txid: TransactionId::from_hash(hash),
+        out_idx,
+    })
+}
+
+fn schnorr_key() -> impl Strategy<Value = XOnlyPublicKey> {
+    any::<[u8; 32]>()
+        .prop_filter_map("not a valid secret key", |bytes| {
+            SecretKey::from_slice(&bytes).ok()
+        })
+        .prop_map(|secret_key| {
+            KeyPair::from_secret_key(secp256k1::global::SECP256K1, &secret_key)
+                .x_only_public_key()
+                .0
+        })
+}
+
+fn preimage() -> impl Strategy<Value = Preimage> {
+    any::<[u8; 32]>().prop_map(Preimage)
+}
+
+fn decrypted_preimage() -> impl Strategy<Value = DecryptedPreimage> {
+    prop_oneof![
+        Just(DecryptedPreimage::Pending),
+        preimage().prop_map(DecryptedPreimage::Some),
+        Just(DecryptedPreimage::Invalid),
+        any::<Vec<u8>>().prop_map(DecryptedPreimage::Raw),
+    ]
+}
+
+/// Encrypts a preimage to a freshly generated threshold key, so the key itself isn't shrinkable
+fn encrypted_preimage() -> impl Strategy<Value = EncryptedPreimage> {
+    preimage().prop_map(|preimage| {
+        let sks = SecretKeySet::random(2, &mut OsRng);
+        EncryptedPreimage::new(preimage, &sks.public_keys().public_key())
+    })
+}
+
+fn account_contract() -> impl Strategy<Value = AccountContract> {
+    (
+        schnorr_key(),
+        prop::option::of((prop::collection::vec(schnorr_key(), 0..4), any::<u16>())),
+    )
+        .prop_map(|(key, multisig)| AccountContract {
+            key,
+            multisig: multisig.map(|(cosigners, threshold)| AccountMultisig {
+                cosigners,
+                threshold,
+            }),
+        })
+}
+
+fn incoming_contract() -> impl Strategy<Value = IncomingContract> {
+    (
+        hash(),
+        encrypted_preimage(),
+        decrypted_preimage(),
+        schnorr_key(),
+        prop::collection::vec((schnorr_key(), any::<u64>()), 0..4),
+        any::<Option<u32>>(),
+    )
+        .prop_map(
+            |(
+                hash,
+                encrypted_preimage,
+                decrypted_preimage,
+                gateway_key,
+                recipients,
+                incoming_refund_height,
+            )| {
+                IncomingContract {
+                    hash,
+                    encrypted_preimage,
+                    decrypted_preimage,
+                    gateway_key,
+                    recipients: recipients
+                        .into_iter()
+                        .map(|(key, msat)| (key, Amount::from_msat(msat)))
+                        .collect(),
+                    incoming_refund_height,
+                }
+            },
+        )
+}
+
+fn outgoing_contract() -> impl Strategy<Value = OutgoingContract> {
+    (
+        hash(),
+        schnorr_key(),
+        any::<u32>(),
+        schnorr_key(),
+        any::<String>(),
+        any::<u64>(),
+        any::<bool>(),
+    )
+        .prop_map(
+            |(hash, gateway_key, timelock, user_key, invoice, invoice_msat, cancelled)| {
+                OutgoingContract {
+                    hash,
+                    gateway_key,
+                    timelock,
+                    user_key,
+                    invoice,
+                    invoice_amount: Amount::from_msat(invoice_msat),
+                    cancelled,
+                }
+            },
+        )
+}
+
+fn contract() -> impl Strategy<Value = Contract> {
+    prop_oneof![
+        account_contract().prop_map(Contract::Account),
+        incoming_contract().prop_map(Contract::Incoming),
+        outgoing_contract().prop_map(Contract::Outgoing),
+    ]
+}
+
+fn funded_contract() -> impl Strategy<Value = FundedContract> {
+    prop_oneof![
+        account_contract().prop_map(FundedContract::Account),
+        (incoming_contract(), out_point(), any::<u64>()).prop_map(
+            |(contract, out_point, funding_epoch)| {
+                FundedContract::Incoming(FundedIncomingContract {
+                    contract,
+                    out_point,
+                    funding_epoch,
+                })
+            }
+        ),
+        outgoing_contract().prop_map(FundedContract::Outgoing),
+    ]
+}
+
+fn contract_account() -> impl Strategy<Value = ContractAccount> {
+    (any::<u64>(), funded_contract()).prop_map(|(msat, contract)| ContractAccount {
+        amount: Amount::from_msat(msat),
+        contract,
+    })
+}
+
+fn contract_outcome() -> impl Strategy<Value = ContractOutcome> {
+    prop_oneof![
+        Just(ContractOutcome::Account(AccountContractOutcome {})),
+        decrypted_preimage().prop_map(ContractOutcome::Incoming),
+        Just(ContractOutcome::Outgoing(OutgoingContractOutcome {})),
+    ]
+}
+
+fn output_outcome() -> impl Strategy<Value = OutputOutcome> {
+    let offer_id = || hash().prop_map(OfferId::from_hash);
+    prop_oneof![
+        (contract_id(), contract_outcome())
+            .prop_map(|(id, outcome)| OutputOutcome::Contract { id, outcome }),
+        offer_id().prop_map(|id| OutputOutcome::Offer { id }),
+        prop::collection::vec(offer_id(), 0..8).prop_map(|ids| OutputOutcome::OfferBatch { ids }),
+    ]
+}
+
+fn offer() -> impl Strategy<Value = IncomingContractOffer> {
+    (
+        any::<u64>(),
+        hash(),
+        encrypted_preimage(),
+        any::<Option<u64>>(),
+        any::<Option<u64>>(),
+        any::<Option<u32>>(),
+        prop::option::of((schnorr_key(), any::<[u8; 32]>(), any::<[u8; 32]>())),
+    )
+        .prop_map(
+            |(msat, hash, encrypted_preimage, expiry_time, hint_msat, not_before, signature)| {
+                IncomingContractOffer {
+                    amount: Amount::from_msat(msat),
+                    hash,
+                    encrypted_preimage,
+                    expiry_time,
+                    denomination_hint: hint_msat.map(Amount::from_msat),
+                    not_before,
+                    signature: signature.map(|(receiver_key, r, s)| OfferSignature {
+                        receiver_key,
+                        signature: Signature::from_slice(&[r, s].concat())
+                            .expect("any 64 bytes are a signature"),
+                    }),
+                }
+            },
+        )
+}
+
+fn contract_or_offer_output() -> impl Strategy<Value = ContractOrOfferOutput> {
+    prop_oneof![
+        (any::<u64>(), contract()).prop_map(|(msat, contract)| {
+            ContractOrOfferOutput::Contract(ContractOutput {
+                amount: Amount::from_msat(msat),
+                contract,
+            })
+        }),
+        offer().prop_map(ContractOrOfferOutput::Offer),
+        prop::collection::vec(offer(), 0..4).prop_map(ContractOrOfferOutput::OfferBatch),
+        (contract_id(), any::<[u8; 32]>(), any::<[u8; 32]>()).prop_map(|(contract, r, s)| {
+            ContractOrOfferOutput::CancelOutgoing {
+                contract,
+                gateway_signature: Signature::from_slice(&[r, s].concat())
+                    .expect("any 64 bytes are a signature"),
+            }
+        }),
+        (offer(), any::<u64>(), contract()).prop_map(|(offer, msat, contract)| {
+            ContractOrOfferOutput::OfferAndContract(
+                offer,
+                ContractOutput {
+                    amount: Amount::from_msat(msat),
+                    contract,
+                },
+            )
+        }),
+    ]
+}
+
+fn contract_witness() -> impl Strategy<Value = ContractWitness> {
+    prop_oneof![
+        Just(ContractWitness::None),
+        preimage().prop_map(ContractWitness::Preimage),
+        prop::collection::vec(schnorr_key(), 0..4).prop_map(ContractWitness::Signers),
+    ]
+}
+
+fn contract_input() -> impl Strategy<Value = ContractInput> {
+    (
+        contract_id(),
+        any::<u64>(),
+        contract_witness(),
+        prop::option::of(schnorr_key()),
+        prop::option::of(contract()),
+    )
+        .prop_map(
+            |(contract_id, msat, witness, recipient, change_contract)| ContractInput {
+                contract_id,
+                amount: Amount::from_msat(msat),
+                witness,
+                recipient,
+                change_contract,
+            },
+        )
+}
+
+fn encoded_len<T: Encodable>(value: &T) -> usize {
+    let mut bytes = Vec::new();
+    value.consensus_encode(&mut bytes).unwrap();
+    bytes.len()
+}
+
+fn decryption_share_ci() -> impl Strategy<Value = DecryptionShareCI> {
+    (contract_id(), any::<u16>(), preimage()).prop_map(|(contract_id, peer, preimage)| {
+        let sks = SecretKeySet::random(2, &mut OsRng);
+        let encrypted_preimage = EncryptedPreimage::new(preimage, &sks.public_keys().public_key());
+        let share = sks
+            .secret_key_share(0)
+            .decrypt_share(&encrypted_preimage.0)
+            .expect("valid ciphertext");
+        DecryptionShareCI {
+            contract_id,
+            peer: PeerId::from(peer),
+            share: PreimageDecryptionShare(share),
+        }
+    })
+}
+
+proptest! {
+    #[test]
+    fn contract_roundtrip(contract in contract()) {
+        assert_roundtrip(contract);
+    }
+
+    #[test]
+    fn funded_contract_roundtrip(contract in funded_contract()) {
+        assert_roundtrip(contract);
+    }
+
+    #[test]
+    fn contract_account_roundtrip(account in contract_account()) {
+        assert_roundtrip(account);
+    }
+
+    #[test]
+    fn output_outcome_roundtrip(outcome in output_outcome()) {
+        assert_roundtrip(outcome);
+    }
+
+    #[test]
+    fn output_encoded_size(output in contract_or_offer_output()) {
+        prop_assert_eq!(output.encoded_size(), encoded_len(&output));
+    }
+
+    #[test]
+    fn input_encoded_size(input in contract_input()) {
+        prop_assert_eq!(input.encoded_size(), encoded_len(&input));
+    }
+
+    #[test]
+    fn decryption_share_ci_roundtrip(ci in decryption_share_ci()) {
+        assert_roundtrip(ci);
+    }
+
+    #[test]
+    fn contract_witness_roundtrip(witness in contract_witness()) {
+        assert_roundtrip(witness);
+    }
+}
+
+#[test]
+fn contract_witness_unknown_kind() {
+    let mut bytes = vec![];
+    3u64.consensus_encode(&mut bytes).unwrap();
+    assert!(ContractWitness::consensus_decode(&mut Cursor::new(bytes)).is_err());
+}
+
+#[test]
+fn output_outcome_invalid_decrypted_preimage() {
+    let outcome = OutputOutcome::Contract {
+        id: ContractId::from_hash(sha256::Hash::hash(b"contract")),
+        outcome: ContractOutcome::Incoming(DecryptedPreimage::Pending),
+    };
+    let mut bytes = Vec::new();
+    outcome.consensus_encode(&mut bytes).unwrap();
+
+    // Replace the preimage status with one that doesn't exist
+    let status_idx = bytes.len() - 8;
+    bytes[status_idx..].copy_from_slice(&3u64.to_le_bytes());
+
+    assert!(OutputOutcome::consensus_decode(&mut Cursor::new(bytes)).is_err());
+}
@@ -1,5 +1,6 @@
 use bitcoin_hashes::sha256;
 use bitcoin_hashes::Hash as BitcoinHash;
+use fedimint_api::encoding::{Decodable, Encodable};
 use fedimint_api::module::testing::FakeFed;
 use fedimint_api::{Amount, OutPoint};
 use fedimint_ln::config::LightningModuleClientConfig;
@@ -7,15 +8,88 @@ use fedimint_ln::contracts::account::AccountContract;
 use fedimint_ln::contracts::incoming::{IncomingContract, IncomingContractOffer};
 use fedimint_ln::contracts::outgoing::OutgoingContract;
 use fedimint_ln::contracts::{
-    AccountContractOutcome, Contract, ContractOutcome, DecryptedPreimage, EncryptedPreimage,
-    IdentifyableContract, OutgoingContractOutcome, Preimage,
+    AccountContractOutcome, Contract, ContractId, ContractOutcome, DecryptedPreimage,
+    EncryptedPreimage, IdentifyableContract, OutgoingContractOutcome, Preimage,
 };
 use fedimint_ln::{
-    ContractInput, ContractOrOfferOutput, ContractOutput, LightningModule, LightningModuleError,
-    OutputOutcome,
+    ContractInput, ContractOrOfferOutput, ContractOutput, ContractWitness, LightningModule,
+    LightningModuleError, OutputOutcome, MAX_OFFER_BATCH_SIZE,
 };
 use secp256k1::KeyPair;
 
+type Fed = FakeFed<LightningModule, LightningModuleClientConfig>;
+
+async fn new_fed() -> Fed {
+    FakeFed::<LightningModule, LightningModuleClientConfig>::new(
+        4,
+        |cfg, db| async { LightningModule::new(cfg, db) },
+        &(),
+    )
+    .await
+}
+
+fn out_point(tag: &[u8], out_idx: u64) -> OutPoint {
+    OutPoint {
+        txid: sha256::Hash::hash(tag).into(),
+        out_idx,
+    }
+}
+
+fn user_preimage() -> Preimage {
+    let ctx = secp256k1::Secp256k1::new();
+    let user_kp = KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng);
+    Preimage(user_kp.x_only_public_key().0.serialize())
+}
+
+fn new_offer(fed: &Fed, preimage: &Preimage, amount: Amount) -> IncomingContractOffer {
+    IncomingContractOffer {
+        amount,
+        hash: sha256::Hash::hash(&preimage.0),
+        encrypted_preimage: EncryptedPreimage::new(
+            preimage.clone(),
+            &fed.client_cfg().threshold_pub_key,
+        ),
+        expiry_time: None,
+        denomination_hint: None,
+        not_before: None,
+        signature: None,
+    }
+}
+
+/// Tampers with the encrypted message of `encrypted_preimage` so it fails verification
+fn corrupt_encrypted_preimage(encrypted_preimage: &EncryptedPreimage) -> EncryptedPreimage {
+    let mut bytes = bincode::serialize(&encrypted_preimage.0).unwrap();
+    // The encrypted 32 byte preimage is the only element prefixed with its length 32
+    let msg_idx = bytes
+        .windows(8)
+        .position(|window| window == 32u64.to_le_bytes())
+        .expect("contains encrypted message")
+        + 8;
+    bytes[msg_idx] ^= 1;
+    EncryptedPreimage(bincode::deserialize(&bytes).unwrap())
+}
+
+/// Creates the incoming contract buying the preimage advertised by `offer`
+fn incoming_contract(offer: &IncomingContractOffer) -> Contract {
+    let ctx = secp256k1::Secp256k1::new();
+    let gw_kp = KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng);
+    Contract::Incoming(IncomingContract {
+        hash: offer.hash,
+        encrypted_preimage: offer.encrypted_preimage.clone(),
+        decrypted_preimage: DecryptedPreimage::Pending,
+        gateway_key: gw_kp.x_only_public_key().0,
+        recipients: vec![],
+        incoming_refund_height: None,
+    })
+}
+
+fn contract_output(contract: &Contract, amount: Amount) -> ContractOrOfferOutput {
+    ContractOrOfferOutput::Contract(ContractOutput {
+        amount,
+        contract: contract.clone(),
+    })
+}
+
 #[test_log::test(tokio::test)]
 async fn test_account() {
     let mut rng = secp256k1::rand::rngs::OsRng;
@@ -31,6 +105,7 @@ async fn test_account() {
     let kp = KeyPair::new(&ctx, &mut rng);
     let contract = Contract::Account(AccountContract {
         key: kp.x_only_public_key().0,
+        multisig: None,
     });
 
     let account_output = ContractOrOfferOutput::Contract(ContractOutput {
@@ -54,7 +129,9 @@ async fn test_account() {
     let account_input = ContractInput {
         contract_id: contract.contract_id(),
         amount: Amount::from_sat(42),
-        witness: None,
+        witness: ContractWitness::None,
+        recipient: None,
+        change_contract: None,
     };
     let meta = fed.verify_input(&account_input).unwrap();
     assert_eq!(meta.keys, vec![kp.x_only_public_key().0]);
@@ -87,6 +164,7 @@ async fn test_outgoing() {
         timelock: 42,
         user_key: user_pk,
         invoice: "not enforced yet".to_string(),
+        invoice_amount: Amount::from_sat(40),
         cancelled: false,
     });
 
@@ -118,7 +196,9 @@ async fn test_outgoing() {
     let account_input_no_witness = ContractInput {
         contract_id: contract.contract_id(),
         amount: Amount::from_sat(42),
-        witness: None,
+        witness: ContractWitness::None,
+        recipient: None,
+        change_contract: None,
     };
     let err = fed.verify_input(&account_input_no_witness).unwrap_err();
     assert_eq!(err, LightningModuleError::MissingPreimage);
@@ -127,7 +207,9 @@ async fn test_outgoing() {
     let account_input_witness = ContractInput {
         contract_id: contract.contract_id(),
         amount: Amount::from_sat(42),
-        witness: Some(preimage),
+        witness: ContractWitness::Preimage(preimage),
+        recipient: None,
+        change_contract: None,
     };
     let meta = fed.verify_input(&account_input_witness).unwrap();
     assert_eq!(meta.keys, vec![gw_pk]);
@@ -166,6 +248,9 @@ async fn test_incoming() {
             &fed.client_cfg().threshold_pub_key,
         ),
         expiry_time: None,
+        denomination_hint: None,
+        not_before: None,
+        signature: None,
     };
     let offer_output = ContractOrOfferOutput::Offer(offer.clone());
     let offer_out_point = OutPoint {
@@ -181,8 +266,10 @@ async fn test_incoming() {
     let contract = Contract::Incoming(IncomingContract {
         hash, // TODO: check unknown hash
         encrypted_preimage: offer.encrypted_preimage,
-        decrypted_preimage: DecryptedPreimage::Pending, // TODO: check what happens if this is not pending
+        decrypted_preimage: DecryptedPreimage::Pending,
         gateway_key: gw_pk,
+        recipients: vec![],
+        incoming_refund_height: None,
     });
     let incoming_output = ContractOrOfferOutput::Contract(ContractOutput {
         amount: Amount::from_sat(42),
@@ -208,7 +295,9 @@ async fn test_incoming() {
     let incoming_input = ContractInput {
         contract_id: contract.contract_id(),
         amount: Amount::from_sat(42),
-        witness: None,
+        witness: ContractWitness::None,
+        recipient: None,
+        change_contract: None,
     };
     let error = fed.verify_input(&incoming_input).unwrap_err();
     assert_eq!(error, LightningModuleError::ContractNotReady);
@@ -229,3 +318,837 @@ async fn test_incoming() {
 
     // TODO: test faulty encrypted preimage
 }
+
+/// Drives an incoming payment through all phases of the protocol: the user registers an offer,
+/// the gateway funds it with an incoming contract, the guardians agree on decryption shares, the
+/// preimage gets decrypted and finally the user spends the contract.
+#[test_log::test(tokio::test)]
+async fn test_incoming_payment_lifecycle() {
+    let mut fed = new_fed().await;
+
+    let ctx = secp256k1::Secp256k1::new();
+    let user_pk = KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng)
+        .x_only_public_key()
+        .0;
+    let preimage = Preimage(user_pk.serialize());
+    let offer = new_offer(&fed, &preimage, Amount::from_sat(42));
+    fed.consensus_round(
+        &[],
+        &[(
+            out_point(b"offer", 0),
+            ContractOrOfferOutput::Offer(offer.clone()),
+        )],
+    )
+    .await;
+    assert_eq!(fed.fetch_from_all(|m| m.get_offers()), vec![offer.clone()]);
+
+    // Funding consumes the offer, every guardian then proposes its decryption share
+    let contract = incoming_contract(&offer);
+    let incoming_out_point = out_point(b"incoming", 0);
+    fed.consensus_round(
+        &[],
+        &[(
+            incoming_out_point,
+            contract_output(&contract, Amount::from_sat(42)),
+        )],
+    )
+    .await;
+    assert_eq!(fed.fetch_from_all(|m| m.get_offers()), vec![]);
+    assert_eq!(
+        fed.fetch_from_all(|m| m.pending_incoming_outpoints()),
+        vec![incoming_out_point]
+    );
+    assert!(fed.fetch_from_all(|m| m.export_decryption_share(contract.contract_id()).is_some()));
+
+    let input = ContractInput {
+        contract_id: contract.contract_id(),
+        amount: Amount::from_sat(42),
+        witness: ContractWitness::None,
+        recipient: None,
+        change_contract: None,
+    };
+    assert_eq!(
+        fed.verify_input(&input).unwrap_err(),
+        LightningModuleError::ContractNotReady
+    );
+
+    // The proposed shares are agreed upon and the preimage decrypted within the next epoch
+    fed.consensus_round(&[], &[]).await;
+    assert_eq!(
+        fed.output_outcome(incoming_out_point),
+        Some(OutputOutcome::Contract {
+            id: contract.contract_id(),
+            outcome: ContractOutcome::Incoming(DecryptedPreimage::Some(preimage)),
+        })
+    );
+    assert_eq!(
+        fed.fetch_from_all(|m| m.pending_incoming_outpoints()),
+        vec![]
+    );
+    assert!(fed.fetch_from_all(|m| m.export_decryption_share(contract.contract_id()).is_none()));
+    assert!(fed.fetch_from_all(|m| m.snapshot().decryption_shares.is_empty()));
+
+    // The user can spend the contract by signing with the key the preimage encodes
+    let meta = fed.verify_input(&input).unwrap();
+    assert_eq!(meta.keys, vec![user_pk]);
+    assert_eq!(meta.amount.amount, Amount::from_sat(42));
+
+    fed.consensus_round(&[input], &[]).await;
+    assert_eq!(
+        fed.fetch_from_all(|m| m
+            .get_contract_account(contract.contract_id())
+            .map(|account| account.amount)),
+        Some(Amount::ZERO)
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_claim_multiple_outgoing() {
+    let mut rng = secp256k1::rand::rngs::OsRng;
+
+    let mut fed = FakeFed::<LightningModule, LightningModuleClientConfig>::new(
+        4,
+        |cfg, db| async { LightningModule::new(cfg, db) },
+        &(),
+    )
+    .await;
+
+    let ctx = secp256k1::Secp256k1::new();
+    let gw_pk = KeyPair::new(&ctx, &mut rng).x_only_public_key().0;
+    let user_pk = KeyPair::new(&ctx, &mut rng).x_only_public_key().0;
+
+    let claims = (0u8..3)
+        .map(|idx| {
+            let preimage = Preimage([idx; 32]);
+            let contract = Contract::Outgoing(OutgoingContract {
+                hash: secp256k1::hashes::sha256::Hash::hash(&preimage.0),
+                gateway_key: gw_pk,
+                timelock: 42,
+                user_key: user_pk,
+                invoice: "not enforced yet".to_string(),
+                invoice_amount: Amount::from_sat(40),
+                cancelled: false,
+            });
+            (contract, preimage)
+        })
+        .collect::<Vec<_>>();
+
+    let outputs = claims
+        .iter()
+        .enumerate()
+        .map(|(idx, (contract, _))| {
+            let out_point = OutPoint {
+                txid: sha256::Hash::hash(b"x").into(),
+                out_idx: idx as u64,
+            };
+            let output = ContractOrOfferOutput::Contract(ContractOutput {
+                amount: Amount::from_sat(42),
+                contract: contract.clone(),
+            });
+            (out_point, output)
+        })
+        .collect::<Vec<_>>();
+    fed.consensus_round(&[], &outputs).await;
+
+    let inputs = claims
+        .into_iter()
+        .map(|(contract, preimage)| ContractInput {
+            contract_id: contract.contract_id(),
+            amount: Amount::from_sat(42),
+            witness: ContractWitness::Preimage(preimage),
+            recipient: None,
+            change_contract: None,
+        })
+        .collect::<Vec<_>>();
+
+    // Every claim validates independently of the others in the same transaction
+    for input in &inputs {
+        let meta = fed.verify_input(input).unwrap();
+        assert_eq!(meta.keys, vec![gw_pk]);
+    }
+
+    fed.consensus_round(&inputs, &[]).await;
+
+    for input in &inputs {
+        assert_eq!(
+            fed.verify_input(input).unwrap_err(),
+            LightningModuleError::InsufficientFunds {
+                contract: input.contract_id,
+                available: Amount::ZERO,
+                requested: Amount::from_sat(42),
+            }
+        );
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_offer_storage_bytes() {
+    let mut fed = FakeFed::<LightningModule, LightningModuleClientConfig>::new(
+        4,
+        |cfg, db| async { LightningModule::new(cfg, db) },
+        &(),
+    )
+    .await;
+
+    let offers = [b"a", b"b"]
+        .iter()
+        .map(|data| {
+            let preimage = Preimage(sha256::Hash::hash(&data[..]).into_inner());
+            IncomingContractOffer {
+                amount: Amount::from_sat(42),
+                hash: sha256::Hash::hash(&preimage.0),
+                encrypted_preimage: EncryptedPreimage::new(
+                    preimage,
+                    &fed.client_cfg().threshold_pub_key,
+                ),
+                expiry_time: None,
+                denomination_hint: None,
+                not_before: None,
+                signature: None,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let outputs = offers
+        .iter()
+        .enumerate()
+        .map(|(idx, offer)| {
+            let out_point = OutPoint {
+                txid: sha256::Hash::hash(b"").into(),
+                out_idx: idx as u64,
+            };
+            (out_point, ContractOrOfferOutput::Offer(offer.clone()))
+        })
+        .collect::<Vec<_>>();
+    fed.consensus_round(&[], &outputs).await;
+
+    let expected_bytes: usize = offers
+        .iter()
+        .map(|offer| offer.consensus_encode(&mut std::io::sink()).unwrap())
+        .sum();
+    assert_eq!(
+        fed.fetch_from_all(|m| m.offer_storage_bytes()),
+        expected_bytes
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_degenerate_outgoing() {
+    let mut rng = secp256k1::rand::rngs::OsRng;
+
+    let fed = FakeFed::<LightningModule, LightningModuleClientConfig>::new(
+        4,
+        |cfg, db| async { LightningModule::new(cfg, db) },
+        &(),
+    )
+    .await;
+
+    let ctx = secp256k1::Secp256k1::new();
+    let gw_pk = KeyPair::new(&ctx, &mut rng).x_only_public_key().0;
+    let user_pk = KeyPair::new(&ctx, &mut rng).x_only_public_key().0;
+
+    let outgoing_output = |gateway_key, user_key| {
+        ContractOrOfferOutput::Contract(ContractOutput {
+            amount: Amount::from_sat(42),
+            contract: Contract::Outgoing(OutgoingContract {
+                hash: sha256::Hash::hash(b"preimage"),
+                gateway_key,
+                timelock: 42,
+                user_key,
+                invoice: "not enforced yet".to_string(),
+                invoice_amount: Amount::from_sat(40),
+                cancelled: false,
+            }),
+        })
+    };
+
+    assert!(fed.verify_output(&outgoing_output(gw_pk, gw_pk)));
+    assert!(!fed.verify_output(&outgoing_output(gw_pk, user_pk)));
+}
+
+#[test_log::test(tokio::test)]
+async fn test_estimated_decryption_epochs() {
+    let mut fed = FakeFed::<LightningModule, LightningModuleClientConfig>::new(
+        4,
+        |cfg, db| async { LightningModule::new(cfg, db) },
+        &(),
+    )
+    .await;
+
+    assert!(fed.fetch_from_all(|m| m.estimated_decryption_epochs()) > 0);
+}
+
+#[test_log::test]
+fn test_decode_oversized_witness() {
+    let contract_id: ContractId = sha256::Hash::hash(b"contract").into();
+    let mut bytes = vec![];
+    contract_id.consensus_encode(&mut bytes).unwrap();
+    Amount::from_sat(42).consensus_encode(&mut bytes).unwrap();
+
+    // Replace the length of an empty signers witness with a huge one, without providing any keys
+    ContractWitness::Signers(vec![])
+        .consensus_encode(&mut bytes)
+        .unwrap();
+    bytes.truncate(bytes.len() - 8);
+    u64::MAX.consensus_encode(&mut bytes).unwrap();
+
+    assert!(ContractInput::consensus_decode(&mut std::io::Cursor::new(bytes)).is_err());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_validate_input_at() {
+    let mut rng = secp256k1::rand::rngs::OsRng;
+
+    let mut fed = FakeFed::<LightningModule, LightningModuleClientConfig>::new(
+        4,
+        |cfg, db| async { LightningModule::new(cfg, db) },
+        &(),
+    )
+    .await;
+
+    let ctx = secp256k1::Secp256k1::new();
+    let gw_pk = KeyPair::new(&ctx, &mut rng).x_only_public_key().0;
+    let user_pk = KeyPair::new(&ctx, &mut rng).x_only_public_key().0;
+
+    let contract = Contract::Outgoing(OutgoingContract {
+        hash: sha256::Hash::hash(b"preimage"),
+        gateway_key: gw_pk,
+        timelock: 42,
+        user_key: user_pk,
+        invoice: "not enforced yet".to_string(),
+        invoice_amount: Amount::from_sat(40),
+        cancelled: false,
+    });
+    let outgoing_output = ContractOrOfferOutput::Contract(ContractOutput {
+        amount: Amount::from_sat(42),
+        contract: contract.clone(),
+    });
+    let outgoing_out_point = OutPoint {
+        txid: sha256::Hash::hash(b"x").into(),
+        out_idx: 0,
+    };
+    fed.consensus_round(&[], &[(outgoing_out_point, outgoing_output)])
+        .await;
+
+    let refund_input = ContractInput {
+        contract_id: contract.contract_id(),
+        amount: Amount::from_sat(42),
+        witness: ContractWitness::None,
+        recipient: None,
+        change_contract: None,
+    };
+
+    // The consensus block height is still 0, so the refund isn't valid yet
+    fed.set_block_height(0);
+    assert_eq!(
+        fed.verify_input(&refund_input).unwrap_err(),
+        LightningModuleError::MissingPreimage
+    );
+
+    let simulated_keys = fed.fetch_from_all(|m| {
+        m.validate_input_at(&refund_input, 42)
+            .map(|meta| meta.puk_keys.collect::<Vec<_>>())
+    });
+    assert_eq!(simulated_keys, Ok(vec![user_pk]));
+}
+
+#[test_log::test(tokio::test)]
+async fn test_offer_funding_contract() {
+    let mut fed = new_fed().await;
+
+    let offer = new_offer(&fed, &user_preimage(), Amount::from_sat(42));
+    fed.consensus_round(
+        &[],
+        &[(
+            out_point(b"offer", 0),
+            ContractOrOfferOutput::Offer(offer.clone()),
+        )],
+    )
+    .await;
+    assert_eq!(
+        fed.fetch_from_all(|m| m.offer_funding_contract(offer.id())),
+        None
+    );
+
+    let contract = incoming_contract(&offer);
+    fed.consensus_round(
+        &[],
+        &[(
+            out_point(b"incoming", 0),
+            contract_output(&contract, Amount::from_sat(42)),
+        )],
+    )
+    .await;
+
+    assert_eq!(
+        fed.fetch_from_all(|m| m.offer_funding_contract(offer.id())),
+        Some(contract.contract_id())
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_offer_batch() {
+    let mut fed = new_fed().await;
+
+    let new_offers = |fed: &Fed| {
+        (0..3)
+            .map(|_| new_offer(fed, &user_preimage(), Amount::from_sat(42)))
+            .collect::<Vec<_>>()
+    };
+
+    let mut invalid_offers = new_offers(&fed);
+    invalid_offers[1].encrypted_preimage =
+        corrupt_encrypted_preimage(&invalid_offers[1].encrypted_preimage);
+    assert!(!invalid_offers[1].encrypted_preimage.0.verify());
+    assert!(fed.verify_output(&ContractOrOfferOutput::OfferBatch(invalid_offers)));
+
+    let oversized_offers = (0..=MAX_OFFER_BATCH_SIZE)
+        .map(|_| new_offer(&fed, &user_preimage(), Amount::from_sat(42)))
+        .collect();
+    assert!(fed.verify_output(&ContractOrOfferOutput::OfferBatch(oversized_offers)));
+
+    let offers = new_offers(&fed);
+    let batch_output = ContractOrOfferOutput::OfferBatch(offers.clone());
+    assert!(!fed.verify_output(&batch_output));
+
+    let batch_out_point = out_point(b"offers", 0);
+    fed.consensus_round(&[], &[(batch_out_point, batch_output)])
+        .await;
+
+    assert_eq!(
+        fed.output_outcome(batch_out_point),
+        Some(OutputOutcome::OfferBatch {
+            ids: offers.iter().map(|offer| offer.id()).collect()
+        })
+    );
+    for offer in offers {
+        assert_eq!(fed.fetch_from_all(|m| m.get_offer(offer.hash)), Some(offer));
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_zero_offer() {
+    let fed = new_fed().await;
+
+    let zero_offer = new_offer(&fed, &user_preimage(), Amount::ZERO);
+    assert!(fed.verify_output(&ContractOrOfferOutput::Offer(zero_offer)));
+
+    let offer = new_offer(&fed, &user_preimage(), Amount::from_sat(42));
+    assert!(!fed.verify_output(&ContractOrOfferOutput::Offer(offer)));
+}
+
+#[test_log::test(tokio::test)]
+async fn test_offer_denomination_hint() {
+    let mut fed = new_fed().await;
+
+    let offer = IncomingContractOffer {
+        denomination_hint: Some(Amount::from_sat(1)),
+        ..new_offer(&fed, &user_preimage(), Amount::from_sat(42))
+    };
+    fed.consensus_round(
+        &[],
+        &[(
+            out_point(b"offer", 0),
+            ContractOrOfferOutput::Offer(offer.clone()),
+        )],
+    )
+    .await;
+
+    let stored = fed.fetch_from_all(|m| m.get_offer(offer.hash));
+    assert_eq!(
+        stored.and_then(|offer| offer.denomination_hint),
+        Some(Amount::from_sat(1))
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_fund_offer_twice() {
+    let mut fed = new_fed().await;
+
+    let offer = new_offer(&fed, &user_preimage(), Amount::from_sat(42));
+    fed.consensus_round(
+        &[],
+        &[(
+            out_point(b"offer", 0),
+            ContractOrOfferOutput::Offer(offer.clone()),
+        )],
+    )
+    .await;
+
+    // Both contracts are accepted in the same epoch, the second one deleting the already removed
+    // offer again
+    let contracts = [incoming_contract(&offer), incoming_contract(&offer)];
+    fed.consensus_round(
+        &[],
+        &[
+            (
+                out_point(b"incoming", 0),
+                contract_output(&contracts[0], Amount::from_sat(42)),
+            ),
+            (
+                out_point(b"incoming", 1),
+                contract_output(&contracts[1], Amount::from_sat(42)),
+            ),
+        ],
+    )
+    .await;
+
+    assert_eq!(fed.fetch_from_all(|m| m.get_offer(offer.hash)), None);
+    for contract in contracts {
+        assert!(fed
+            .fetch_from_all(|m| m.get_contract_account(contract.contract_id()))
+            .is_some());
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn test_incoming_invalid_preimage() {
+    let mut fed = new_fed().await;
+
+    // Exceeds the field size, so it's never a valid public key the user could sign with
+    let preimage = Preimage([0xff; 32]);
+    let offer = new_offer(&fed, &preimage, Amount::from_sat(42));
+    fed.consensus_round(
+        &[],
+        &[(
+            out_point(b"offer", 0),
+            ContractOrOfferOutput::Offer(offer.clone()),
+        )],
+    )
+    .await;
+
+    let contract = incoming_contract(&offer);
+    let gateway_key = match &contract {
+        Contract::Incoming(incoming) => incoming.gateway_key,
+        _ => unreachable!(),
+    };
+    let incoming_out_point = out_point(b"incoming", 0);
+    fed.consensus_round(
+        &[],
+        &[(
+            incoming_out_point,
+            contract_output(&contract, Amount::from_sat(42)),
+        )],
+    )
+    .await;
+    fed.consensus_round(&[], &[]).await;
+
+    assert_eq!(
+        fed.output_outcome(incoming_out_point),
+        Some(OutputOutcome::Contract {
+            id: contract.contract_id(),
+            outcome: ContractOutcome::Incoming(DecryptedPreimage::Invalid),
+        })
+    );
+
+    // Only the gateway's key may sign for the reclaim
+    let meta = fed
+        .verify_input(&ContractInput {
+            contract_id: contract.contract_id(),
+            amount: Amount::from_sat(42),
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract: None,
+        })
+        .unwrap();
+    assert_eq!(meta.keys, vec![gateway_key]);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_get_offers_paged() {
+    let mut fed = new_fed().await;
+
+    let offer_outputs = (0..25)
+        .map(|idx| {
+            (
+                out_point(b"offers", idx),
+                ContractOrOfferOutput::Offer(new_offer(
+                    &fed,
+                    &user_preimage(),
+                    Amount::from_sat(42),
+                )),
+            )
+        })
+        .collect::<Vec<_>>();
+    fed.consensus_round(&[], &offer_outputs).await;
+
+    let mut paged_offers = vec![];
+    let mut cursor = None;
+    let mut page_sizes = vec![];
+    loop {
+        let (page, next) = fed.fetch_from_all(|m| m.get_offers_paged(cursor, 10));
+        page_sizes.push(page.len());
+        paged_offers.extend(page);
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+
+    assert_eq!(page_sizes, vec![10, 10, 5]);
+    assert_eq!(paged_offers, fed.fetch_from_all(|m| m.get_offers()));
+}
+
+#[test_log::test(tokio::test)]
+async fn test_gateway_earnings() {
+    let mut fed = new_fed().await;
+
+    let ctx = secp256k1::Secp256k1::new();
+    let gw_pk = KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng)
+        .x_only_public_key()
+        .0;
+    let user_pk = KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng)
+        .x_only_public_key()
+        .0;
+
+    let preimage = Preimage([42u8; 32]);
+    let contract = Contract::Outgoing(OutgoingContract {
+        hash: sha256::Hash::hash(&preimage.0),
+        gateway_key: gw_pk,
+        timelock: 42,
+        user_key: user_pk,
+        invoice: "not enforced yet".to_string(),
+        invoice_amount: Amount::from_sat(40),
+        cancelled: false,
+    });
+    fed.consensus_round(
+        &[],
+        &[(
+            out_point(b"outgoing", 0),
+            contract_output(&contract, Amount::from_sat(42)),
+        )],
+    )
+    .await;
+    assert_eq!(
+        fed.fetch_from_all(|m| m.gateway_earnings(&gw_pk)),
+        Amount::ZERO
+    );
+
+    let claim = ContractInput {
+        contract_id: contract.contract_id(),
+        amount: Amount::from_sat(42),
+        witness: ContractWitness::Preimage(preimage),
+        recipient: None,
+        change_contract: None,
+    };
+    fed.consensus_round(&[claim], &[]).await;
+
+    assert_eq!(
+        fed.fetch_from_all(|m| m.gateway_earnings(&gw_pk)),
+        Amount::from_sat(2)
+    );
+    assert_eq!(
+        fed.fetch_from_all(|m| m.gateway_earnings(&user_pk)),
+        Amount::ZERO
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_gateway_earnings_partial_claims() {
+    let mut fed = new_fed().await;
+
+    let ctx = secp256k1::Secp256k1::new();
+    let gw_pk = KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng)
+        .x_only_public_key()
+        .0;
+    let user_pk = KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng)
+        .x_only_public_key()
+        .0;
+
+    let preimage = Preimage([42u8; 32]);
+    let contract = Contract::Outgoing(OutgoingContract {
+        hash: sha256::Hash::hash(&preimage.0),
+        gateway_key: gw_pk,
+        timelock: 42,
+        user_key: user_pk,
+        invoice: "not enforced yet".to_string(),
+        invoice_amount: Amount::from_sat(40),
+        cancelled: false,
+    });
+    fed.consensus_round(
+        &[],
+        &[(
+            out_point(b"outgoing", 0),
+            contract_output(&contract, Amount::from_sat(42)),
+        )],
+    )
+    .await;
+
+    let claim = |sats| ContractInput {
+        contract_id: contract.contract_id(),
+        amount: Amount::from_sat(sats),
+        witness: ContractWitness::Preimage(preimage.clone()),
+        recipient: None,
+        change_contract: None,
+    };
+    fed.consensus_round(&[claim(20)], &[]).await;
+    fed.consensus_round(&[claim(22)], &[]).await;
+
+    assert_eq!(
+        fed.fetch_from_all(|m| m.gateway_earnings(&gw_pk)),
+        Amount::from_sat(2)
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn test_freeze_contract() {
+    let mut fed = new_fed().await;
+
+    let ctx = secp256k1::Secp256k1::new();
+    let kp = KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng);
+    let contract = Contract::Account(AccountContract {
+        key: kp.x_only_public_key().0,
+        multisig: None,
+    });
+    fed.consensus_round(
+        &[],
+        &[(
+            out_point(b"account", 0),
+            contract_output(&contract, Amount::from_sat(42)),
+        )],
+    )
+    .await;
+
+    let account_input = ContractInput {
+        contract_id: contract.contract_id(),
+        amount: Amount::from_sat(42),
+        witness: ContractWitness::None,
+        recipient: None,
+        change_contract: None,
+    };
+    assert!(fed.verify_input(&account_input).is_ok());
+
+    fed.fetch_from_all(|m| m.propose_freeze(contract.contract_id(), true));
+    fed.consensus_round(&[], &[]).await;
+    assert!(fed.fetch_from_all(|m| m.is_frozen(contract.contract_id())));
+    assert_eq!(
+        fed.verify_input(&account_input).unwrap_err(),
+        LightningModuleError::ContractFrozen(contract.contract_id())
+    );
+
+    fed.fetch_from_all(|m| m.propose_freeze(contract.contract_id(), false));
+    fed.consensus_round(&[], &[]).await;
+    assert!(!fed.fetch_from_all(|m| m.is_frozen(contract.contract_id())));
+    assert!(fed.verify_input(&account_input).is_ok());
+}
+
+#[test_log::test(tokio::test)]
+async fn test_refundability_survives_reorg() {
+    let mut fed = new_fed().await;
+
+    let ctx = secp256k1::Secp256k1::new();
+    let new_key = || {
+        KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng)
+            .x_only_public_key()
+            .0
+    };
+    let user_pk = new_key();
+    let outgoing = Contract::Outgoing(OutgoingContract {
+        hash: sha256::Hash::hash(b"preimage"),
+        gateway_key: new_key(),
+        timelock: 42,
+        user_key: user_pk,
+        invoice: "not enforced yet".to_string(),
+        invoice_amount: Amount::from_sat(40),
+        cancelled: false,
+    });
+    let account = Contract::Account(AccountContract {
+        key: new_key(),
+        multisig: None,
+    });
+    fed.consensus_round(
+        &[],
+        &[
+            (
+                out_point(b"outgoing", 0),
+                contract_output(&outgoing, Amount::from_sat(42)),
+            ),
+            (
+                out_point(b"account", 0),
+                contract_output(&account, Amount::from_sat(42)),
+            ),
+        ],
+    )
+    .await;
+
+    let refund_input = ContractInput {
+        contract_id: outgoing.contract_id(),
+        amount: Amount::from_sat(42),
+        witness: ContractWitness::None,
+        recipient: None,
+        change_contract: None,
+    };
+
+    // The height the module sees is recorded when processing an input
+    fed.set_block_height(50);
+    fed.consensus_round(
+        &[ContractInput {
+            contract_id: account.contract_id(),
+            amount: Amount::from_sat(42),
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract: None,
+        }],
+        &[],
+    )
+    .await;
+    assert_eq!(fed.verify_input(&refund_input).unwrap().keys, vec![user_pk]);
+
+    // A reorg lowers the consensus height below the timelock again
+    fed.set_block_height(30);
+    assert_eq!(fed.verify_input(&refund_input).unwrap().keys, vec![user_pk]);
+}
+
+#[test_log::test(tokio::test)]
+async fn test_contracts_spent_in_epoch() {
+    let mut fed = new_fed().await;
+
+    let ctx = secp256k1::Secp256k1::new();
+    let accounts = (0..2)
+        .map(|_| {
+            let kp = KeyPair::new(&ctx, &mut secp256k1::rand::rngs::OsRng);
+            Contract::Account(AccountContract {
+                key: kp.x_only_public_key().0,
+                multisig: None,
+            })
+        })
+        .collect::<Vec<_>>();
+    let outputs = accounts
+        .iter()
+        .enumerate()
+        .map(|(idx, account)| {
+            (
+                out_point(b"account", idx as u64),
+                contract_output(account, Amount::from_sat(42)),
+            )
+        })
+        .collect::<Vec<_>>();
+    // Epoch 0
+    fed.consensus_round(&[], &outputs).await;
+
+    let inputs = accounts
+        .iter()
+        .map(|account| ContractInput {
+            contract_id: account.contract_id(),
+            amount: Amount::from_sat(42),
+            witness: ContractWitness::None,
+            recipient: None,
+            change_contract: None,
+        })
+        .collect::<Vec<_>>();
+    // Epoch 1
+    fed.consensus_round(&inputs, &[]).await;
+
+    assert_eq!(
+        fed.fetch_from_all(|m| m.contracts_spent_in_epoch(0)),
+        vec![]
+    );
+    let mut spent = fed.fetch_from_all(|m| m.contracts_spent_in_epoch(1));
+    spent.sort();
+    let mut expected = accounts
+        .iter()
+        .map(|account| account.contract_id())
+        .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(spent, expected);
+}
@@ -525,6 +525,22 @@ impl FederationModule for Wallet {
                     Ok(module.consensus_height().unwrap_or(0))
                 }
             },
+            api_endpoint! {
+                "/randomness_beacon",
+                async |module: &Wallet, _params: ()| -> Option<[u8; 32]> {
+                    Ok(module
+                        .current_round_consensus()
+                        .map(|consensus| consensus.randomness_beacon))
+                }
+            },
+            api_endpoint! {
+                "/fee_rate",
+                async |module: &Wallet, _params: ()| -> Option<u64> {
+                    Ok(module
+                        .current_round_consensus()
+                        .map(|consensus| consensus.fee_rate.sats_per_kvb))
+                }
+            },
             api_endpoint! {
                 "/peg_out_fees",
                 async |module: &Wallet, params: (Address, u64)| -> Option<PegOutFees> {